@@ -0,0 +1,11 @@
+//! Helpers shared by the benchmarks
+
+/// Deterministic xorshift generator, so every run benchmarks the same keys
+pub fn rng(mut state: u64) -> impl FnMut() -> u64 {
+    move || {
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+        state
+    }
+}
@@ -0,0 +1,243 @@
+//! Compares the tree with `std::collections::BTreeMap` on the basic map operations and range scans,
+//! for small and large keys and values, and compares `augment_search` with summing up a range of a
+//! `BTreeMap`
+//!
+//! Every group has a `b_tree_t<degree>` and a `btreemap` function, so
+//! `cargo bench --bench maps -- <group>` shows the two next to each other. Trees are built with the
+//! minimum degree the crate is compiled with, 6 unless another one is picked with the
+//! `b_tree_min_degree` cfg. Running
+//!
+//! ```sh
+//! RUSTFLAGS='--cfg b_tree_min_degree="3"' cargo bench --bench maps
+//! cargo bench --bench maps
+//! RUSTFLAGS='--cfg b_tree_min_degree="16"' cargo bench --bench maps
+//! ```
+//!
+//! puts the three degrees next to each other in the report.
+
+use std::collections::BTreeMap;
+use std::hint::black_box;
+
+use b_tree::augments::SumAugment;
+use b_tree::BTree;
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion, Throughput};
+
+mod common;
+
+use common::rng;
+
+/// The name of the tree's benchmarks, after the minimum degree the crate is compiled with
+#[cfg(not(any(b_tree_min_degree = "3", b_tree_min_degree = "16")))]
+const B_TREE: &str = "b_tree_t6";
+#[cfg(b_tree_min_degree = "3")]
+const B_TREE: &str = "b_tree_t3";
+#[cfg(b_tree_min_degree = "16")]
+const B_TREE: &str = "b_tree_t16";
+
+const SIZE: usize = 100_000;
+
+/// The number of queries run per iteration by the range scan and augment benchmarks
+const QUERIES: usize = 1_000;
+
+/// Keys and values that can be generated from a seed. Distinct seeds give distinct samples.
+trait Sample: Clone {
+    fn from_seed(seed: u64) -> Self;
+}
+
+impl Sample for u64 {
+    fn from_seed(seed: u64) -> Self {
+        seed
+    }
+}
+
+impl<const N: usize> Sample for [u64; N] {
+    fn from_seed(seed: u64) -> Self {
+        [seed; N]
+    }
+}
+
+/// Random even seeds, so that setting the lowest bit of any of them gives a seed not among them
+fn random_seeds(len: usize) -> Vec<u64> {
+    let mut next = rng(0x2545_f491_4f6c_dd1d);
+    (0..len).map(|_| next() & !1).collect()
+}
+
+fn pairs<K: Sample, V: Sample>(seeds: &[u64]) -> Vec<(K, V)> {
+    seeds
+        .iter()
+        .map(|&seed| (K::from_seed(seed), V::from_seed(seed)))
+        .collect()
+}
+
+fn b_tree<K: Ord + Clone, V: Clone>(pairs: &[(K, V)]) -> BTree<K, V> {
+    let mut tree = BTree::new();
+    for (key, value) in pairs {
+        tree.insert(key.clone(), value.clone());
+    }
+    tree
+}
+
+fn btreemap<K: Ord + Clone, V: Clone>(pairs: &[(K, V)]) -> BTreeMap<K, V> {
+    pairs.iter().cloned().collect()
+}
+
+/// Runs the map benchmarks with keys of type `K` and values of type `V`, naming the groups after
+/// `sizes`
+fn compare_maps<K: Sample + Ord, V: Sample>(c: &mut Criterion, sizes: &str) {
+    let seeds = random_seeds(SIZE);
+    let mut sorted_seeds = seeds.clone();
+    sorted_seeds.sort_unstable();
+    let reversed_seeds: Vec<_> = sorted_seeds.iter().rev().copied().collect();
+
+    for (order, seeds) in [
+        ("sequential", &sorted_seeds),
+        ("reverse", &reversed_seeds),
+        ("random", &seeds),
+    ] {
+        let pairs = pairs::<K, V>(seeds);
+        let mut group = c.benchmark_group(format!("insert_{order}/{sizes}"));
+        group.throughput(Throughput::Elements(SIZE as u64));
+        group.bench_function(B_TREE, |b| b.iter(|| b_tree(black_box(&pairs))));
+        group.bench_function("btreemap", |b| b.iter(|| btreemap(black_box(&pairs))));
+        group.finish();
+    }
+
+    let pairs = pairs::<K, V>(&seeds);
+    let tree = b_tree(&pairs);
+    let map = btreemap(&pairs);
+
+    for (outcome, lowest_bit) in [("hit", 0), ("miss", 1)] {
+        let keys: Vec<_> = seeds
+            .iter()
+            .map(|seed| K::from_seed(seed | lowest_bit))
+            .collect();
+        let mut group = c.benchmark_group(format!("search_{outcome}/{sizes}"));
+        group.throughput(Throughput::Elements(SIZE as u64));
+        group.bench_function(B_TREE, |b| {
+            b.iter(|| keys.iter().filter(|key| tree.search(key).is_some()).count())
+        });
+        group.bench_function("btreemap", |b| {
+            b.iter(|| keys.iter().filter(|key| map.contains_key(key)).count())
+        });
+        group.finish();
+    }
+
+    let keys: Vec<_> = pairs.iter().map(|(key, _)| key.clone()).collect();
+    let mut group = c.benchmark_group(format!("delete_random/{sizes}"));
+    group.throughput(Throughput::Elements(SIZE as u64));
+    group.bench_function(B_TREE, |b| {
+        b.iter_batched(
+            || tree.clone(),
+            |mut tree| {
+                for key in &keys {
+                    tree.delete(key);
+                }
+                tree
+            },
+            BatchSize::LargeInput,
+        )
+    });
+    group.bench_function("btreemap", |b| {
+        b.iter_batched(
+            || map.clone(),
+            |mut map| {
+                for key in &keys {
+                    map.remove(key);
+                }
+                map
+            },
+            BatchSize::LargeInput,
+        )
+    });
+    group.finish();
+
+    for width in [10, 100, 1_000] {
+        // Scans start at random keys and cover `width` pairs each
+        let ranges: Vec<_> = seeds[..QUERIES]
+            .iter()
+            .map(|seed| {
+                let start = sorted_seeds.binary_search(seed).unwrap();
+                let end = sorted_seeds[(start + width).min(SIZE - 1)];
+                K::from_seed(*seed)..K::from_seed(end)
+            })
+            .collect();
+        let mut group = c.benchmark_group(format!("range_scan_{width}/{sizes}"));
+        group.throughput(Throughput::Elements((QUERIES * width) as u64));
+        group.bench_function(B_TREE, |b| {
+            b.iter(|| {
+                ranges
+                    .iter()
+                    .map(|range| tree.range(range.clone()).count())
+                    .sum::<usize>()
+            })
+        });
+        group.bench_function("btreemap", |b| {
+            b.iter(|| {
+                ranges
+                    .iter()
+                    .map(|range| map.range(range.clone()).count())
+                    .sum::<usize>()
+            })
+        });
+        group.finish();
+    }
+}
+
+/// Compares `augment_search` with `SumAugment` with summing the values up to the key in a
+/// `BTreeMap`, which takes time linear in the number of pairs summed, and with looking the sum up
+/// in precomputed prefix sums, which is as fast as it gets but has to be rebuilt on every update
+fn compare_sums(c: &mut Criterion) {
+    let seeds = random_seeds(SIZE);
+    let pairs: Vec<_> = seeds.iter().map(|&seed| (seed, seed % 1_000)).collect();
+    let tree: BTree<u64, u64, SumAugment> = pairs.iter().copied().collect();
+    let map = btreemap(&pairs);
+    let keys = &seeds[..QUERIES];
+
+    // `prefix_sums[i]` is the sum of the values of the `i` smallest keys
+    let sorted_keys: Vec<_> = map.keys().copied().collect();
+    let prefix_sums: Vec<_> = [0]
+        .into_iter()
+        .chain(map.values().scan(0, |sum, value| {
+            *sum += value;
+            Some(*sum)
+        }))
+        .collect();
+
+    let mut group = c.benchmark_group("augment_search/u64_u64");
+    group.throughput(Throughput::Elements(QUERIES as u64));
+    group.bench_function(B_TREE, |b| {
+        b.iter(|| {
+            keys.iter()
+                .map(|key| tree.augment_search(key))
+                .fold(0, u64::wrapping_add)
+        })
+    });
+    group.bench_function("btreemap", |b| {
+        b.iter(|| {
+            keys.iter()
+                .map(|key| map.range(..=key).map(|(_, value)| value).sum::<u64>())
+                .fold(0, u64::wrapping_add)
+        })
+    });
+    group.bench_function("prefix_sums", |b| {
+        b.iter(|| {
+            keys.iter()
+                .map(|key| prefix_sums[sorted_keys.partition_point(|k| k <= key)])
+                .fold(0, u64::wrapping_add)
+        })
+    });
+    group.finish();
+}
+
+fn benches(c: &mut Criterion) {
+    compare_maps::<u64, u64>(c, "u64_u64");
+    compare_maps::<[u64; 4], [u64; 8]>(c, "32B_64B");
+    compare_sums(c);
+}
+
+criterion_group! {
+    name = maps;
+    config = Criterion::default().sample_size(20);
+    targets = benches
+}
+criterion_main!(maps);
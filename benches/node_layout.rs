@@ -0,0 +1,103 @@
+//! Benchmarks the operations that allocate and move nodes the most, and reports how much memory
+//! the tree takes up per pair before running them
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::hint::black_box;
+use std::iter;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use b_tree::augments::SumAugment;
+use b_tree::BTree;
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion};
+
+mod common;
+
+use common::rng;
+
+/// Counts the bytes and allocations currently held by the process
+struct Counting;
+
+static BYTES: AtomicUsize = AtomicUsize::new(0);
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        BYTES.fetch_add(layout.size(), Ordering::Relaxed);
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        BYTES.fetch_sub(layout.size(), Ordering::Relaxed);
+        ALLOCATIONS.fetch_sub(1, Ordering::Relaxed);
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: Counting = Counting;
+
+type Tree = BTree<u64, u64, SumAugment>;
+
+const SIZE: usize = 100_000;
+
+fn random_keys(len: usize) -> Vec<u64> {
+    iter::repeat_with(rng(0x2545_f491_4f6c_dd1d))
+        .take(len)
+        .collect()
+}
+
+fn filled(keys: &[u64]) -> Tree {
+    let mut tree = Tree::default();
+    for &key in keys {
+        tree.insert(key, key);
+    }
+    tree
+}
+
+fn report_memory(keys: &[u64]) {
+    for len in [1_000, SIZE] {
+        let (bytes, allocations) = (
+            BYTES.load(Ordering::Relaxed),
+            ALLOCATIONS.load(Ordering::Relaxed),
+        );
+        let tree = filled(&keys[..len]);
+        let bytes = BYTES.load(Ordering::Relaxed) - bytes;
+        let allocations = ALLOCATIONS.load(Ordering::Relaxed) - allocations;
+        println!(
+            "memory/{len}: {bytes} bytes in {allocations} allocations, {:.1} bytes per pair",
+            bytes as f64 / len as f64
+        );
+        drop(tree);
+    }
+}
+
+fn benches(c: &mut Criterion) {
+    let keys = random_keys(SIZE);
+    report_memory(&keys);
+
+    c.bench_function("insert_random", |b| b.iter(|| filled(black_box(&keys))));
+
+    let tree = filled(&keys);
+    c.bench_function("search_random", |b| {
+        b.iter(|| keys.iter().filter(|key| tree.search(key).is_some()).count())
+    });
+    c.bench_function("delete_random", |b| {
+        b.iter_batched(
+            || tree.clone(),
+            |mut tree| {
+                for key in &keys {
+                    tree.delete(key);
+                }
+                tree
+            },
+            BatchSize::LargeInput,
+        )
+    });
+    c.bench_function("iter", |b| {
+        b.iter(|| tree.iter().map(|(_, v)| v).sum::<u64>())
+    });
+}
+
+criterion_group!(node_layout, benches);
+criterion_main!(node_layout);
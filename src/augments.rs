@@ -1,6 +1,12 @@
-use std::ops::{Add, Sub};
+use alloc::vec;
+use alloc::vec::Vec;
+use core::fmt::Debug;
+use core::marker::PhantomData;
+use core::ops::{Add, Bound, RangeBounds, Sub};
 
-use crate::Augment;
+use crate::persistent::{Sharing, Unique};
+use crate::snapshot::Encode;
+use crate::{Augment, BTree, Node, RangeUpdate};
 
 impl<K, V> Augment<K, V> for () {
     type Value = ();
@@ -160,9 +166,1163 @@ where
     }
 }
 
+/// Like [`SumAugment`], but also supports adding a delta to every value in a key range with
+/// [`BTree::update_range`](crate::BTree::update_range)
+pub struct LazySumAugment;
+
+/// The augment value of [`LazySumAugment`]
+#[derive(Clone, Debug, PartialEq)]
+pub struct LazySum<V> {
+    /// The sum of the values in the subtree, pending deltas included
+    sum: V,
+    len: usize,
+    /// A delta that has yet to be added to the pairs and children of the node
+    pending: Option<V>,
+}
+
+/// Returns `value` added to itself `n` times, using `O(log n)` additions
+fn times<V: Default + Clone>(value: &V, mut n: usize) -> V
+where
+    for<'a> &'a V: Add<Output = V>,
+{
+    let (mut acc, mut power) = (V::default(), value.clone());
+    while n > 0 {
+        if n & 1 == 1 {
+            acc = &acc + &power;
+        }
+        n >>= 1;
+        if n > 0 {
+            power = &power + &power;
+        }
+    }
+    acc
+}
+
+impl<V: Default + Clone> LazySum<V>
+where
+    for<'a> &'a V: Add<Output = V> + Sub<Output = V>,
+{
+    /// A subtree without pending deltas
+    fn new(sum: V, len: usize) -> Self {
+        Self {
+            sum,
+            len,
+            pending: None,
+        }
+    }
+
+    fn updated(&self, delta: &V) -> Self {
+        let pending = match &self.pending {
+            Some(pending) => pending + delta,
+            None => delta.clone(),
+        };
+        Self {
+            sum: &self.sum + &times(delta, self.len),
+            len: self.len,
+            pending: Some(pending),
+        }
+    }
+}
+
+// The tree pushes pending deltas down before it modifies a node, so the hooks that update the
+// augment value of a node can ignore them
+impl<K, V: Default + Clone + 'static> Augment<K, V> for LazySumAugment
+where
+    for<'a> &'a V: Add<Output = V> + Sub<Output = V>,
+{
+    type Value = LazySum<V>;
+    type Output = V;
+
+    fn initial_value() -> Self::Value {
+        LazySum::new(V::default(), 0)
+    }
+
+    fn initial_output() -> Self::Output {
+        V::default()
+    }
+
+    fn inserted_sub_tree(_: &K, value: &V, old: &Self::Value) -> Self::Value {
+        LazySum::new(&old.sum + value, old.len + 1)
+    }
+
+    fn deleted_sub_tree(_: &K, value: &V, old: &Self::Value) -> Self::Value {
+        LazySum::new(&old.sum - value, old.len - 1)
+    }
+
+    fn split<'a>(
+        left_keys: &[(K, V)],
+        _: &[(K, V)],
+        (_, median_value): &(K, V),
+        left_children: impl Iterator<Item = &'a Self::Value>,
+        _: impl Iterator<Item = &'a Self::Value>,
+        old: &Self::Value,
+    ) -> (Self::Value, Self::Value)
+    where
+        Self::Value: 'a,
+    {
+        let mut left = LazySum::new(V::default(), left_keys.len());
+        for (_, value) in left_keys {
+            left.sum = &left.sum + value;
+        }
+        for child in left_children {
+            left.sum = &left.sum + &child.sum;
+            left.len += child.len;
+        }
+
+        let right = LazySum::new(
+            &(&old.sum - median_value) - &left.sum,
+            old.len - 1 - left.len,
+        );
+        (left, right)
+    }
+
+    fn split_root(
+        (_, root_value): &(K, V),
+        left: &Self::Value,
+        right: &Self::Value,
+    ) -> Self::Value {
+        LazySum::new(
+            &(root_value + &left.sum) + &right.sum,
+            left.len + right.len + 1,
+        )
+    }
+
+    fn merge((_, parent_value): &(K, V), left: &Self::Value, right: &Self::Value) -> Self::Value {
+        LazySum::new(
+            &(&left.sum + &right.sum) + parent_value,
+            left.len + right.len + 1,
+        )
+    }
+
+    fn steal(
+        (_, parent_value): &(K, V),
+        (_, victim_value): &(K, V),
+        stolen_child: Option<&Self::Value>,
+        thief: &Self::Value,
+        victim: &Self::Value,
+    ) -> (Self::Value, Self::Value) {
+        let (child_sum, child_len) = match stolen_child {
+            Some(child) => (child.sum.clone(), child.len),
+            None => (V::default(), 0),
+        };
+        (
+            LazySum::new(
+                &(&thief.sum + parent_value) + &child_sum,
+                thief.len + 1 + child_len,
+            ),
+            LazySum::new(
+                &(&victim.sum - victim_value) - &child_sum,
+                victim.len - 1 - child_len,
+            ),
+        )
+    }
+
+    fn visit<'a>(
+        found: bool,
+        idx: usize,
+        keys: &[(K, V)],
+        children: impl Iterator<Item = &'a Self::Value>,
+        value: &Self::Value,
+        mut acc: Self::Output,
+    ) -> Self::Output
+    where
+        Self::Value: 'a,
+    {
+        let num_left = if found { idx + 1 } else { idx };
+        let mut len = num_left;
+        for (_, value) in &keys[..num_left] {
+            acc = &acc + value;
+        }
+        for child in children.take(num_left) {
+            acc = &acc + &child.sum;
+            len += child.len;
+        }
+
+        // The pairs and children visited do not include the pending delta yet
+        match &value.pending {
+            Some(pending) => &acc + &times(pending, len),
+            None => acc,
+        }
+    }
+
+    fn has_pending(value: &Self::Value) -> bool {
+        value.pending.is_some()
+    }
+
+    fn push_down<'a>(
+        value: &mut Self::Value,
+        pairs: &mut [(K, V)],
+        children: impl Iterator<Item = &'a mut Self::Value>,
+    ) where
+        Self::Value: 'a,
+    {
+        let Some(delta) = value.pending.take() else {
+            return;
+        };
+        for (_, value) in pairs {
+            *value = &*value + &delta;
+        }
+        for child in children {
+            *child = child.updated(&delta);
+        }
+    }
+
+    fn pushed_down(parent: &Self::Value, child: &Self::Value) -> Self::Value {
+        match &parent.pending {
+            Some(delta) => child.updated(delta),
+            None => child.clone(),
+        }
+    }
+}
+
+impl<K, V: Default + Clone + 'static> RangeUpdate<K, V> for LazySumAugment
+where
+    for<'a> &'a V: Add<Output = V> + Sub<Output = V>,
+{
+    type Delta = V;
+
+    fn updated_value(delta: &V, value: &V) -> V {
+        value + delta
+    }
+
+    fn updated_sub_tree(delta: &V, old: &Self::Value) -> Self::Value {
+        old.updated(delta)
+    }
+}
+
+/// The end of the interval stored in a value of a tree with an [`IntervalAugment`]. The value can
+/// either be the end itself or a pair of the end and some other data.
+pub trait IntervalEnd<K> {
+    fn end(&self) -> &K;
+}
+
+impl<K> IntervalEnd<K> for K {
+    fn end(&self) -> &K {
+        self
+    }
+}
+
+impl<K, T> IntervalEnd<K> for (K, T) {
+    fn end(&self) -> &K {
+        &self.0
+    }
+}
+
+/// Turns the tree into an interval tree. Every pair is the half-open interval from its key to the
+/// [end](IntervalEnd) stored in its value, and the augment value of a subtree is the largest end
+/// in it, so [`BTree::overlapping`] and [`BTree::stabbing`] can skip subtrees whose intervals all
+/// end too early.
+///
+/// [`BTree::augment_search`] returns the largest end of the intervals that start at or before the
+/// key, which is greater than the key if and only if one of them contains it.
+///
+/// ```
+/// use b_tree::augments::IntervalAugment;
+/// use b_tree::BTree;
+///
+/// let mut calendar = BTree::with_augment::<IntervalAugment>();
+/// calendar.insert(9, (10, "standup"));
+/// calendar.insert(13, (15, "review"));
+///
+/// let conflicts: Vec<_> = calendar.overlapping(14..16).map(|(_, (_, name))| *name).collect();
+/// assert_eq!(conflicts, ["review"]);
+/// assert_eq!(calendar.stabbing(&10).count(), 0);
+/// ```
+pub struct IntervalAugment;
+
+// `K: 'static` stands in for the `K: 'a` implied by the `Self::Value: 'a` bounds of the hooks. The
+// compiler does not see through `Option<K>: 'a` when comparing them with the trait, so spelling
+// out `K: 'a` or leaving it out both fail to compile.
+impl<K: Ord + Clone + 'static, V: IntervalEnd<K>> Augment<K, V> for IntervalAugment {
+    type Value = Option<K>;
+    type Output = Option<K>;
+
+    const RECOMPUTE_ON_DELETE: bool = true;
+
+    fn initial_value() -> Self::Value {
+        None
+    }
+
+    fn initial_output() -> Self::Output {
+        None
+    }
+
+    fn inserted_sub_tree(_: &K, value: &V, old: &Self::Value) -> Self::Value {
+        old.as_ref().max(Some(value.end())).cloned()
+    }
+
+    fn deleted_sub_tree(_: &K, _: &V, _: &Self::Value) -> Self::Value {
+        unreachable!("the tree recomputes the largest end on delete")
+    }
+
+    fn split<'a>(
+        left_keys: &[(K, V)],
+        right_keys: &[(K, V)],
+        _: &(K, V),
+        left_children: impl Iterator<Item = &'a Self::Value>,
+        right_children: impl Iterator<Item = &'a Self::Value>,
+        _: &Self::Value,
+    ) -> (Self::Value, Self::Value)
+    where
+        Self::Value: 'a,
+    {
+        (
+            largest_end(left_keys, left_children),
+            largest_end(right_keys, right_children),
+        )
+    }
+
+    fn split_root(
+        (_, root_value): &(K, V),
+        left: &Self::Value,
+        right: &Self::Value,
+    ) -> Self::Value {
+        Self::merge_ends(root_value, left, right)
+    }
+
+    fn merge((_, parent_value): &(K, V), left: &Self::Value, right: &Self::Value) -> Self::Value {
+        Self::merge_ends(parent_value, left, right)
+    }
+
+    fn steal(
+        (_, parent_value): &(K, V),
+        _: &(K, V),
+        stolen_child: Option<&Self::Value>,
+        thief: &Self::Value,
+        victim: &Self::Value,
+    ) -> (Self::Value, Self::Value) {
+        let thief = Self::merge_ends(parent_value, thief, stolen_child.unwrap_or(&None));
+        // The victim is recomputed by the tree
+        (thief, victim.clone())
+    }
+
+    fn visit<'a>(
+        found: bool,
+        idx: usize,
+        keys: &[(K, V)],
+        children: impl Iterator<Item = &'a Self::Value>,
+        _: &Self::Value,
+        acc: Self::Output,
+    ) -> Self::Output
+    where
+        Self::Value: 'a,
+    {
+        let num_keys = idx + usize::from(found);
+        let visited = largest_end(&keys[..num_keys], children.take(num_keys));
+        acc.max(visited)
+    }
+}
+
+impl IntervalAugment {
+    fn merge_ends<K: Ord + Clone, V: IntervalEnd<K>>(
+        value: &V,
+        left: &Option<K>,
+        right: &Option<K>,
+    ) -> Option<K> {
+        left.as_ref()
+            .max(right.as_ref())
+            .max(Some(value.end()))
+            .cloned()
+    }
+}
+
+/// Returns the largest end of the intervals in `pairs` and the subtrees with the given augment
+/// values
+fn largest_end<'a, K: Ord + Clone + 'a, V: IntervalEnd<K>>(
+    pairs: &[(K, V)],
+    children: impl Iterator<Item = &'a Option<K>>,
+) -> Option<K> {
+    let pairs_end = pairs.iter().map(|(_, value)| value.end()).max();
+    children.flatten().max().max(pairs_end).cloned()
+}
+
+impl<K, V, S> BTree<K, V, IntervalAugment, S>
+where
+    K: Ord + Clone + 'static,
+    V: IntervalEnd<K>,
+    S: Sharing<K, V, IntervalAugment>,
+{
+    /// Returns an iterator over the intervals that overlap `range`, in ascending order of their
+    /// start
+    ///
+    /// Only the subtrees that start before the range ends and hold an interval ending after it
+    /// starts are visited, so finding `k` intervals takes `O((k + 1) log n)` time.
+    pub fn overlapping(&self, range: impl RangeBounds<K>) -> Overlapping<'_, K, V, S> {
+        self.check_poison();
+        let mut iter = Overlapping {
+            stack: Vec::new(),
+            start: range.start_bound().cloned(),
+            end: range.end_bound().cloned(),
+        };
+        iter.push_leftmost(&self.root);
+        iter
+    }
+
+    /// Returns an iterator over the intervals that contain `point`, in ascending order of their
+    /// start
+    pub fn stabbing(&self, point: &K) -> Overlapping<'_, K, V, S> {
+        self.overlapping(point..=point)
+    }
+}
+
+/// A node along with the index of its next pair and the number of its pairs that start before the
+/// range ends
+type IntervalPosition<'a, K, V, S> = (&'a Node<K, V, IntervalAugment, S>, usize, usize);
+
+/// An iterator over the intervals of a tree with an [`IntervalAugment`] that overlap a range,
+/// created by [`BTree::overlapping`] and [`BTree::stabbing`]
+pub struct Overlapping<'a, K, V, S = Unique>
+where
+    // Required for `IntervalAugment` to be an augment at all
+    K: Ord + Clone + 'static,
+    V: IntervalEnd<K>,
+    S: Sharing<K, V, IntervalAugment>,
+{
+    /// The nodes on the path to the next interval
+    stack: Vec<IntervalPosition<'a, K, V, S>>,
+    start: Bound<K>,
+    end: Bound<K>,
+}
+
+impl<'a, K, V, S> Overlapping<'a, K, V, S>
+where
+    K: Ord + Clone + 'static,
+    V: IntervalEnd<K>,
+    S: Sharing<K, V, IntervalAugment>,
+{
+    fn ends_after_start(&self, end: &K) -> bool {
+        match &self.start {
+            Bound::Included(start) | Bound::Excluded(start) => end > start,
+            Bound::Unbounded => true,
+        }
+    }
+
+    fn push_leftmost(&mut self, mut node: &'a Node<K, V, IntervalAugment, S>) {
+        while node
+            .aug_val
+            .as_ref()
+            .is_some_and(|end| self.ends_after_start(end))
+        {
+            let (_, last) = node.bounds_idx(Bound::Unbounded, self.end.as_ref());
+            self.stack.push((node, 0, last));
+            if node.is_leaf() {
+                break;
+            }
+            node = &node.children()[0];
+        }
+    }
+}
+
+impl<'a, K, V, S> Iterator for Overlapping<'a, K, V, S>
+where
+    K: Ord + Clone + 'static,
+    V: IntervalEnd<K>,
+    S: Sharing<K, V, IntervalAugment>,
+{
+    type Item = (&'a K, &'a V);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while let Some((node, idx, last)) = self.stack.last_mut() {
+            let node = *node;
+            if *idx == *last {
+                self.stack.pop();
+                continue;
+            }
+
+            let (key, value) = &node.pairs()[*idx];
+            *idx += 1;
+            if !node.is_leaf() {
+                let next = &node.children()[*idx];
+                self.push_leftmost(next);
+            }
+            if self.ends_after_start(value.end()) {
+                return Some((key, value));
+            }
+        }
+        None
+    }
+}
+
+/// Allows for finding the number of pairs with smaller (or equal) keys
+pub struct CountAugment;
+
+impl<K, V> Augment<K, V> for CountAugment {
+    type Value = usize;
+    type Output = usize;
+
+    fn initial_value() -> Self::Value {
+        0
+    }
+
+    fn initial_output() -> Self::Output {
+        0
+    }
+
+    fn inserted_sub_tree(_: &K, _: &V, old: &Self::Value) -> Self::Value {
+        old + 1
+    }
+
+    fn deleted_sub_tree(_: &K, _: &V, old: &Self::Value) -> Self::Value {
+        old - 1
+    }
+
+    fn split<'a>(
+        left_keys: &[(K, V)],
+        _: &[(K, V)],
+        _: &(K, V),
+        left_children: impl Iterator<Item = &'a Self::Value>,
+        _: impl Iterator<Item = &'a Self::Value>,
+        old: &Self::Value,
+    ) -> (Self::Value, Self::Value) {
+        let left = left_keys.len() + left_children.sum::<usize>();
+        (left, old - 1 - left)
+    }
+
+    fn split_root(_: &(K, V), left: &Self::Value, right: &Self::Value) -> Self::Value {
+        left + 1 + right
+    }
+
+    fn merge(_: &(K, V), left: &Self::Value, right: &Self::Value) -> Self::Value {
+        left + 1 + right
+    }
+
+    fn steal(
+        _: &(K, V),
+        _: &(K, V),
+        stolen_child: Option<&Self::Value>,
+        thief: &Self::Value,
+        victim: &Self::Value,
+    ) -> (Self::Value, Self::Value) {
+        let moved = stolen_child.copied().unwrap_or(0);
+        (thief + 1 + moved, victim - 1 - moved)
+    }
+
+    fn visit<'a>(
+        found: bool,
+        idx: usize,
+        _: &[(K, V)],
+        children: impl Iterator<Item = &'a Self::Value>,
+        _: &Self::Value,
+        acc: Self::Output,
+    ) -> Self::Output {
+        let num_keys = idx + usize::from(found);
+        acc + num_keys + children.take(num_keys).sum::<usize>()
+    }
+}
+
+/// The number of keys in a set and a hash of them, as maintained by [`FingerprintAugment`]. Sets
+/// with equal fingerprints are equal with overwhelming probability.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct Fingerprint {
+    pub count: usize,
+    /// The wrapping sum of the hashes of the keys
+    pub hash: u64,
+}
+
+impl Fingerprint {
+    /// Returns the fingerprint of the set holding only `key`. The hash of a key is the start of the
+    /// [`Sha256`] digest of its encoding, so it is the same on every platform.
+    pub fn of<K: Encode>(key: &K) -> Self {
+        let mut bytes = Vec::new();
+        key.encode(&mut bytes);
+        let digest = Sha256::hash(&bytes);
+        Self {
+            count: 1,
+            hash: u64::from_le_bytes(digest[..8].try_into().unwrap()),
+        }
+    }
+}
+
+impl Add for Fingerprint {
+    type Output = Self;
+
+    fn add(self, other: Self) -> Self {
+        Self {
+            count: self.count + other.count,
+            hash: self.hash.wrapping_add(other.hash),
+        }
+    }
+}
+
+impl Sub for Fingerprint {
+    type Output = Self;
+
+    fn sub(self, other: Self) -> Self {
+        Self {
+            count: self.count - other.count,
+            hash: self.hash.wrapping_sub(other.hash),
+        }
+    }
+}
+
+/// Maintains the [`Fingerprint`] of the keys of every subtree, ignoring the values. As the hashes
+/// of the keys are added up, the fingerprint of a set does not depend on the shape of the tree,
+/// and [`BTree::augment_range`] returns the fingerprint of the keys in a range. This is what
+/// [`reconcile`](crate::reconcile) compares to find the keys two trees disagree on.
+pub struct FingerprintAugment;
+
+impl<K: Encode, V> Augment<K, V> for FingerprintAugment {
+    type Value = Fingerprint;
+    type Output = Fingerprint;
+
+    fn initial_value() -> Self::Value {
+        Fingerprint::default()
+    }
+
+    fn initial_output() -> Self::Output {
+        Fingerprint::default()
+    }
+
+    fn inserted_sub_tree(key: &K, _: &V, old: &Self::Value) -> Self::Value {
+        *old + Fingerprint::of(key)
+    }
+
+    fn deleted_sub_tree(key: &K, _: &V, old: &Self::Value) -> Self::Value {
+        *old - Fingerprint::of(key)
+    }
+
+    fn split<'a>(
+        left_keys: &[(K, V)],
+        _: &[(K, V)],
+        median: &(K, V),
+        left_children: impl Iterator<Item = &'a Self::Value>,
+        _: impl Iterator<Item = &'a Self::Value>,
+        old: &Self::Value,
+    ) -> (Self::Value, Self::Value) {
+        let left = left_keys
+            .iter()
+            .map(|(key, _)| Fingerprint::of(key))
+            .chain(left_children.copied())
+            .fold(Fingerprint::default(), Add::add);
+        (left, *old - left - Fingerprint::of(&median.0))
+    }
+
+    fn split_root(root_pair: &(K, V), left: &Self::Value, right: &Self::Value) -> Self::Value {
+        *left + Fingerprint::of(&root_pair.0) + *right
+    }
+
+    fn merge(parent_pair: &(K, V), left: &Self::Value, right: &Self::Value) -> Self::Value {
+        *left + Fingerprint::of(&parent_pair.0) + *right
+    }
+
+    fn steal(
+        parent_pair: &(K, V),
+        victim_pair: &(K, V),
+        stolen_child: Option<&Self::Value>,
+        thief: &Self::Value,
+        victim: &Self::Value,
+    ) -> (Self::Value, Self::Value) {
+        let moved = stolen_child.copied().unwrap_or_default();
+        (
+            *thief + Fingerprint::of(&parent_pair.0) + moved,
+            *victim - Fingerprint::of(&victim_pair.0) - moved,
+        )
+    }
+
+    fn visit<'a>(
+        found: bool,
+        idx: usize,
+        keys: &[(K, V)],
+        children: impl Iterator<Item = &'a Self::Value>,
+        _: &Self::Value,
+        acc: Self::Output,
+    ) -> Self::Output {
+        let num_keys = idx + usize::from(found);
+        keys[..num_keys]
+            .iter()
+            .map(|(key, _)| Fingerprint::of(key))
+            .chain(children.take(num_keys).copied())
+            .fold(acc, Add::add)
+    }
+}
+
+/// Maintains two augments side by side, with [`BTree::augment_search`] returning the outputs of
+/// both. As the pair does not implement [`RangeUpdate`], neither augment may record pending
+/// changes.
+impl<K, V, A: Augment<K, V>, B: Augment<K, V>> Augment<K, V> for (A, B)
+where
+    A::Value: 'static,
+    B::Value: 'static,
+{
+    type Value = (A::Value, B::Value);
+    type Output = (A::Output, B::Output);
+
+    const RECOMPUTE_ON_DELETE: bool = A::RECOMPUTE_ON_DELETE || B::RECOMPUTE_ON_DELETE;
+    const RECOMPUTE_ALWAYS: bool = A::RECOMPUTE_ALWAYS || B::RECOMPUTE_ALWAYS;
+
+    fn initial_value() -> Self::Value {
+        (A::initial_value(), B::initial_value())
+    }
+
+    fn initial_output() -> Self::Output {
+        (A::initial_output(), B::initial_output())
+    }
+
+    fn inserted_sub_tree(key: &K, value: &V, (a, b): &Self::Value) -> Self::Value {
+        (
+            A::inserted_sub_tree(key, value, a),
+            B::inserted_sub_tree(key, value, b),
+        )
+    }
+
+    fn deleted_sub_tree(key: &K, value: &V, (a, b): &Self::Value) -> Self::Value {
+        (
+            A::deleted_sub_tree(key, value, a),
+            B::deleted_sub_tree(key, value, b),
+        )
+    }
+
+    fn split<'a>(
+        left_keys: &[(K, V)],
+        right_keys: &[(K, V)],
+        median: &(K, V),
+        left_children: impl Iterator<Item = &'a Self::Value>,
+        right_children: impl Iterator<Item = &'a Self::Value>,
+        (a, b): &Self::Value,
+    ) -> (Self::Value, Self::Value)
+    where
+        Self::Value: 'a,
+    {
+        let left_children: Vec<_> = left_children.collect();
+        let right_children: Vec<_> = right_children.collect();
+        let (left_a, right_a) = A::split(
+            left_keys,
+            right_keys,
+            median,
+            left_children.iter().map(|(a, _)| a),
+            right_children.iter().map(|(a, _)| a),
+            a,
+        );
+        let (left_b, right_b) = B::split(
+            left_keys,
+            right_keys,
+            median,
+            left_children.iter().map(|(_, b)| b),
+            right_children.iter().map(|(_, b)| b),
+            b,
+        );
+        ((left_a, left_b), (right_a, right_b))
+    }
+
+    fn split_root(root_pair: &(K, V), left: &Self::Value, right: &Self::Value) -> Self::Value {
+        (
+            A::split_root(root_pair, &left.0, &right.0),
+            B::split_root(root_pair, &left.1, &right.1),
+        )
+    }
+
+    fn merge(parent_pair: &(K, V), left: &Self::Value, right: &Self::Value) -> Self::Value {
+        (
+            A::merge(parent_pair, &left.0, &right.0),
+            B::merge(parent_pair, &left.1, &right.1),
+        )
+    }
+
+    fn steal(
+        parent_pair: &(K, V),
+        victim_pair: &(K, V),
+        stolen_child: Option<&Self::Value>,
+        thief: &Self::Value,
+        victim: &Self::Value,
+    ) -> (Self::Value, Self::Value) {
+        let (thief_a, victim_a) = A::steal(
+            parent_pair,
+            victim_pair,
+            stolen_child.map(|(a, _)| a),
+            &thief.0,
+            &victim.0,
+        );
+        let (thief_b, victim_b) = B::steal(
+            parent_pair,
+            victim_pair,
+            stolen_child.map(|(_, b)| b),
+            &thief.1,
+            &victim.1,
+        );
+        ((thief_a, thief_b), (victim_a, victim_b))
+    }
+
+    fn compute<'a>(
+        pairs: &[(K, V)],
+        children: impl DoubleEndedIterator<Item = &'a Self::Value>,
+    ) -> Self::Value
+    where
+        Self::Value: 'a,
+    {
+        let children: Vec<_> = children.collect();
+        (
+            A::compute(pairs, children.iter().map(|(a, _)| a)),
+            B::compute(pairs, children.iter().map(|(_, b)| b)),
+        )
+    }
+
+    fn identical_sub_trees(a: &Self::Value, b: &Self::Value) -> bool {
+        A::identical_sub_trees(&a.0, &b.0) || B::identical_sub_trees(&a.1, &b.1)
+    }
+
+    fn visit<'a>(
+        found: bool,
+        idx: usize,
+        keys: &[(K, V)],
+        children: impl Iterator<Item = &'a Self::Value>,
+        (a, b): &Self::Value,
+        (acc_a, acc_b): Self::Output,
+    ) -> Self::Output
+    where
+        Self::Value: 'a,
+    {
+        let children: Vec<_> = children.collect();
+        (
+            A::visit(found, idx, keys, children.iter().map(|(a, _)| a), a, acc_a),
+            B::visit(found, idx, keys, children.iter().map(|(_, b)| b), b, acc_b),
+        )
+    }
+}
+
+/// Hashes bytes for a [`HashAugment`]. All digests of a hasher must have the same length.
+pub trait MerkleHasher {
+    type Digest: Clone + Eq + Debug + AsRef<[u8]>;
+
+    fn hash(data: &[u8]) -> Self::Digest;
+}
+
+/// SHA-256 as specified in FIPS 180-4, the default hasher of [`HashAugment`]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Sha256;
+
+impl Sha256 {
+    const ROUND_CONSTANTS: [u32; 64] = [
+        0x428a2f98, 0x71374491, 0xb5c0fbcf, 0xe9b5dba5, 0x3956c25b, 0x59f111f1, 0x923f82a4,
+        0xab1c5ed5, 0xd807aa98, 0x12835b01, 0x243185be, 0x550c7dc3, 0x72be5d74, 0x80deb1fe,
+        0x9bdc06a7, 0xc19bf174, 0xe49b69c1, 0xefbe4786, 0x0fc19dc6, 0x240ca1cc, 0x2de92c6f,
+        0x4a7484aa, 0x5cb0a9dc, 0x76f988da, 0x983e5152, 0xa831c66d, 0xb00327c8, 0xbf597fc7,
+        0xc6e00bf3, 0xd5a79147, 0x06ca6351, 0x14292967, 0x27b70a85, 0x2e1b2138, 0x4d2c6dfc,
+        0x53380d13, 0x650a7354, 0x766a0abb, 0x81c2c92e, 0x92722c85, 0xa2bfe8a1, 0xa81a664b,
+        0xc24b8b70, 0xc76c51a3, 0xd192e819, 0xd6990624, 0xf40e3585, 0x106aa070, 0x19a4c116,
+        0x1e376c08, 0x2748774c, 0x34b0bcb5, 0x391c0cb3, 0x4ed8aa4a, 0x5b9cca4f, 0x682e6ff3,
+        0x748f82ee, 0x78a5636f, 0x84c87814, 0x8cc70208, 0x90befffa, 0xa4506ceb, 0xbef9a3f7,
+        0xc67178f2,
+    ];
+
+    const INITIAL_STATE: [u32; 8] = [
+        0x6a09e667, 0xbb67ae85, 0x3c6ef372, 0xa54ff53a, 0x510e527f, 0x9b05688c, 0x1f83d9ab,
+        0x5be0cd19,
+    ];
+
+    fn compress(state: &mut [u32; 8], block: &[u8]) {
+        let mut schedule = [0u32; 64];
+        for (word, bytes) in schedule.iter_mut().zip(block.chunks_exact(4)) {
+            *word = u32::from_be_bytes(bytes.try_into().unwrap());
+        }
+        for i in 16..64 {
+            let (w15, w2) = (schedule[i - 15], schedule[i - 2]);
+            let s0 = w15.rotate_right(7) ^ w15.rotate_right(18) ^ (w15 >> 3);
+            let s1 = w2.rotate_right(17) ^ w2.rotate_right(19) ^ (w2 >> 10);
+            schedule[i] = schedule[i - 16]
+                .wrapping_add(s0)
+                .wrapping_add(schedule[i - 7])
+                .wrapping_add(s1);
+        }
+
+        let [mut a, mut b, mut c, mut d, mut e, mut f, mut g, mut h] = *state;
+        for (round_constant, word) in Self::ROUND_CONSTANTS.iter().zip(schedule) {
+            let s1 = e.rotate_right(6) ^ e.rotate_right(11) ^ e.rotate_right(25);
+            let choice = (e & f) ^ (!e & g);
+            let t1 = h
+                .wrapping_add(s1)
+                .wrapping_add(choice)
+                .wrapping_add(*round_constant)
+                .wrapping_add(word);
+            let s0 = a.rotate_right(2) ^ a.rotate_right(13) ^ a.rotate_right(22);
+            let majority = (a & b) ^ (a & c) ^ (b & c);
+            let t2 = s0.wrapping_add(majority);
+
+            (h, g, f, e, d, c, b, a) = (g, f, e, d.wrapping_add(t1), c, b, a, t1.wrapping_add(t2));
+        }
+
+        for (word, new) in state.iter_mut().zip([a, b, c, d, e, f, g, h]) {
+            *word = word.wrapping_add(new);
+        }
+    }
+}
+
+impl MerkleHasher for Sha256 {
+    type Digest = [u8; 32];
+
+    fn hash(data: &[u8]) -> Self::Digest {
+        let mut state = Self::INITIAL_STATE;
+        let full_blocks = data.len() / 64 * 64;
+        for block in data[..full_blocks].chunks_exact(64) {
+            Self::compress(&mut state, block);
+        }
+
+        // Pad with a one bit, zeros and the length in bits, into one or two final blocks
+        let mut tail = data[full_blocks..].to_vec();
+        tail.push(0x80);
+        tail.resize(if tail.len() <= 56 { 56 } else { 120 }, 0);
+        tail.extend_from_slice(&(data.len() as u64 * 8).to_be_bytes());
+        for block in tail.chunks_exact(64) {
+            Self::compress(&mut state, block);
+        }
+
+        let mut digest = [0; 32];
+        for (bytes, word) in digest.chunks_exact_mut(4).zip(state) {
+            bytes.copy_from_slice(&word.to_be_bytes());
+        }
+        digest
+    }
+}
+
+/// Turns the tree into a Merkle tree. The augment value of a node is a hash over the
+/// [encoded](Encode) pairs of the node and the augment values of its children, in order, so the
+/// [root hash](BTree::root_hash) commits to every pair in the tree. [`BTree::prove`] creates a
+/// proof that a pair is in the tree, which anyone holding the root hash can check with [`verify`].
+///
+/// The hash of a node depends on how the pairs are spread over the nodes, so two trees with the
+/// same pairs only have the same root hash if they were built by the same operations. Every
+/// modification recomputes the hashes of the nodes it touches, see
+/// [`Augment::RECOMPUTE_ALWAYS`].
+///
+/// ```
+/// use b_tree::augments::{self, HashAugment};
+/// use b_tree::BTree;
+///
+/// let mut store = BTree::with_augment::<HashAugment>();
+/// store.insert(1u32, "alice".to_string());
+/// store.insert(2u32, "bob".to_string());
+///
+/// let root_hash = store.root_hash();
+/// let proof = store.prove(&2).unwrap();
+/// assert!(augments::verify(&root_hash, &2u32, &"bob".to_string(), &proof));
+/// assert!(!augments::verify(&root_hash, &2u32, &"eve".to_string(), &proof));
+/// ```
+pub struct HashAugment<H = Sha256>(PhantomData<H>);
+
+impl<H: MerkleHasher> HashAugment<H> {
+    const RECOMPUTED: &'static str = "the tree recomputes hash augment values instead";
+
+    /// The key is prefixed with its length, so that no two pairs encode to the same bytes
+    fn pair_digest<K: Encode, V: Encode>(key: &K, value: &V) -> H::Digest {
+        let mut key_bytes = Vec::new();
+        key.encode(&mut key_bytes);
+
+        let mut bytes = vec![0];
+        bytes.extend_from_slice(&(key_bytes.len() as u64).to_le_bytes());
+        bytes.extend_from_slice(&key_bytes);
+        value.encode(&mut bytes);
+        H::hash(&bytes)
+    }
+
+    /// Hashes the digests of the pairs and children of a node. The leading byte tells nodes apart
+    /// from pairs.
+    fn node_digest<'a>(items: impl Iterator<Item = &'a H::Digest>) -> H::Digest
+    where
+        H::Digest: 'a,
+    {
+        let mut bytes = vec![1];
+        for item in items {
+            bytes.extend_from_slice(item.as_ref());
+        }
+        H::hash(&bytes)
+    }
+
+    /// The digests of the children and pairs of a node, in order, starting with the first child
+    fn items<'a, K: Encode, V: Encode>(
+        pairs: &[(K, V)],
+        mut children: impl Iterator<Item = &'a H::Digest>,
+    ) -> Vec<H::Digest>
+    where
+        H::Digest: 'a,
+    {
+        let mut items = Vec::with_capacity(2 * pairs.len() + 1);
+        for (key, value) in pairs {
+            items.extend(children.next().cloned());
+            items.push(Self::pair_digest(key, value));
+        }
+        items.extend(children.next().cloned());
+        items
+    }
+}
+
+impl<K: Encode, V: Encode, H: MerkleHasher> Augment<K, V> for HashAugment<H>
+where
+    H::Digest: 'static,
+{
+    type Value = H::Digest;
+    type Output = ();
+
+    const RECOMPUTE_ON_DELETE: bool = true;
+    const RECOMPUTE_ALWAYS: bool = true;
+
+    fn initial_value() -> Self::Value {
+        Self::node_digest([].iter())
+    }
+
+    fn initial_output() -> Self::Output {}
+
+    fn inserted_sub_tree(_: &K, _: &V, _: &Self::Value) -> Self::Value {
+        unreachable!("{}", Self::RECOMPUTED)
+    }
+
+    fn deleted_sub_tree(_: &K, _: &V, _: &Self::Value) -> Self::Value {
+        unreachable!("{}", Self::RECOMPUTED)
+    }
+
+    fn split<'a>(
+        left_keys: &[(K, V)],
+        right_keys: &[(K, V)],
+        _: &(K, V),
+        left_children: impl Iterator<Item = &'a Self::Value>,
+        right_children: impl Iterator<Item = &'a Self::Value>,
+        _: &Self::Value,
+    ) -> (Self::Value, Self::Value)
+    where
+        Self::Value: 'a,
+    {
+        (
+            Self::node_digest(Self::items(left_keys, left_children).iter()),
+            Self::node_digest(Self::items(right_keys, right_children).iter()),
+        )
+    }
+
+    fn split_root(root_pair: &(K, V), left: &Self::Value, right: &Self::Value) -> Self::Value {
+        let pair = Self::pair_digest(&root_pair.0, &root_pair.1);
+        Self::node_digest([left, &pair, right].into_iter())
+    }
+
+    fn merge(_: &(K, V), _: &Self::Value, _: &Self::Value) -> Self::Value {
+        unreachable!("{}", Self::RECOMPUTED)
+    }
+
+    fn steal(
+        _: &(K, V),
+        _: &(K, V),
+        _: Option<&Self::Value>,
+        _: &Self::Value,
+        _: &Self::Value,
+    ) -> (Self::Value, Self::Value) {
+        unreachable!("{}", Self::RECOMPUTED)
+    }
+
+    fn visit<'a>(
+        _: bool,
+        _: usize,
+        _: &[(K, V)],
+        _: impl Iterator<Item = &'a Self::Value>,
+        _: &Self::Value,
+        _: Self::Output,
+    ) -> Self::Output
+    where
+        Self::Value: 'a,
+    {
+    }
+
+    fn compute<'a>(
+        pairs: &[(K, V)],
+        children: impl DoubleEndedIterator<Item = &'a Self::Value>,
+    ) -> Self::Value
+    where
+        Self::Value: 'a,
+    {
+        Self::node_digest(Self::items(pairs, children).iter())
+    }
+
+    fn identical_sub_trees(a: &Self::Value, b: &Self::Value) -> bool {
+        a == b
+    }
+}
+
+impl<K, V, H, S> BTree<K, V, HashAugment<H>, S>
+where
+    K: Ord + Encode,
+    V: Encode,
+    H: MerkleHasher,
+    H::Digest: 'static,
+    S: Sharing<K, V, HashAugment<H>>,
+{
+    /// Returns the hash of the root node, which commits to every pair in the tree
+    pub fn root_hash(&self) -> H::Digest {
+        self.check_poison();
+        self.root.aug_val.clone()
+    }
+
+    /// Returns a proof that `key` is in the tree along with its current value, or `None` if it is
+    /// not. The proof holds `O(log n)` digests.
+    pub fn prove(&self, key: &K) -> Option<Proof<H>> {
+        self.check_poison();
+        let mut levels = Vec::new();
+        let mut node = &*self.root;
+        loop {
+            let mut items =
+                HashAugment::<H>::items(node.pairs(), node.children().iter().map(|c| &c.aug_val));
+            let (idx, child) = match node.find_key_idx(key) {
+                Ok(idx) if node.is_leaf() => (idx, None),
+                Ok(idx) => (2 * idx + 1, None),
+                Err(_) if node.is_leaf() => return None,
+                Err(idx) => (2 * idx, Some(&node.children()[idx])),
+            };
+            items.remove(idx);
+            levels.push(ProofLevel { items, idx });
+
+            match child {
+                Some(child) => node = child,
+                None => break,
+            }
+        }
+
+        levels.reverse();
+        Some(Proof { levels })
+    }
+}
+
+/// A proof that a pair is in a tree with a [`HashAugment`], created by [`BTree::prove`] and
+/// checked by [`verify`]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Proof<H: MerkleHasher = Sha256> {
+    /// The nodes on the path from the node holding the pair up to the root
+    pub levels: Vec<ProofLevel<H::Digest>>,
+}
+
+/// A node on the path of a [`Proof`]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ProofLevel<D> {
+    /// The digests of the children and pairs of the node, in order, without the one on the path
+    pub items: Vec<D>,
+    /// Where the digest on the path goes among `items`
+    pub idx: usize,
+}
+
+/// Checks that `proof` shows `key` and `value` to be a pair of the tree with root hash
+/// `root_hash`
+pub fn verify<K: Encode, V: Encode, H: MerkleHasher>(
+    root_hash: &H::Digest,
+    key: &K,
+    value: &V,
+    proof: &Proof<H>,
+) -> bool {
+    let mut digest = HashAugment::<H>::pair_digest(key, value);
+    for ProofLevel { items, idx } in &proof.levels {
+        if *idx > items.len() {
+            return false;
+        }
+        let (before, after) = items.split_at(*idx);
+        digest = HashAugment::<H>::node_digest(before.iter().chain([&digest]).chain(after));
+    }
+    !proof.levels.is_empty() && digest == *root_hash
+}
+
 #[cfg(test)]
 mod tests {
-    use crate::augments::SumAugment;
+    use std::collections::BTreeMap;
+    use std::panic::{self, AssertUnwindSafe};
+    use std::string::String;
+    use std::vec::Vec;
+
+    use crate::augments::{
+        verify, HashAugment, IntervalAugment, LazySumAugment, MerkleHasher, Sha256, SumAugment,
+    };
+    use crate::persistent::PersistentBTree;
+    use crate::tests::{check_tree, rng};
     use crate::BTree;
 
     #[test]
@@ -185,8 +1345,8 @@ mod tests {
             tree.insert(i, i);
         }
 
-        assert_eq!(tree.augment_search(&2000), (0..1000).sum());
-        assert_eq!(tree.augment_search(&750), (0..=750).sum());
+        assert_eq!(tree.augment_search(&2000), (0..1000).sum::<i32>());
+        assert_eq!(tree.augment_search(&750), (0..=750).sum::<i32>());
         assert_eq!(
             tree.augment_search(&3400),
             (0..1000).sum::<i32>() + (3000..=3400).sum::<i32>()
@@ -251,8 +1411,8 @@ mod tests {
             assert_eq!(tree.delete(&i), Some(i));
         }
 
-        assert_eq!(tree.augment_search(&2000), (0..1000).sum());
-        assert_eq!(tree.augment_search(&750), (0..=750).sum());
+        assert_eq!(tree.augment_search(&2000), (0..1000).sum::<i32>());
+        assert_eq!(tree.augment_search(&750), (0..=750).sum::<i32>());
         assert_eq!(
             tree.augment_search(&3400),
             (0..1000).sum::<i32>() + (3000..=3400).sum::<i32>()
@@ -262,4 +1422,195 @@ mod tests {
             (0..1000).sum::<i32>() + (3000..4000).sum::<i32>()
         );
     }
+
+    #[test]
+    fn range_updates_match_model() {
+        let mut tree = BTree::with_augment::<LazySumAugment>();
+        let mut model = BTreeMap::new();
+
+        let mut next = rng::<u32>(0x9e37_79b9);
+        for step in 0..5000 {
+            let state = next();
+            let key = (state >> 4) % 1000;
+            match state % 4 {
+                0 => assert_eq!(tree.delete(&key).is_some(), model.remove(&key).is_some()),
+                1 => {
+                    let end = key + (state >> 12) % 300;
+                    let delta = i64::from(state % 7) - 3;
+                    tree.update_range(key..end, &delta);
+                    for (_, value) in model.range_mut(key..end) {
+                        *value += delta;
+                    }
+                }
+                _ => {
+                    let value = i64::from(key);
+                    assert_eq!(tree.insert(key, value), !model.contains_key(&key));
+                    model.entry(key).or_insert(value);
+                }
+            }
+
+            if step % 100 == 0 {
+                for key in (0..1000).step_by(37) {
+                    let expected: i64 = model.range(..=key).map(|(_, v)| v).sum();
+                    assert_eq!(tree.augment_search(&key), expected);
+                }
+            }
+        }
+
+        tree.apply_pending();
+        check_tree(&tree);
+        assert!(tree.iter().eq(model.iter()));
+    }
+
+    #[test]
+    fn reads_do_not_see_old_values() {
+        let mut tree: BTree<u32, i64, LazySumAugment> = (0..1000).map(|i| (i, 1)).collect();
+        tree.update_range(100..900, &5);
+        assert_eq!(tree.augment_search(&999), 5000);
+        assert!(tree.has_pending_changes());
+        let iter = panic::catch_unwind(AssertUnwindSafe(|| tree.iter().count()));
+        assert!(iter.is_err());
+        assert!(!tree.is_poisoned());
+
+        for key in 0..1000 {
+            let expected = if (100..900).contains(&key) { 6 } else { 1 };
+            assert_eq!(tree.search_updated(&key), Some(&expected));
+            assert_eq!(tree.search(&key), Some(&expected));
+        }
+        // Every pending change lies on the path to some key
+        assert!(!tree.has_pending_changes());
+        check_tree(&tree);
+    }
+
+    #[test]
+    fn range_updates_leave_snapshots_alone() {
+        let mut tree: PersistentBTree<u32, i64, LazySumAugment> =
+            (0..10_000).map(|i| (i, 1)).collect();
+        let snapshot = tree.snapshot();
+
+        tree.update_range(..5000, &2);
+        tree.update_range(2500.., &-1);
+        assert_eq!(tree.augment_search(&2499), 7500);
+        assert_eq!(tree.augment_search(&9999), 7500 + 5000);
+        assert_eq!(snapshot.augment_search(&9999), 10_000);
+
+        tree.apply_pending();
+        check_tree(&tree);
+        assert_eq!(tree.search(&0), Some(&3));
+        assert_eq!(tree.search(&3000), Some(&2));
+        assert!(snapshot.iter().all(|(_, &v)| v == 1));
+    }
+
+    #[test]
+    fn interval_queries_match_model() {
+        let mut tree = BTree::with_augment::<IntervalAugment>();
+        let mut model = BTreeMap::new();
+
+        let mut next = rng::<u32>(0x1234_5678);
+        for step in 0..6000 {
+            let start = next() % 2000;
+            if next().is_multiple_of(3) {
+                assert_eq!(tree.delete(&start), model.remove(&start));
+            } else {
+                let end = start + 1 + next() % 150;
+                assert_eq!(tree.insert(start, end), !model.contains_key(&start));
+                model.entry(start).or_insert(end);
+            }
+
+            if step % 300 == 0 {
+                check_tree(&tree);
+                for _ in 0..20 {
+                    let (from, to) = (next() % 2100, next() % 2100);
+                    let expected: Vec<_> = model
+                        .iter()
+                        .filter(|(&start, &end)| start < to && end > from)
+                        .collect();
+                    assert!(tree.overlapping(from..to).eq(expected));
+
+                    let expected: Vec<_> = model
+                        .range(..=from)
+                        .filter(|(_, &end)| end > from)
+                        .collect();
+                    assert!(tree.stabbing(&from).eq(expected));
+
+                    let largest = model.range(..=from).map(|(_, &end)| end).max();
+                    assert_eq!(tree.augment_search(&from), largest);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn sha256_matches_test_vectors() {
+        let hex =
+            |digest: [u8; 32]| -> String { digest.iter().map(|b| format!("{b:02x}")).collect() };
+        assert_eq!(
+            hex(Sha256::hash(b"")),
+            "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+        );
+        assert_eq!(
+            hex(Sha256::hash(b"abc")),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+        assert_eq!(
+            hex(Sha256::hash(
+                b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq"
+            )),
+            "248d6a61d20638b8e5c026930c3e6039a33ce45964ff2167f6ecedd419db06c1"
+        );
+        assert_eq!(
+            hex(Sha256::hash(
+                b"abcdefghbcdefghicdefghijdefghijkefghijklfghijklmghijklmnhijklmnoijklmnopjklmnopqklmnopqrlmnopqrsmnopqrstnopqrstu"
+            )),
+            "cf5b16a778af8380036ce59e7b0492370b249b11e8f07a51afac45037afee9d1"
+        );
+    }
+
+    #[test]
+    fn hash_proofs_verify_only_current_pairs() {
+        let mut tree = BTree::with_augment::<HashAugment>();
+        let mut twin = BTree::with_augment::<HashAugment>();
+        let mut model = BTreeMap::new();
+
+        let mut next = rng::<u32>(0x0ddb_a115);
+        for step in 0..3000 {
+            let key = next() % 1000;
+            if next().is_multiple_of(3) {
+                let before = tree.root_hash();
+                let removed = tree.delete(&key);
+                assert_eq!(removed, model.remove(&key));
+                twin.delete(&key);
+                // Deleting a missing key may still move pairs around, so only check the other way
+                assert!(removed.is_none() || tree.root_hash() != before);
+            } else {
+                let value = next() % 100;
+                assert_eq!(tree.insert(key, value), !model.contains_key(&key));
+                twin.insert(key, value);
+                model.entry(key).or_insert(value);
+            }
+
+            if step % 300 == 0 {
+                check_tree(&tree);
+                let root_hash = tree.root_hash();
+                assert_eq!(twin.root_hash(), root_hash);
+                for key in (0..1000).step_by(7) {
+                    let Some(value) = model.get(&key) else {
+                        assert!(tree.prove(&key).is_none());
+                        continue;
+                    };
+                    let proof = tree.prove(&key).unwrap();
+                    assert!(verify(&root_hash, &key, value, &proof));
+                    assert!(!verify(&root_hash, &key, &(value + 1), &proof));
+                    assert!(!verify(&root_hash, &(key + 1), value, &proof));
+                }
+            }
+        }
+
+        // A proof made before a pair changes does not verify against the new root hash
+        let (&key, &value) = model.iter().next().unwrap();
+        let proof = tree.prove(&key).unwrap();
+        tree.delete(&key);
+        tree.insert(key, value + 1);
+        assert!(!verify(&tree.root_hash(), &key, &value, &proof));
+    }
 }
@@ -0,0 +1,145 @@
+//! The list of children of an [`InternalNode`](crate::node::InternalNode)
+//!
+//! Children are pointers to nodes, kept in a fixed array inside the internal node itself, so an
+//! internal node is a single allocation. Leaves are a type of their own without such an array.
+//! Inserting or removing a child only moves pointers, never whole nodes.
+
+use core::mem::MaybeUninit;
+use core::ops::{Deref, DerefMut};
+use core::ptr;
+use core::slice;
+
+use crate::{slice_assume_init, slice_assume_init_mut, MIN_DEGREE};
+
+/// A list of at most `2 * MIN_DEGREE` children that derefs to a slice
+pub(crate) struct Children<C> {
+    /// The number of initialized children at the start of `edges`
+    len: usize,
+    edges: [MaybeUninit<C>; 2 * MIN_DEGREE],
+}
+
+impl<C> Children<C> {
+    pub(crate) const fn new() -> Self {
+        Self {
+            len: 0,
+            edges: [const { MaybeUninit::uninit() }; 2 * MIN_DEGREE],
+        }
+    }
+
+    pub(crate) fn insert(&mut self, idx: usize, child: C) {
+        assert!(idx <= self.len && self.len < 2 * MIN_DEGREE);
+        unsafe {
+            let slot = self.edges.as_mut_ptr().add(idx);
+            ptr::copy(slot, slot.add(1), self.len - idx);
+            slot.write(MaybeUninit::new(child));
+        }
+        self.len += 1;
+    }
+
+    pub(crate) fn push(&mut self, child: C) {
+        self.insert(self.len, child);
+    }
+
+    pub(crate) fn remove(&mut self, idx: usize) -> C {
+        assert!(idx < self.len);
+        self.len -= 1;
+        unsafe {
+            let slot = self.edges.as_mut_ptr().add(idx);
+            let child = slot.read().assume_init();
+            ptr::copy(slot.add(1), slot, self.len - idx);
+            child
+        }
+    }
+
+    pub(crate) fn pop(&mut self) -> Option<C> {
+        (self.len > 0).then(|| self.remove(self.len - 1))
+    }
+
+    /// Moves the children from index `at` onwards to the end of `other`
+    pub(crate) fn move_tail(&mut self, at: usize, other: &mut Self) {
+        assert!(at <= self.len && other.len + self.len - at <= 2 * MIN_DEGREE);
+        let moved = self.len - at;
+        unsafe {
+            let src = self.edges.as_ptr().add(at);
+            let dst = other.edges.as_mut_ptr().add(other.len);
+            ptr::copy_nonoverlapping(src, dst, moved);
+        }
+        self.len = at;
+        other.len += moved;
+    }
+
+    /// Moves all children of `other` to the end of the list
+    pub(crate) fn append(&mut self, other: &mut Self) {
+        other.move_tail(0, self);
+    }
+}
+
+impl<C> Deref for Children<C> {
+    type Target = [C];
+
+    fn deref(&self) -> &[C] {
+        unsafe { slice_assume_init(&self.edges[..self.len]) }
+    }
+}
+
+impl<C> DerefMut for Children<C> {
+    fn deref_mut(&mut self) -> &mut [C] {
+        unsafe { slice_assume_init_mut(&mut self.edges[..self.len]) }
+    }
+}
+
+impl<'a, C> IntoIterator for &'a Children<C> {
+    type Item = &'a C;
+    type IntoIter = slice::Iter<'a, C>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
+
+impl<C> IntoIterator for Children<C> {
+    type Item = C;
+    type IntoIter = IntoIter<C>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        IntoIter {
+            next: 0,
+            children: self,
+        }
+    }
+}
+
+impl<C> Drop for Children<C> {
+    fn drop(&mut self) {
+        unsafe { ptr::drop_in_place(self.deref_mut()) };
+    }
+}
+
+/// Moves the children out of a [`Children`] in order
+pub(crate) struct IntoIter<C> {
+    /// The children before `next` have been moved out already
+    next: usize,
+    children: Children<C>,
+}
+
+impl<C> Iterator for IntoIter<C> {
+    type Item = C;
+
+    fn next(&mut self) -> Option<C> {
+        if self.next == self.children.len {
+            return None;
+        }
+        let child = unsafe { self.children.edges[self.next].assume_init_read() };
+        self.next += 1;
+        Some(child)
+    }
+}
+
+impl<C> Drop for IntoIter<C> {
+    fn drop(&mut self) {
+        let remaining = &mut self.children.edges[self.next..self.children.len];
+        // The children that were moved out must not be dropped again with the list
+        self.children.len = 0;
+        unsafe { ptr::drop_in_place(slice_assume_init_mut(remaining)) };
+    }
+}
@@ -0,0 +1,1113 @@
+//! A B-tree that many threads can read and modify at the same time
+//!
+//! [`ConcurrentBTree`] protects every node with its own latch, so modifications of different parts
+//! of the tree run in parallel. Writers use latch coupling (crabbing): a writer latches a child
+//! before releasing its parent and never latches upwards, which rules out deadlocks. As in
+//! [`BTree`](crate::BTree), writers split full nodes and fill up minimal nodes on their way down,
+//! so a modification never has to go back up the tree, and a node is released as soon as the next
+//! node on the path is latched.
+//!
+//! Readers take no latches at all. The contents of a node are never modified in place: a writer
+//! changes a copy of them, which replaces the original when the node is released. Every node also
+//! has a version counter, which is odd while the node is latched. A reader records the version of
+//! every node it reads, and once it has read a child, checks that the version of the parent has not
+//! changed, so the two are known to have been consistent with each other. Otherwise it starts over
+//! from the root. Contents that have been replaced are freed through epoch-based reclamation once
+//! no reader can be looking at them any more.
+//!
+//! The augment value of a node is stored next to the pointer to it in its parent, so visiting a
+//! node never requires reading its children. Writers update the augment values on the way down
+//! as well, which only works if they know in advance that the operation will change the tree.
+//! Every insert and delete therefore first looks up its key while holding a lock for that key,
+//! which keeps the key from being inserted or deleted by anyone else in the meantime. The one
+//! exception to the top-down order is deleting a pair from an internal node: the latches below it
+//! are held until the pair replacing it is found, and the augment values along that path are
+//! updated on the way back up.
+
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hash};
+use std::mem;
+use std::ops::{Bound, Deref, DerefMut, RangeBounds};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread;
+
+use crossbeam_epoch::{self as epoch, Atomic, Guard, Owned};
+
+use crate::{Augment, MIN_DEGREE};
+
+/// The number of locks keys are spread over
+const KEY_LOCKS: usize = 64;
+
+const POISONED: &str = "ConcurrentBTree is poisoned: an operation panicked while modifying it";
+
+/// A pointer to a node along with the augment value of its subtree
+struct Edge<K, V, A: Augment<K, V>> {
+    node: Arc<Node<K, V, A>>,
+    aug_val: A::Value,
+}
+
+/// The contents of a node, which are replaced as a whole whenever the node is modified
+struct Contents<K, V, A: Augment<K, V>> {
+    pairs: Vec<(K, V)>,
+    children: Vec<Edge<K, V, A>>,
+}
+
+struct Node<K, V, A: Augment<K, V>> {
+    latch: Mutex<()>,
+    /// Odd while the node is latched
+    version: AtomicU64,
+    contents: Atomic<Contents<K, V, A>>,
+}
+
+/// A latched node. Changes are made to a copy of its contents, which replaces the original when
+/// the latch is released.
+struct Latched<'a, K, V, A: Augment<K, V>> {
+    node: &'a Node<K, V, A>,
+    copy: Option<Contents<K, V, A>>,
+    /// Whether the thread was already panicking when the node was latched
+    panicking: bool,
+    _latch: MutexGuard<'a, ()>,
+}
+
+impl<K, V, A: Augment<K, V>> Edge<K, V, A> {
+    fn new(contents: Contents<K, V, A>, aug_val: A::Value) -> Self {
+        Self {
+            node: Arc::new(Node::new(contents)),
+            aug_val,
+        }
+    }
+}
+
+impl<K, V, A: Augment<K, V>> Clone for Edge<K, V, A>
+where
+    A::Value: Clone,
+{
+    fn clone(&self) -> Self {
+        Self {
+            node: Arc::clone(&self.node),
+            aug_val: self.aug_val.clone(),
+        }
+    }
+}
+
+impl<K: Clone, V: Clone, A: Augment<K, V>> Clone for Contents<K, V, A>
+where
+    A::Value: Clone,
+{
+    fn clone(&self) -> Self {
+        let mut pairs = Vec::with_capacity(2 * MIN_DEGREE - 1);
+        pairs.extend_from_slice(&self.pairs);
+        Self {
+            pairs,
+            children: self.children.clone(),
+        }
+    }
+}
+
+impl<K: Ord, V, A: Augment<K, V>> Contents<K, V, A> {
+    fn new_leaf() -> Self {
+        Self {
+            pairs: Vec::with_capacity(2 * MIN_DEGREE - 1),
+            children: Vec::new(),
+        }
+    }
+
+    fn find_key_idx(&self, key: &K) -> Result<usize, usize> {
+        self.pairs.binary_search_by(|(k, _)| k.cmp(key))
+    }
+
+    /// Finds `key` in the node and, if there is an output to accumulate, visits the node, whose
+    /// augment value is `aug_val`
+    fn visit(
+        &self,
+        key: &K,
+        aug_val: &A::Value,
+        acc: Option<A::Output>,
+    ) -> (Result<usize, usize>, Option<A::Output>) {
+        let res = self.find_key_idx(key);
+        let acc = acc.map(|acc| {
+            let (idx, found) = match res {
+                Ok(i) => (i, true),
+                Err(i) => (i, false),
+            };
+            let children = self.children.iter().map(|e| &e.aug_val);
+            A::visit(found, idx, &self.pairs, children, aug_val, acc)
+        });
+        (res, acc)
+    }
+
+    /// Splits the full node in two. `aug_val` is replaced by the augment value of the left half.
+    fn split(&mut self, aug_val: &mut A::Value) -> ((K, V), Edge<K, V, A>) {
+        debug_assert!(self.is_full());
+
+        let right_pairs = self.pairs.split_off(MIN_DEGREE);
+        let median = self.pairs.pop().unwrap();
+        let right_children = if self.is_leaf() {
+            Vec::new()
+        } else {
+            self.children.split_off(MIN_DEGREE)
+        };
+
+        let right_aug_val;
+        (*aug_val, right_aug_val) = A::split(
+            &self.pairs,
+            &right_pairs,
+            &median,
+            self.children.iter().map(|e| &e.aug_val),
+            right_children.iter().map(|e| &e.aug_val),
+            aug_val,
+        );
+
+        let right = Contents {
+            pairs: right_pairs,
+            children: right_children,
+        };
+        (median, Edge::new(right, right_aug_val))
+    }
+
+    fn is_min(&self) -> bool {
+        self.pairs.len() < MIN_DEGREE
+    }
+
+    fn is_full(&self) -> bool {
+        self.pairs.len() == 2 * MIN_DEGREE - 1
+    }
+
+    fn is_leaf(&self) -> bool {
+        self.children.is_empty()
+    }
+}
+
+impl<K, V, A: Augment<K, V>> Node<K, V, A> {
+    fn new(contents: Contents<K, V, A>) -> Self {
+        Self {
+            latch: Mutex::new(()),
+            version: AtomicU64::new(0),
+            contents: Atomic::new(contents),
+        }
+    }
+
+    /// Returns the version and contents of the node, unless it is latched
+    fn read<'g>(&self, guard: &'g Guard) -> Option<(u64, &'g Contents<K, V, A>)> {
+        let version = self.version.load(Ordering::SeqCst);
+        if version % 2 == 1 {
+            return None;
+        }
+        // Safety: replaced contents are not freed until every thread that was pinned when they
+        // were replaced has unpinned, and the node is kept alive by contents referring to it
+        let contents = unsafe { self.contents.load(Ordering::SeqCst, guard).deref() };
+        Some((version, contents))
+    }
+
+    /// Fails if the node has been latched since it was read at `version`
+    fn validate(&self, version: u64) -> Option<()> {
+        (self.version.load(Ordering::SeqCst) == version).then_some(())
+    }
+
+    /// Returns the current contents of the node
+    ///
+    /// # Safety
+    /// The caller must hold the latch of the node, as only the holder of the latch replaces the
+    /// contents
+    unsafe fn current(&self) -> &Contents<K, V, A> {
+        unsafe {
+            self.contents
+                .load(Ordering::SeqCst, epoch::unprotected())
+                .deref()
+        }
+    }
+}
+
+impl<K, V, A> Node<K, V, A>
+where
+    K: Ord + Clone + Send + 'static,
+    V: Clone + Send + 'static,
+    A: Augment<K, V>,
+    A::Value: Clone + Send + 'static,
+{
+    fn latch(&self) -> Latched<'_, K, V, A> {
+        let latch = self.latch.lock().expect(POISONED);
+        self.version.fetch_add(1, Ordering::SeqCst);
+        Latched {
+            node: self,
+            copy: None,
+            panicking: thread::panicking(),
+            _latch: latch,
+        }
+    }
+
+    /// Appends the pairs of the subtree that lie in `range` to `pairs`, and returns whether the
+    /// scan should go on. Fails if the node, or its parent read at `parent_version`, is latched
+    /// before the subtree has been scanned.
+    fn scan(
+        &self,
+        parent: &Self,
+        parent_version: u64,
+        range: (Bound<&K>, Bound<&K>),
+        pairs: &mut Vec<(K, V)>,
+        guard: &Guard,
+    ) -> Option<bool> {
+        let (version, node) = self.read(guard)?;
+        parent.validate(parent_version)?;
+
+        let first = match range.0 {
+            Bound::Included(start) => node.pairs.partition_point(|(k, _)| k < start),
+            Bound::Excluded(start) => node.pairs.partition_point(|(k, _)| k <= start),
+            Bound::Unbounded => 0,
+        };
+        for idx in first..=node.pairs.len() {
+            if !node.is_leaf() {
+                let child = &node.children[idx].node;
+                if !child.scan(self, version, range, pairs, guard)? {
+                    return Some(false);
+                }
+            }
+
+            let Some((key, value)) = node.pairs.get(idx) else {
+                break;
+            };
+            let in_range = match range.1 {
+                Bound::Included(end) => key <= end,
+                Bound::Excluded(end) => key < end,
+                Bound::Unbounded => true,
+            };
+            if !in_range {
+                self.validate(version)?;
+                return Some(false);
+            }
+            pairs.push((key.clone(), value.clone()));
+        }
+
+        self.validate(version)?;
+        Some(true)
+    }
+}
+
+impl<K, V, A: Augment<K, V>> Drop for Node<K, V, A> {
+    fn drop(&mut self) {
+        // Readers only reach a node through contents referring to it, which are not freed while
+        // readers may be looking at them, so no reader can be looking at this node either
+        unsafe {
+            drop(
+                self.contents
+                    .load(Ordering::SeqCst, epoch::unprotected())
+                    .into_owned(),
+            )
+        }
+    }
+}
+
+impl<K, V, A: Augment<K, V>> Deref for Latched<'_, K, V, A> {
+    type Target = Contents<K, V, A>;
+
+    fn deref(&self) -> &Self::Target {
+        match &self.copy {
+            Some(copy) => copy,
+            // Safety: the node is latched
+            None => unsafe { self.node.current() },
+        }
+    }
+}
+
+impl<K: Clone, V: Clone, A: Augment<K, V>> DerefMut for Latched<'_, K, V, A>
+where
+    A::Value: Clone,
+{
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        if self.copy.is_none() {
+            self.copy = Some(Contents::clone(self));
+        }
+        self.copy.as_mut().unwrap()
+    }
+}
+
+impl<K, V, A> Latched<'_, K, V, A>
+where
+    K: Ord + Clone + Send + 'static,
+    V: Clone + Send + 'static,
+    A: Augment<K, V>,
+    A::Value: Clone + Send + 'static,
+{
+    /// Inserts the pair into the subtree of the node, whose augment value the caller has already
+    /// updated. The key must not be in the subtree.
+    fn insert(mut self, key: K, value: V) {
+        debug_assert!(!self.is_full());
+
+        let mut idx = self.find_key_idx(&key).expect_err("key is in the tree");
+        if self.is_leaf() {
+            self.pairs.insert(idx, (key, value));
+            return;
+        }
+
+        let mut link = Arc::clone(&self.children[idx].node);
+        let mut child = link.latch();
+        if child.is_full() {
+            let (median, right) = child.split(&mut self.children[idx].aug_val);
+            let go_right = key > median.0;
+            self.pairs.insert(idx, median);
+            self.children.insert(idx + 1, right);
+
+            if go_right {
+                idx += 1;
+                drop(child);
+                link = Arc::clone(&self.children[idx].node);
+                child = link.latch();
+            }
+        }
+
+        let edge = &mut self.children[idx];
+        edge.aug_val = A::inserted_sub_tree(&key, &value, &edge.aug_val);
+        drop(self);
+        child.insert(key, value)
+    }
+
+    /// Deletes `key` from the subtree of the node, whose augment value the caller has already
+    /// updated. The key must be in the subtree, with a value equal to `value`.
+    fn delete(mut self, key: &K, value: &V) -> V {
+        let idx = match self.find_key_idx(key) {
+            Ok(idx) => return self.delete_own(key, value, idx),
+            Err(idx) => idx,
+        };
+        debug_assert!(!self.is_leaf(), "key is not in the tree");
+
+        let idx = if self.child_is_min(idx) {
+            self.make_space(idx)
+        } else {
+            idx
+        };
+
+        let edge = &mut self.children[idx];
+        edge.aug_val = A::deleted_sub_tree(key, value, &edge.aug_val);
+        let link = Arc::clone(&edge.node);
+        let child = link.latch();
+        drop(self);
+        child.delete(key, value)
+    }
+
+    fn delete_own(mut self, key: &K, value: &V, idx: usize) -> V {
+        if self.is_leaf() {
+            return self.pairs.remove(idx).1;
+        }
+
+        if !self.child_is_min(idx) {
+            let pair = self.delete_extreme(idx, true);
+            return mem::replace(&mut self.pairs[idx], pair).1;
+        }
+        if !self.child_is_min(idx + 1) {
+            let pair = self.delete_extreme(idx + 1, false);
+            return mem::replace(&mut self.pairs[idx], pair).1;
+        }
+
+        self.merge_children(idx);
+        let edge = &mut self.children[idx];
+        edge.aug_val = A::deleted_sub_tree(key, value, &edge.aug_val);
+        let link = Arc::clone(&edge.node);
+        let child = link.latch();
+        drop(self);
+        child.delete_own(key, value, MIN_DEGREE - 1)
+    }
+
+    /// Deletes the largest pair of the subtree of child `idx` if `max` is set, and the smallest one
+    /// otherwise. The latches on the path are held until the pair is found, so the augment values
+    /// can be updated on the way back up.
+    fn delete_extreme(&mut self, idx: usize, max: bool) -> (K, V) {
+        let link = Arc::clone(&self.children[idx].node);
+        let mut child = link.latch();
+
+        let (key, value) = if child.is_leaf() {
+            if max {
+                child.pairs.pop().unwrap()
+            } else {
+                child.pairs.remove(0)
+            }
+        } else {
+            let next = if max { child.pairs.len() } else { 0 };
+            let next = if child.child_is_min(next) {
+                child.make_space(next)
+            } else {
+                next
+            };
+            child.delete_extreme(next, max)
+        };
+
+        let edge = &mut self.children[idx];
+        edge.aug_val = A::deleted_sub_tree(&key, &value, &edge.aug_val);
+        (key, value)
+    }
+
+    /// Merges child `idx + 1` and the pair separating it from child `idx` into child `idx`
+    fn merge_children(&mut self, idx: usize) {
+        let parent_pair = self.pairs.remove(idx);
+        let right = self.children.remove(idx + 1);
+        let left = &mut self.children[idx];
+        left.aug_val = A::merge(&parent_pair, &left.aug_val, &right.aug_val);
+
+        let mut left = left.node.latch();
+        // Readers may still be looking at the right child, so its contents are copied rather than
+        // moved out. Latching it also waits for writers that passed through it earlier.
+        let right = right.node.latch();
+        left.pairs.push(parent_pair);
+        left.pairs.extend_from_slice(&right.pairs);
+        left.children.extend_from_slice(&right.children);
+    }
+
+    /// Makes sure child `idx` has more than the minimum number of pairs, by stealing from or
+    /// merging with a sibling. Returns the new index of the child.
+    fn make_space(&mut self, mut idx: usize) -> usize {
+        if idx > 0 && !self.child_is_min(idx - 1) {
+            // Steal a key from the left sibling (through parent)
+            let victim_link = Arc::clone(&self.children[idx - 1].node);
+            let thief_link = Arc::clone(&self.children[idx].node);
+            let mut victim = victim_link.latch();
+            let mut thief = thief_link.latch();
+
+            let sibling_pair = victim.pairs.pop().unwrap();
+            let stolen_child = victim.children.pop();
+            self.steal(idx - 1, sibling_pair, stolen_child, &mut thief, true);
+        } else if idx < self.pairs.len() && !self.child_is_min(idx + 1) {
+            // Steal a key from the right sibling (through parent)
+            let thief_link = Arc::clone(&self.children[idx].node);
+            let victim_link = Arc::clone(&self.children[idx + 1].node);
+            let mut thief = thief_link.latch();
+            let mut victim = victim_link.latch();
+
+            let sibling_pair = victim.pairs.remove(0);
+            let stolen_child = (!victim.is_leaf()).then(|| victim.children.remove(0));
+            self.steal(idx, sibling_pair, stolen_child, &mut thief, false);
+        } else if idx > 0 {
+            // We can merge with the left sibling
+            idx -= 1;
+            self.merge_children(idx);
+        } else {
+            // Merge with right sibling
+            self.merge_children(idx);
+        }
+
+        idx
+    }
+
+    /// Moves pair `pair_idx` into `thief` and replaces it by `sibling_pair`, which was taken from a
+    /// sibling along with `stolen_child`. `from_left` tells which side of `thief` the sibling is on.
+    fn steal(
+        &mut self,
+        pair_idx: usize,
+        sibling_pair: (K, V),
+        stolen_child: Option<Edge<K, V, A>>,
+        thief: &mut Contents<K, V, A>,
+        from_left: bool,
+    ) {
+        let (thief_idx, victim_idx) = if from_left {
+            (pair_idx + 1, pair_idx)
+        } else {
+            (pair_idx, pair_idx + 1)
+        };
+        let (thief_val, victim_val) = A::steal(
+            &self.pairs[pair_idx],
+            &sibling_pair,
+            stolen_child.as_ref().map(|e| &e.aug_val),
+            &self.children[thief_idx].aug_val,
+            &self.children[victim_idx].aug_val,
+        );
+        self.children[thief_idx].aug_val = thief_val;
+        self.children[victim_idx].aug_val = victim_val;
+
+        let parent_pair = mem::replace(&mut self.pairs[pair_idx], sibling_pair);
+        if from_left {
+            thief.pairs.insert(0, parent_pair);
+            thief.children.splice(0..0, stolen_child);
+        } else {
+            thief.pairs.push(parent_pair);
+            thief.children.extend(stolen_child);
+        }
+    }
+
+    /// Returns whether child `idx` has the minimum number of pairs, once any writer in it is done.
+    /// Its version is left alone, as readers do not need to start over when nothing changes.
+    fn child_is_min(&self, idx: usize) -> bool {
+        let child = &self.children[idx].node;
+        let _latch = child.latch.lock().expect(POISONED);
+        // Safety: the latch is held
+        unsafe { child.current() }.is_min()
+    }
+}
+
+impl<K, V, A: Augment<K, V>> Drop for Latched<'_, K, V, A> {
+    fn drop(&mut self) {
+        // A copy that was being modified when a panic started may be in any state, so it is
+        // thrown away
+        let unwinding = !self.panicking && thread::panicking();
+        if let Some(copy) = self.copy.take().filter(|_| !unwinding) {
+            let guard = epoch::pin();
+            let old = self
+                .node
+                .contents
+                .swap(Owned::new(copy), Ordering::SeqCst, &guard);
+            // Safety: nodes can only be latched if their contents may be freed on any thread, and
+            // the old contents are not freed until no reader can be looking at them
+            unsafe { guard.defer_destroy(old) };
+        }
+        self.node.version.fetch_add(1, Ordering::SeqCst);
+    }
+}
+
+/// Poisons the tree if it is dropped because of a panic that started after it was created
+struct PoisonOnUnwind<'a> {
+    poisoned: &'a AtomicBool,
+    panicking: bool,
+}
+
+impl Drop for PoisonOnUnwind<'_> {
+    fn drop(&mut self) {
+        if !self.panicking && thread::panicking() {
+            self.poisoned.store(true, Ordering::Relaxed);
+        }
+    }
+}
+
+/// A B-tree that can be shared between threads. See the [module documentation](self) for how it
+/// works.
+///
+/// Lookups never block and return clones of the values, as writers may replace the contents of a
+/// node at any time. Modifying a node means copying its contents, so keys, values and augment
+/// values must be [`Clone`]. They must also be [`Send`] and `'static`, as replaced contents are
+/// freed by whichever thread finds that no reader is using them any more. Keys must be [`Hash`], as
+/// inserts and deletes lock their key through a fixed set of striped locks picked by its hash.
+///
+/// If a key comparison or [`Augment`] hook panics while the tree is being modified, the tree is
+/// poisoned like [`BTree`](crate::BTree#panic-safety) is, and every later operation on it panics.
+pub struct ConcurrentBTree<K, V, A: Augment<K, V> = ()> {
+    /// Holds no pairs, only the edge to the root, so replacing the root works like modifying any
+    /// other node
+    head: Node<K, V, A>,
+    len: AtomicUsize,
+    key_locks: Box<[Mutex<()>]>,
+    hasher: RandomState,
+    poisoned: AtomicBool,
+}
+
+impl<K: Ord + Hash, V> ConcurrentBTree<K, V> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_augment<T: Augment<K, V>>() -> ConcurrentBTree<K, V, T> {
+        ConcurrentBTree::default()
+    }
+}
+
+impl<K, V, A> ConcurrentBTree<K, V, A>
+where
+    K: Ord + Hash + Clone + Send + 'static,
+    V: Clone + Send + 'static,
+    A: Augment<K, V>,
+    A::Value: Clone + Send + 'static,
+{
+    pub fn insert(&self, key: K, value: V) -> bool {
+        let _key_lock = self.lock_key(&key);
+        let _unwind = self.poison_on_unwind();
+        if self.lookup(&key, false, |_| ()).0.is_some() {
+            return false;
+        }
+
+        let mut head = self.head.latch();
+        let mut link = Arc::clone(&head.children[0].node);
+        let mut node = link.latch();
+        if node.is_full() {
+            let root = &mut head.children[0];
+            let (median, right) = node.split(&mut root.aug_val);
+            drop(node);
+
+            let aug_val = A::split_root(&median, &root.aug_val, &right.aug_val);
+            let left_aug_val = mem::replace(&mut root.aug_val, aug_val);
+            let new_root = Contents {
+                pairs: vec![median],
+                children: vec![
+                    Edge {
+                        node: link,
+                        aug_val: left_aug_val,
+                    },
+                    right,
+                ],
+            };
+            root.node = Arc::new(Node::new(new_root));
+
+            link = Arc::clone(&root.node);
+            node = link.latch();
+        }
+
+        let root = &mut head.children[0];
+        root.aug_val = A::inserted_sub_tree(&key, &value, &root.aug_val);
+        self.len.fetch_add(1, Ordering::Relaxed);
+        drop(head);
+        node.insert(key, value);
+        true
+    }
+
+    pub fn delete(&self, key: &K) -> Option<V> {
+        let _key_lock = self.lock_key(key);
+        let _unwind = self.poison_on_unwind();
+        let value = self.lookup(key, false, V::clone).0?;
+
+        let mut head = self.head.latch();
+        let mut link = Arc::clone(&head.children[0].node);
+        let mut node = link.latch();
+        // The root only loses its last pair by merging its two children, so do that up front to
+        // be able to replace the root before releasing it
+        if node.pairs.len() == 1 && !node.is_leaf() && node.child_is_min(0) && node.child_is_min(1)
+        {
+            node.merge_children(0);
+            let child = node.children.pop().unwrap();
+            drop(node);
+
+            head.children[0] = child;
+            link = Arc::clone(&head.children[0].node);
+            node = link.latch();
+        }
+
+        let root = &mut head.children[0];
+        root.aug_val = A::deleted_sub_tree(key, &value, &root.aug_val);
+        self.len.fetch_sub(1, Ordering::Relaxed);
+        drop(head);
+        Some(node.delete(key, &value))
+    }
+
+    pub fn search(&self, key: &K) -> Option<V> {
+        self.lookup(key, false, V::clone).0
+    }
+
+    pub fn augment_search(&self, key: &K) -> A::Output {
+        self.lookup(key, true, |_| ()).1.unwrap()
+    }
+
+    /// Returns clones of the pairs with keys in `range`, in ascending order
+    ///
+    /// Like the other lookups, this takes no latches. When a writer modifies a node the scan
+    /// depends on, the scan starts over from the root, right after the last pair it found. Every
+    /// pair that stays in the tree during the whole scan is returned exactly once, while pairs
+    /// that are inserted or deleted meanwhile may or may not be.
+    pub fn range(&self, range: impl RangeBounds<K>) -> Vec<(K, V)> {
+        let mut pairs = Vec::new();
+        loop {
+            self.check_poison();
+            let start = match pairs.last() {
+                Some((key, _)) => Bound::Excluded(K::clone(key)),
+                None => range.start_bound().cloned(),
+            };
+
+            let guard = epoch::pin();
+            let bounds = (start.as_ref(), range.end_bound());
+            let done = self.head.read(&guard).and_then(|(version, head)| {
+                head.children[0]
+                    .node
+                    .scan(&self.head, version, bounds, &mut pairs, &guard)
+            });
+            if done.is_some() {
+                return pairs;
+            }
+            thread::yield_now();
+        }
+    }
+
+    /// Returns the number of pairs in the tree. Other threads may change it at any time.
+    pub fn len(&self) -> usize {
+        self.len.load(Ordering::Relaxed)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Returns `true` if a key comparison or augment hook panicked while the tree was being
+    /// modified
+    pub fn is_poisoned(&self) -> bool {
+        self.poisoned.load(Ordering::Relaxed)
+    }
+
+    /// Looks up `key` without taking any latches, visiting every node on the way if `visit` is set
+    fn lookup<R>(
+        &self,
+        key: &K,
+        visit: bool,
+        found: impl Fn(&V) -> R,
+    ) -> (Option<R>, Option<A::Output>) {
+        loop {
+            self.check_poison();
+            let guard = epoch::pin();
+            if let Some(res) = self.try_lookup(key, visit, &found, &guard) {
+                return res;
+            }
+            thread::yield_now();
+        }
+    }
+
+    /// Makes a single attempt at a lookup, which fails if a node on the path is latched meanwhile
+    fn try_lookup<R>(
+        &self,
+        key: &K,
+        visit: bool,
+        found: impl Fn(&V) -> R,
+        guard: &Guard,
+    ) -> Option<(Option<R>, Option<A::Output>)> {
+        let (mut parent_version, head) = self.head.read(guard)?;
+        let mut parent = &self.head;
+        let mut edge = &head.children[0];
+        let mut acc = visit.then(A::initial_output);
+
+        loop {
+            let (version, node) = edge.node.read(guard)?;
+            parent.validate(parent_version)?;
+
+            let res;
+            (res, acc) = node.visit(key, &edge.aug_val, acc);
+            let value = match res {
+                Ok(idx) => Some(found(&node.pairs[idx].1)),
+                Err(idx) if !node.is_leaf() => {
+                    parent = &edge.node;
+                    parent_version = version;
+                    edge = &node.children[idx];
+                    continue;
+                }
+                Err(_) => None,
+            };
+            edge.node.validate(version)?;
+            return Some((value, acc));
+        }
+    }
+
+    fn lock_key(&self, key: &K) -> MutexGuard<'_, ()> {
+        let idx = self.hasher.hash_one(key) as usize % KEY_LOCKS;
+        // The key locks guard no data, so a panic while holding one leaves nothing broken
+        self.key_locks[idx]
+            .lock()
+            .unwrap_or_else(|err| err.into_inner())
+    }
+
+    fn check_poison(&self) {
+        if self.is_poisoned() {
+            panic!("{POISONED}");
+        }
+    }
+
+    fn poison_on_unwind(&self) -> PoisonOnUnwind<'_> {
+        self.check_poison();
+        PoisonOnUnwind {
+            poisoned: &self.poisoned,
+            panicking: thread::panicking(),
+        }
+    }
+}
+
+impl<K: Ord + Hash, V, A: Augment<K, V>> Default for ConcurrentBTree<K, V, A> {
+    fn default() -> Self {
+        const {
+            assert!(
+                !A::RECOMPUTE_ON_DELETE && !A::RECOMPUTE_ALWAYS,
+                "ConcurrentBTree does not support augments that recompute their values"
+            )
+        };
+        let head = Contents {
+            pairs: Vec::new(),
+            children: vec![Edge::new(Contents::new_leaf(), A::initial_value())],
+        };
+        Self {
+            head: Node::new(head),
+            len: AtomicUsize::new(0),
+            key_locks: (0..KEY_LOCKS).map(|_| Mutex::new(())).collect(),
+            hasher: RandomState::new(),
+            poisoned: AtomicBool::new(false),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+    use std::ops::Bound;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+    use std::thread;
+
+    use crossbeam_epoch as epoch;
+
+    use crate::augments::SumAugment;
+    use crate::concurrent::{ConcurrentBTree, Node};
+    use crate::tests::rng;
+    use crate::{Augment, MIN_DEGREE};
+
+    type Tree = ConcurrentBTree<u32, i64, SumAugment>;
+
+    const THREADS: u32 = 8;
+
+    /// Checks the structural invariants of a quiescent subtree and that its augment value agrees
+    /// with the sum of its values. Returns the height of the subtree.
+    fn check_node(node: &Node<u32, i64, SumAugment>, aug_val: i64, is_root: bool) -> usize {
+        let guard = epoch::pin();
+        let (_, node) = node.read(&guard).expect("node is latched");
+        assert!(node.pairs.len() < 2 * MIN_DEGREE);
+        assert!(is_root || node.pairs.len() >= MIN_DEGREE - 1);
+        assert!(node.pairs.windows(2).all(|w| w[0].0 < w[1].0));
+        let own: i64 = node.pairs.iter().map(|(_, v)| v).sum();
+        let below: i64 = node.children.iter().map(|e| e.aug_val).sum();
+        assert_eq!(aug_val, own + below);
+
+        if node.is_leaf() {
+            return 0;
+        }
+        assert_eq!(node.children.len(), node.pairs.len() + 1);
+        let heights: Vec<_> = node
+            .children
+            .iter()
+            .map(|e| check_node(&e.node, e.aug_val, false))
+            .collect();
+        assert!(heights.windows(2).all(|w| w[0] == w[1]));
+        heights[0] + 1
+    }
+
+    fn check_against_model(tree: &Tree, model: &BTreeMap<u32, i64>) {
+        let guard = epoch::pin();
+        let (_, head) = tree.head.read(&guard).expect("head is latched");
+        check_node(&head.children[0].node, head.children[0].aug_val, true);
+        drop(guard);
+
+        assert_eq!(tree.len(), model.len());
+        let mut sum = 0;
+        for key in 0..=model.keys().max().copied().unwrap_or(0) {
+            sum += model.get(&key).copied().unwrap_or(0);
+            assert_eq!(tree.search(&key), model.get(&key).copied());
+            assert_eq!(tree.augment_search(&key), sum);
+        }
+    }
+
+    #[test]
+    fn disjoint_writers_match_their_models() {
+        let tree = Tree::default();
+
+        let models: Vec<_> = thread::scope(|s| {
+            let handles: Vec<_> = (0..THREADS)
+                .map(|t| {
+                    let tree = &tree;
+                    s.spawn(move || {
+                        let mut next = rng::<u64>(u64::from(t) + 1);
+                        let mut model = BTreeMap::new();
+                        for _ in 0..5000 {
+                            // Every thread owns the keys that are equal to it modulo `THREADS`
+                            let key = (next() % 600) as u32 * THREADS + t;
+                            match next() % 4 {
+                                0 => assert_eq!(tree.delete(&key), model.remove(&key)),
+                                1 => assert_eq!(tree.search(&key), model.get(&key).copied()),
+                                _ => {
+                                    let value = (next() % 100) as i64;
+                                    let inserted = !model.contains_key(&key);
+                                    assert_eq!(tree.insert(key, value), inserted);
+                                    model.entry(key).or_insert(value);
+                                }
+                            }
+                        }
+                        model
+                    })
+                })
+                .collect();
+            handles.into_iter().map(|h| h.join().unwrap()).collect()
+        });
+
+        let model = models.into_iter().flatten().collect();
+        check_against_model(&tree, &model);
+    }
+
+    #[test]
+    fn contended_keys_stay_consistent() {
+        let tree = Arc::new(Tree::default());
+
+        // Each thread tracks how many times it inserted and deleted each key
+        let nets: Vec<_> = (0..THREADS)
+            .map(|t| {
+                let tree = Arc::clone(&tree);
+                thread::spawn(move || {
+                    let mut next = rng::<u64>(u64::from(t) + 100);
+                    let mut net = vec![0i64; 300];
+                    for _ in 0..5000 {
+                        let key = (next() % 300) as u32;
+                        match next() % 3 {
+                            0 => net[key as usize] -= i64::from(tree.delete(&key).is_some()),
+                            1 => net[key as usize] += i64::from(tree.insert(key, 1)),
+                            _ => {
+                                let sum = tree.augment_search(&key);
+                                assert!((0..=i64::from(key) + 1).contains(&sum));
+                            }
+                        }
+                    }
+                    net
+                })
+            })
+            .collect::<Vec<_>>()
+            .into_iter()
+            .map(|h| h.join().unwrap())
+            .collect();
+
+        let mut model = BTreeMap::new();
+        for key in 0..300 {
+            let net: i64 = nets.iter().map(|net| net[key as usize]).sum();
+            assert!(net == 0 || net == 1, "key {key} was inserted {net} times");
+            if net == 1 {
+                model.insert(key, 1);
+            }
+        }
+        check_against_model(&tree, &model);
+    }
+
+    #[test]
+    fn range_matches_model() {
+        let tree = Tree::default();
+        let mut model = BTreeMap::new();
+        let mut next = rng::<u64>(7);
+        for _ in 0..3000 {
+            let key = (next() % 1000) as u32;
+            if next().is_multiple_of(3) {
+                tree.delete(&key);
+                model.remove(&key);
+            } else {
+                tree.insert(key, key.into());
+                model.entry(key).or_insert(key.into());
+            }
+        }
+
+        let pairs = |range: (Bound<u32>, Bound<u32>)| -> Vec<_> {
+            model.range(range).map(|(&k, &v)| (k, v)).collect()
+        };
+        assert_eq!(tree.range(..), pairs((Bound::Unbounded, Bound::Unbounded)));
+        for _ in 0..200 {
+            let (a, b) = ((next() % 1100) as u32, (next() % 1100) as u32);
+            let (start, end) = (a.min(b), a.max(b));
+            assert_eq!(
+                tree.range(start..end),
+                pairs((Bound::Included(start), Bound::Excluded(end)))
+            );
+            assert_eq!(
+                tree.range(start..=end),
+                pairs((Bound::Included(start), Bound::Included(end)))
+            );
+            let bounds = (Bound::Excluded(start), Bound::Unbounded);
+            assert_eq!(tree.range(bounds), pairs(bounds));
+        }
+    }
+
+    #[test]
+    fn lock_free_reads_see_stable_keys() {
+        let tree = Tree::default();
+        // Multiples of 10 are never touched by the writers, so every read must find them
+        for key in (0..2000).step_by(10) {
+            tree.insert(key, 1);
+        }
+        let done = AtomicBool::new(false);
+
+        thread::scope(|s| {
+            let writers: Vec<_> = (0..THREADS / 2)
+                .map(|t| {
+                    let tree = &tree;
+                    s.spawn(move || {
+                        let mut next = rng::<u64>(u64::from(t) + 1000);
+                        for _ in 0..20_000 {
+                            let key = (next() % 2000) as u32;
+                            if key.is_multiple_of(10) {
+                                continue;
+                            }
+                            if next().is_multiple_of(2) {
+                                tree.insert(key, 1);
+                            } else {
+                                tree.delete(&key);
+                            }
+                        }
+                    })
+                })
+                .collect();
+
+            for t in 0..THREADS / 2 {
+                let (tree, done) = (&tree, &done);
+                s.spawn(move || {
+                    let mut next = rng::<u64>(u64::from(t) + 2000);
+                    while !done.load(Ordering::Relaxed) {
+                        let key = (next() % 200) as u32 * 10;
+                        assert_eq!(tree.search(&key), Some(1));
+                        // The sum counts key and everything below it
+                        assert!(tree.augment_search(&key) > i64::from(key / 10));
+
+                        let start = (next() % 2000) as u32;
+                        let pairs = tree.range(start..start + 200);
+                        assert!(pairs.windows(2).all(|w| w[0].0 < w[1].0));
+                        let stable = pairs.iter().filter(|(k, _)| k.is_multiple_of(10));
+                        let expected =
+                            (start..(start + 200).min(2000)).filter(|k| k.is_multiple_of(10));
+                        assert_eq!(stable.count(), expected.count());
+                    }
+                });
+            }
+
+            for writer in writers {
+                writer.join().unwrap();
+            }
+            done.store(true, Ordering::Relaxed);
+        });
+    }
+
+    #[test]
+    fn panicking_hook_poisons_the_tree() {
+        struct Panicky;
+
+        impl Augment<u32, ()> for Panicky {
+            type Value = ();
+            type Output = ();
+
+            fn initial_value() {}
+
+            fn initial_output() {}
+
+            fn inserted_sub_tree(key: &u32, _: &(), _: &()) {
+                assert_ne!(*key, 13, "unlucky key");
+            }
+
+            fn deleted_sub_tree(_: &u32, _: &(), _: &()) {}
+
+            fn split<'a>(
+                _: &[(u32, ())],
+                _: &[(u32, ())],
+                _: &(u32, ()),
+                _: impl Iterator<Item = &'a ()>,
+                _: impl Iterator<Item = &'a ()>,
+                _: &(),
+            ) -> ((), ()) {
+                ((), ())
+            }
+
+            fn split_root(_: &(u32, ()), _: &(), _: &()) {}
+
+            fn merge(_: &(u32, ()), _: &(), _: &()) {}
+
+            fn steal(_: &(u32, ()), _: &(u32, ()), _: Option<&()>, _: &(), _: &()) -> ((), ()) {
+                ((), ())
+            }
+
+            fn visit<'a>(
+                _: bool,
+                _: usize,
+                _: &[(u32, ())],
+                _: impl Iterator<Item = &'a ()>,
+                _: &(),
+                _: (),
+            ) {
+            }
+        }
+
+        let tree = ConcurrentBTree::<u32, (), Panicky>::default();
+        for i in 0..13 {
+            assert!(tree.insert(i, ()));
+        }
+        assert!(!tree.is_poisoned());
+
+        let res = thread::scope(|s| s.spawn(|| tree.insert(13, ())).join());
+        assert!(res.is_err());
+        assert!(tree.is_poisoned());
+        let res = thread::scope(|s| s.spawn(|| tree.search(&0)).join());
+        assert!(res.is_err());
+    }
+}
@@ -0,0 +1,359 @@
+//! Differences between two trees
+//!
+//! [`BTree::diff`] walks two trees side by side and yields the pairs that differ between them in
+//! ascending key order. Both walks only open a node once it is known to hold a pair that has to be
+//! compared, so two subtrees at the front of the walks that are the same node, as they are between
+//! versions of a [`PersistentBTree`](crate::persistent::PersistentBTree), are skipped as a whole.
+//! The same goes for subtrees that [`Augment::identical_sub_trees`] reports as identical, like
+//! subtrees with equal [`HashAugment`](crate::augments::HashAugment) hashes. The work done thus
+//! grows with the size of the difference and the height of the trees, not with their sizes.
+//!
+//! ```
+//! use b_tree::diff::DiffEvent;
+//! use b_tree::persistent::PersistentBTree;
+//!
+//! let mut tree: PersistentBTree<u32, &str> = (0..1000).map(|i| (i, "old")).collect();
+//! let before = tree.snapshot();
+//! tree.delete(&10);
+//! tree.delete(&500);
+//! tree.insert(500, "new");
+//! tree.insert(1000, "new");
+//!
+//! assert!(before.diff(&tree).eq([
+//!     DiffEvent::Removed(&10, &"old"),
+//!     DiffEvent::Changed(&500, &"old", &"new"),
+//!     DiffEvent::Added(&1000, &"new"),
+//! ]));
+//! ```
+
+use alloc::vec;
+use alloc::vec::Vec;
+use core::cmp::Ordering;
+use core::ptr;
+
+use crate::persistent::{Sharing, Unique};
+use crate::{Augment, BTree, Node};
+
+/// A difference between two trees, yielded by [`Diff`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DiffEvent<'a, K, V> {
+    /// The key is only in the new tree
+    Added(&'a K, &'a V),
+    /// The key is only in the old tree
+    Removed(&'a K, &'a V),
+    /// The key is in both trees, with the old and the new value
+    Changed(&'a K, &'a V, &'a V),
+}
+
+impl<K: Ord, V: PartialEq, A: Augment<K, V>, S: Sharing<K, V, A>> BTree<K, V, A, S> {
+    /// Returns an iterator over the differences between `self`, the old tree, and `other`, the new
+    /// tree, in ascending key order, see the [module documentation](crate::diff)
+    ///
+    /// Like [`BTree::iter`], the walks panic if they come across a change from
+    /// [`BTree::update_range`] that has yet to be applied.
+    pub fn diff<'a>(&'a self, other: &'a Self) -> Diff<'a, K, V, A, S> {
+        self.check_poison();
+        other.check_poison();
+        Diff {
+            old: Walk::new(&self.root),
+            new: Walk::new(&other.root),
+        }
+    }
+}
+
+/// An item of a walk, either a pair or a subtree that has not been opened yet, along with its
+/// height
+enum Item<'a, K, V, A: Augment<K, V>, S: Sharing<K, V, A>> {
+    Pair(&'a (K, V)),
+    Node(&'a Node<K, V, A, S>, usize),
+}
+
+impl<K, V, A: Augment<K, V>, S: Sharing<K, V, A>> Clone for Item<'_, K, V, A, S> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<K, V, A: Augment<K, V>, S: Sharing<K, V, A>> Copy for Item<'_, K, V, A, S> {}
+
+/// The items of a tree that have not been walked yet, with the next one on top
+struct Walk<'a, K, V, A: Augment<K, V>, S: Sharing<K, V, A>>(Vec<Item<'a, K, V, A, S>>);
+
+impl<'a, K: Ord, V, A: Augment<K, V>, S: Sharing<K, V, A>> Walk<'a, K, V, A, S> {
+    fn new(root: &'a Node<K, V, A, S>) -> Self {
+        let mut height = 0;
+        let mut node = root;
+        while !node.is_leaf() {
+            node = &node.children()[0];
+            height += 1;
+        }
+
+        // Only an empty root has no pairs, and the other items are never empty
+        let items = if root.n > 0 {
+            vec![Item::Node(root, height)]
+        } else {
+            Vec::new()
+        };
+        Self(items)
+    }
+
+    fn peek(&self) -> Option<Item<'a, K, V, A, S>> {
+        self.0.last().copied()
+    }
+
+    /// Replaces the subtree on top with its children and pairs
+    fn open(&mut self) {
+        let Some(Item::Node(node, height)) = self.0.pop() else {
+            unreachable!("only subtrees are opened")
+        };
+        node.check_pending();
+        let pairs = node.pairs();
+        if node.is_leaf() {
+            self.0.extend(pairs.iter().rev().map(Item::Pair));
+        } else {
+            self.0
+                .push(Item::Node(&node.children()[node.n], height - 1));
+            for (pair, child) in pairs.iter().zip(node.children()).rev() {
+                self.0.push(Item::Pair(pair));
+                self.0.push(Item::Node(child, height - 1));
+            }
+        }
+    }
+
+    /// Takes the pair on top, opening subtrees until there is one
+    fn next_pair(&mut self) -> Option<&'a (K, V)> {
+        loop {
+            match self.peek()? {
+                Item::Pair(pair) => {
+                    self.0.pop();
+                    return Some(pair);
+                }
+                Item::Node(..) => self.open(),
+            }
+        }
+    }
+}
+
+/// Returns the pair with the smallest key in the subtree of `node`
+fn first_pair<K: Ord, V, A: Augment<K, V>, S: Sharing<K, V, A>>(
+    mut node: &Node<K, V, A, S>,
+) -> &(K, V) {
+    while !node.is_leaf() {
+        node = &node.children()[0];
+    }
+    &node.pairs()[0]
+}
+
+/// An iterator over the differences between two [`BTree`]s in ascending key order, created by
+/// [`BTree::diff`]
+pub struct Diff<'a, K, V, A: Augment<K, V> = (), S: Sharing<K, V, A> = Unique> {
+    old: Walk<'a, K, V, A, S>,
+    new: Walk<'a, K, V, A, S>,
+}
+
+impl<'a, K: Ord, V: PartialEq, A: Augment<K, V>, S: Sharing<K, V, A>> Iterator
+    for Diff<'a, K, V, A, S>
+{
+    type Item = DiffEvent<'a, K, V>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            match (self.old.peek(), self.new.peek()) {
+                (None, None) => return None,
+                (Some(_), None) => {
+                    let (key, value) = self.old.next_pair()?;
+                    return Some(DiffEvent::Removed(key, value));
+                }
+                (None, Some(_)) => {
+                    let (key, value) = self.new.next_pair()?;
+                    return Some(DiffEvent::Added(key, value));
+                }
+                (
+                    Some(Item::Pair((old_key, old_value))),
+                    Some(Item::Pair((new_key, new_value))),
+                ) => match old_key.cmp(new_key) {
+                    Ordering::Less => {
+                        self.old.0.pop();
+                        return Some(DiffEvent::Removed(old_key, old_value));
+                    }
+                    Ordering::Greater => {
+                        self.new.0.pop();
+                        return Some(DiffEvent::Added(new_key, new_value));
+                    }
+                    Ordering::Equal => {
+                        self.old.0.pop();
+                        self.new.0.pop();
+                        if old_value != new_value {
+                            return Some(DiffEvent::Changed(old_key, old_value, new_value));
+                        }
+                    }
+                },
+                // A pair that comes before everything in the other subtree can be taken without
+                // opening it, which keeps the subtree whole in case it is shared
+                (Some(Item::Pair((key, value))), Some(Item::Node(node, _))) => {
+                    if *key < first_pair(node).0 {
+                        self.old.0.pop();
+                        return Some(DiffEvent::Removed(key, value));
+                    }
+                    self.new.open();
+                }
+                (Some(Item::Node(node, _)), Some(Item::Pair((key, value)))) => {
+                    if *key < first_pair(node).0 {
+                        self.new.0.pop();
+                        return Some(DiffEvent::Added(key, value));
+                    }
+                    self.old.open();
+                }
+                (Some(Item::Node(old, old_height)), Some(Item::Node(new, new_height))) => {
+                    if ptr::eq(&**old, &**new) || A::identical_sub_trees(&old.aug_val, &new.aug_val)
+                    {
+                        self.old.0.pop();
+                        self.new.0.pop();
+                        continue;
+                    }
+
+                    // Open the subtree that starts first, as it cannot be part of the other one.
+                    // Otherwise open the higher one, whose children may match the other subtree.
+                    match first_pair(old)
+                        .0
+                        .cmp(&first_pair(new).0)
+                        .then(new_height.cmp(&old_height))
+                    {
+                        Ordering::Less => self.old.open(),
+                        Ordering::Greater => self.new.open(),
+                        Ordering::Equal => {
+                            self.old.open();
+                            self.new.open();
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+    use std::collections::BTreeMap;
+    use std::vec::Vec;
+
+    use crate::augments::HashAugment;
+    use crate::diff::DiffEvent;
+    use crate::persistent::PersistentBTree;
+    use crate::snapshot::Encode;
+    use crate::tests::rng;
+    use crate::BTree;
+
+    thread_local! {
+        static COMPARISONS: Cell<usize> = const { Cell::new(0) };
+    }
+
+    /// A value that counts how often it is compared
+    #[derive(Debug, Clone, Copy)]
+    struct Counted(u32);
+
+    impl PartialEq for Counted {
+        fn eq(&self, other: &Self) -> bool {
+            COMPARISONS.with(|c| c.set(c.get() + 1));
+            self.0 == other.0
+        }
+    }
+
+    impl Encode for Counted {
+        fn encode(&self, out: &mut Vec<u8>) {
+            self.0.encode(out);
+        }
+    }
+
+    fn comparisons() -> usize {
+        COMPARISONS.with(Cell::take)
+    }
+
+    fn model_diff(old: &BTreeMap<u32, u32>, new: &BTreeMap<u32, u32>) -> Vec<(u32, i64, i64)> {
+        let mut keys: Vec<_> = old.keys().chain(new.keys()).copied().collect();
+        keys.sort_unstable();
+        keys.dedup();
+        keys.into_iter()
+            .filter(|key| old.get(key) != new.get(key))
+            .map(|key| {
+                let value = |map: &BTreeMap<u32, u32>| map.get(&key).map_or(-1, |&v| i64::from(v));
+                (key, value(old), value(new))
+            })
+            .collect()
+    }
+
+    fn flatten(event: DiffEvent<u32, Counted>) -> (u32, i64, i64) {
+        match event {
+            DiffEvent::Added(&key, value) => (key, -1, i64::from(value.0)),
+            DiffEvent::Removed(&key, value) => (key, i64::from(value.0), -1),
+            DiffEvent::Changed(&key, old, new) => (key, i64::from(old.0), i64::from(new.0)),
+        }
+    }
+
+    #[test]
+    fn snapshot_diffs_match_model() {
+        let mut tree: PersistentBTree<u32, Counted> = PersistentBTree::default();
+        let mut model = BTreeMap::new();
+        let mut versions = vec![(tree.snapshot(), model.clone())];
+
+        let mut next = rng::<u32>(0x5eed_d1ff);
+        for step in 0..4000 {
+            let key = next() % 2000;
+            if next().is_multiple_of(3) {
+                assert_eq!(tree.delete(&key).map(|v| v.0), model.remove(&key));
+            } else {
+                let value = next() % 4;
+                tree.delete(&key);
+                tree.insert(key, Counted(value));
+                model.insert(key, value);
+            }
+
+            if step % 200 == 0 {
+                versions.push((tree.snapshot(), model.clone()));
+            }
+        }
+        versions.push((tree.snapshot(), model));
+
+        for (old, old_model) in &versions {
+            for (new, new_model) in versions.iter().step_by(3) {
+                let diff: Vec<_> = old.diff(new).map(flatten).collect();
+                assert_eq!(diff, model_diff(old_model, new_model));
+            }
+        }
+    }
+
+    #[test]
+    fn diff_skips_shared_sub_trees() {
+        let mut tree: PersistentBTree<u32, Counted> =
+            (0..100_000).map(|i| (i, Counted(i))).collect();
+        let before = tree.snapshot();
+        tree.delete(&1234);
+        tree.delete(&77_777);
+        tree.insert(77_777, Counted(0));
+
+        comparisons();
+        let diff: Vec<_> = before.diff(&tree).map(flatten).collect();
+        assert_eq!(diff, [(1234, 1234, -1), (77_777, 77_777, 0)]);
+        assert!(comparisons() < 200);
+
+        assert_eq!(tree.diff(&tree).count(), 0);
+        assert_eq!(comparisons(), 0);
+    }
+
+    #[test]
+    fn diff_skips_sub_trees_with_equal_hashes() {
+        let build = || -> BTree<u32, Counted, HashAugment> {
+            (0..20_000).map(|i| (i, Counted(i))).collect()
+        };
+        let old = build();
+        let mut new = build();
+        new.delete(&5000);
+        new.insert(5000, Counted(1));
+
+        comparisons();
+        let diff: Vec<_> = old.diff(&new).map(flatten).collect();
+        assert_eq!(diff, [(5000, 5000, 1)]);
+        assert!(comparisons() < 200);
+    }
+}
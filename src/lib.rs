@@ -1,10 +1,88 @@
-use std::cmp::Ordering;
-use std::fmt::{self, Debug, Formatter};
-use std::mem::{self, MaybeUninit};
+#![cfg_attr(not(feature = "std"), no_std)]
+
+extern crate alloc;
+// Tests use std for their models and for catching panics, but the library itself stays `no_std`
+#[cfg(all(test, not(feature = "std")))]
+#[macro_use]
+extern crate std;
+
+use alloc::alloc::{handle_alloc_error, Layout};
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::cmp::Ordering;
+use core::error::Error;
+use core::fmt::{self, Debug, Display, Formatter};
+use core::mem::{self, ManuallyDrop, MaybeUninit};
+use core::ops::{Bound, Deref, DerefMut, RangeBounds, Sub};
+use core::ptr;
 
 pub mod augments;
-
+mod children;
+#[cfg(feature = "concurrent")]
+pub mod concurrent;
+pub mod diff;
+pub mod multimap;
+#[cfg(feature = "std")]
+pub mod paged;
+pub mod persistent;
+pub mod priority_queue;
+pub mod range_map;
+#[cfg(feature = "rayon")]
+pub mod rayon;
+pub mod reconcile;
+#[cfg(feature = "serde")]
+mod serde;
+pub mod snapshot;
+pub mod vec;
+
+pub use allocator_api2;
+
+use allocator_api2::alloc::{AllocError, Allocator};
+use children::Children;
+use node::{InternalNode, LeafNode, Node};
+use persistent::{Sharing, Unique};
+
+/// The minimum degree of the nodes. Benchmarks can pick another one with
+/// `RUSTFLAGS='--cfg b_tree_min_degree="3"'` (or `"16"`).
+#[cfg(not(any(b_tree_min_degree = "3", b_tree_min_degree = "16")))]
 const MIN_DEGREE: usize = 6;
+#[cfg(b_tree_min_degree = "3")]
+const MIN_DEGREE: usize = 3;
+#[cfg(b_tree_min_degree = "16")]
+const MIN_DEGREE: usize = 16;
+
+/// # Safety
+/// All elements of `slice` must be initialized
+unsafe fn slice_assume_init<T>(slice: &[MaybeUninit<T>]) -> &[T] {
+    &*(slice as *const [MaybeUninit<T>] as *const [T])
+}
+
+/// # Safety
+/// All elements of `slice` must be initialized
+unsafe fn slice_assume_init_mut<T>(slice: &mut [MaybeUninit<T>]) -> &mut [T] {
+    &mut *(slice as *mut [MaybeUninit<T>] as *mut [T])
+}
+
+/// Whether no key lies within the bounds, because they are reversed or exclude each other
+fn is_empty_range<K: Ord>(start: Bound<&K>, end: Bound<&K>) -> bool {
+    match (start, end) {
+        (Bound::Included(start), Bound::Included(end)) => start > end,
+        (
+            Bound::Included(start) | Bound::Excluded(start),
+            Bound::Included(end) | Bound::Excluded(end),
+        ) => start >= end,
+        _ => false,
+    }
+}
+
+/// Why [`Node::insert_non_full`] did not insert a pair, which it hands back
+enum InsertError<K, V> {
+    /// The key is already in the tree
+    Duplicate(K, V),
+    /// A node could not be allocated
+    Alloc(K, V),
+}
 
 pub trait Augment<K, V> {
     type Value;
@@ -52,60 +130,143 @@ pub trait Augment<K, V> {
     ) -> Self::Output
     where
         Self::Value: 'a;
-}
 
-struct Node<K, V, A: Augment<K, V>> {
-    n: usize,
-    keys: [MaybeUninit<(K, V)>; 2 * MIN_DEGREE - 1],
-    children: Vec<Self>,
-    aug_val: A::Value,
-}
+    /// Whether `value` records a change that has not been pushed down to the pairs and children of
+    /// its node yet. Only augments that implement [`RangeUpdate`] record such changes.
+    fn has_pending(_value: &Self::Value) -> bool {
+        false
+    }
 
-impl<K: Ord, V, A: Augment<K, V>> Node<K, V, A> {
-    const NEW_KEY: MaybeUninit<(K, V)> = MaybeUninit::uninit();
+    /// Applies the pending change recorded in `value`, the augment value of a node, to the pairs of
+    /// the node and the augment values of its children, and removes it from `value`. The tree does
+    /// this before it modifies a node, so the other hooks never see a pending change on the node
+    /// they update. Only called if [`Augment::has_pending`] returns `true`.
+    fn push_down<'a>(
+        _value: &mut Self::Value,
+        _pairs: &mut [(K, V)],
+        _children: impl Iterator<Item = &'a mut Self::Value>,
+    ) where
+        Self::Value: 'a,
+    {
+    }
 
-    fn new_root() -> Self {
-        Self {
-            n: 0,
-            keys: [Self::NEW_KEY; 2 * MIN_DEGREE - 1],
-            children: Vec::with_capacity(2 * MIN_DEGREE),
-            aug_val: A::initial_value(),
-        }
+    /// Returns the augment value `child` would have if the pending change in `parent` was pushed
+    /// down to it. Searches, which cannot modify the tree, pass these values to [`Augment::visit`].
+    /// Only called if [`Augment::has_pending`] returns `true` for `parent`.
+    fn pushed_down(_parent: &Self::Value, _child: &Self::Value) -> Self::Value {
+        unreachable!("augment records pending changes but does not implement `pushed_down`")
     }
 
-    unsafe fn split(&mut self) -> ((K, V), Self) {
-        debug_assert!(self.is_full());
+    /// Set this for augments that cannot take a pair back out of an augment value, like a maximum.
+    /// Instead of calling [`Augment::deleted_sub_tree`], the tree then computes the augment value
+    /// of every node that loses a pair with [`Augment::compute`]. The same goes for the victim
+    /// returned by [`Augment::steal`], whose value is ignored.
+    ///
+    /// `concurrent::ConcurrentBTree` updates augment values on the way down, before the nodes
+    /// below are modified, so it does not support such augments.
+    const RECOMPUTE_ON_DELETE: bool = false;
+
+    /// Set this for augments whose value depends on how the pairs are spread over the nodes, like
+    /// a hash of every node. The tree then computes the augment value of every node it modifies
+    /// with [`Augment::compute`], once the node and the nodes below it are up to date, and calls
+    /// none of the other hooks that update values except [`Augment::split`] and
+    /// [`Augment::split_root`], which see every pair and child of the nodes they return. Implies
+    /// [`Augment::RECOMPUTE_ON_DELETE`].
+    const RECOMPUTE_ALWAYS: bool = false;
+
+    /// Computes the augment value of a node from its pairs and the augment values of its children,
+    /// of which there are none or one more than there are pairs. The default folds the pairs and
+    /// children with [`Augment::inserted_sub_tree`] and [`Augment::merge`].
+    fn compute<'a>(
+        pairs: &[(K, V)],
+        children: impl DoubleEndedIterator<Item = &'a Self::Value>,
+    ) -> Self::Value
+    where
+        Self::Value: 'a,
+    {
+        let mut children = children.rev();
+        let Some(right) = children.next() else {
+            return pairs
+                .iter()
+                .fold(Self::initial_value(), |acc, (key, value)| {
+                    Self::inserted_sub_tree(key, value, &acc)
+                });
+        };
 
-        let median = self.keys[MIN_DEGREE - 1].assume_init_read();
+        // Fold from the right, as if merging each child with everything to its right
+        let (last, rest) = pairs.split_last().expect("node has children but no pairs");
+        let init = Self::merge(last, children.next().unwrap(), right);
+        rest.iter()
+            .rev()
+            .zip(children)
+            .fold(init, |acc, (pair, child)| Self::merge(pair, child, &acc))
+    }
 
-        let mut keys = [Self::NEW_KEY; 2 * MIN_DEGREE - 1];
-        self.keys[MIN_DEGREE..].swap_with_slice(&mut keys[..MIN_DEGREE - 1]);
+    /// Whether two subtrees with the augment values `a` and `b` are known to hold the same pairs in
+    /// the same nodes, like two equal hashes. [`BTree::diff`] skips such subtrees without looking at
+    /// their pairs. The default never claims so.
+    fn identical_sub_trees(_a: &Self::Value, _b: &Self::Value) -> bool {
+        false
+    }
+}
 
-        let children = if self.is_leaf() {
-            Vec::with_capacity(2 * MIN_DEGREE)
-        } else {
-            self.children.split_off(MIN_DEGREE)
-        };
-        self.n = MIN_DEGREE - 1;
+/// An [`Augment`] that can apply a change to every value in a key range at once, see
+/// [`BTree::update_range`]
+///
+/// Subtrees that lie completely inside the range are not visited. Instead, the change is recorded
+/// as pending in their augment values, so implementors must also implement
+/// [`Augment::has_pending`], [`Augment::push_down`] and [`Augment::pushed_down`].
+pub trait RangeUpdate<K, V>: Augment<K, V> {
+    type Delta;
+
+    /// Returns `value` with `delta` applied to it
+    fn updated_value(delta: &Self::Delta, value: &V) -> V;
+
+    /// Returns the augment value of a subtree after applying `delta` to all of its values, with
+    /// `delta` recorded as pending for the pairs and children of its root
+    fn updated_sub_tree(delta: &Self::Delta, old: &Self::Value) -> Self::Value;
+}
 
-        let augment;
-        (self.aug_val, augment) = A::split(
-            mem::transmute(&self.keys[..MIN_DEGREE - 1]),
-            mem::transmute(&keys[..MIN_DEGREE - 1]),
-            &median,
-            self.children.iter().map(|n| &n.aug_val),
-            children.iter().map(|n| &n.aug_val),
-            &self.aug_val,
-        );
+mod node {
+    use core::mem::MaybeUninit;
+
+    use crate::children::Children;
+    use crate::persistent::Sharing;
+    use crate::{Augment, MIN_DEGREE};
+
+    /// The pairs of a node of a [`BTree`](crate::BTree), which is all there is to a leaf. The node
+    /// types are only public because [`Sharing`] has to mention them, and they live in a private
+    /// module so that they cannot be named outside of the crate.
+    pub struct LeafNode<K, V, A: Augment<K, V>> {
+        /// The number of initialized pairs at the start of `keys`
+        pub(crate) n: usize,
+        pub(crate) keys: [MaybeUninit<(K, V)>; 2 * MIN_DEGREE - 1],
+        pub(crate) aug_val: A::Value,
+    }
 
-        let new_node = Self {
-            n: MIN_DEGREE - 1,
-            keys,
-            children,
-            aug_val: augment,
-        };
+    /// A node with children, which it keeps in an array of its own. The pairs come first, so that
+    /// getting to them does not depend on the type of the node.
+    #[repr(C)]
+    pub struct InternalNode<K, V, A: Augment<K, V>, S: Sharing<K, V, A>> {
+        pub(crate) data: LeafNode<K, V, A>,
+        /// `data.n + 1` children, except while the node is being modified
+        pub(crate) children: Children<Node<K, V, A, S>>,
+    }
+
+    /// A pointer to a leaf or an internal node, owned by the parent of the node or by the tree
+    pub enum Node<K, V, A: Augment<K, V>, S: Sharing<K, V, A>> {
+        Leaf(S::Leaf),
+        Internal(S::Internal),
+    }
+}
 
-        (median, new_node)
+impl<K, V, A: Augment<K, V>> LeafNode<K, V, A> {
+    fn new() -> Self {
+        Self {
+            n: 0,
+            keys: [const { MaybeUninit::uninit() }; 2 * MIN_DEGREE - 1],
+            aug_val: A::initial_value(),
+        }
     }
 
     fn insert_pair(&mut self, idx: usize, pair: (K, V)) {
@@ -119,129 +280,362 @@ impl<K: Ord, V, A: Augment<K, V>> Node<K, V, A> {
         self.n += 1;
     }
 
-    fn insert_child(&mut self, idx: usize, child: Self) {
-        self.children.insert(idx, child);
+    /// # Safety
+    /// `idx` must be in the interval `[0; self.n)`
+    unsafe fn remove_pair(&mut self, idx: usize) -> (K, V) {
+        // Extract ownership of the key without using extra work
+        let pair = self.keys[idx].assume_init_read();
+        self.n -= 1;
+        for i in idx..self.n {
+            self.keys[i] = MaybeUninit::new(self.keys[i + 1].assume_init_read());
+        }
+        pair
+    }
+
+    fn push_pair(&mut self, pair: (K, V)) {
+        self.insert_pair(self.n, pair);
+    }
+
+    /// # Safety
+    /// Must not be empty
+    unsafe fn pop_pair(&mut self) -> (K, V) {
+        self.remove_pair(self.n - 1)
+    }
+
+    fn pairs(&self) -> &[(K, V)] {
+        unsafe { slice_assume_init(&self.keys[..self.n]) }
+    }
+
+    fn pairs_mut(&mut self) -> &mut [(K, V)] {
+        unsafe { slice_assume_init_mut(&mut self.keys[..self.n]) }
+    }
+
+    fn is_min(&self) -> bool {
+        self.n < MIN_DEGREE
+    }
+
+    fn is_full(&self) -> bool {
+        self.n == 2 * MIN_DEGREE - 1
     }
+}
 
+impl<K: Ord, V, A: Augment<K, V>> LeafNode<K, V, A> {
     fn find_key_idx(&self, key: &K) -> Result<usize, usize> {
         self.keys[..self.n].binary_search_by_key(&key, |k| unsafe { &k.assume_init_ref().0 })
     }
 
+    /// Returns the range of indices of the pairs of the node that lie within the bounds. The range
+    /// is empty, rather than reversed, if the bounds are.
+    fn bounds_idx(&self, start: Bound<&K>, end: Bound<&K>) -> (usize, usize) {
+        let pairs = self.pairs();
+        let first = match start {
+            Bound::Included(start) => pairs.partition_point(|(k, _)| k < start),
+            Bound::Excluded(start) => pairs.partition_point(|(k, _)| k <= start),
+            Bound::Unbounded => 0,
+        };
+        let last = match end {
+            Bound::Included(end) => pairs.partition_point(|(k, _)| k <= end),
+            Bound::Excluded(end) => pairs.partition_point(|(k, _)| k < end),
+            Bound::Unbounded => pairs.len(),
+        };
+        (first, last.max(first))
+    }
+}
+
+impl<K, V, A: Augment<K, V>, S: Sharing<K, V, A>> InternalNode<K, V, A, S> {
+    fn new() -> Self {
+        Self {
+            data: LeafNode::new(),
+            children: Children::new(),
+        }
+    }
+}
+
+impl<K, V, A: Augment<K, V>, S: Sharing<K, V, A>> Node<K, V, A, S> {
+    /// Allocates an empty leaf with `alloc`
+    fn new_in(alloc: S::Alloc) -> Self {
+        S::try_wrap_leaf(LeafNode::new(), alloc)
+            .map(Node::Leaf)
+            .unwrap_or_else(|err| Self::alloc_failed(err))
+    }
+
+    /// Allocates an internal node without pairs or children with `alloc`
+    fn try_new_internal_in(alloc: S::Alloc) -> Result<Self, AllocError> {
+        S::try_wrap_internal(InternalNode::new(), alloc).map(Node::Internal)
+    }
+
+    /// Like [`Node::try_new_internal_in`], but aborts if the node cannot be allocated
+    fn new_internal_in(alloc: S::Alloc) -> Self {
+        Self::try_new_internal_in(alloc).unwrap_or_else(|err| Self::alloc_failed(err))
+    }
+
+    /// Allocates an empty node for a sibling of the node, which is a leaf if the node is
+    fn try_new_sibling(&self) -> Result<Self, AllocError> {
+        match self {
+            Node::Leaf(_) => S::try_wrap_leaf(LeafNode::new(), self.alloc()).map(Node::Leaf),
+            Node::Internal(_) => Self::try_new_internal_in(self.alloc()),
+        }
+    }
+
+    /// Aborts the way the global allocator does when it runs out of memory
+    fn alloc_failed(_: AllocError) -> ! {
+        handle_alloc_error(Layout::new::<InternalNode<K, V, A, S>>())
+    }
+
+    /// The allocator of the node and its children
+    fn alloc(&self) -> S::Alloc {
+        S::alloc(self)
+    }
+
+    fn is_leaf(&self) -> bool {
+        matches!(self, Node::Leaf(_))
+    }
+
+    /// The children of the node, of which a leaf has none
+    fn children(&self) -> &[Self] {
+        match self {
+            Node::Leaf(_) => &[],
+            Node::Internal(node) => &node.children,
+        }
+    }
+
+    /// Gives exclusive access to the children of the node, which must not be a leaf
+    fn children_mut(&mut self) -> &mut Children<Self> {
+        match self {
+            Node::Leaf(_) => unreachable!("a leaf has no children"),
+            Node::Internal(node) => &mut S::internal_mut(node).children,
+        }
+    }
+
+    /// Gives exclusive access to the pairs and, unless the node is a leaf, the children of the
+    /// node at the same time
+    fn parts_mut(&mut self) -> (&mut LeafNode<K, V, A>, Option<&mut Children<Self>>) {
+        match self {
+            Node::Leaf(node) => (S::leaf_mut(node), None),
+            Node::Internal(node) => {
+                let node = S::internal_mut(node);
+                (&mut node.data, Some(&mut node.children))
+            }
+        }
+    }
+
+    fn child_mut(&mut self, idx: usize) -> &mut Self {
+        &mut self.children_mut()[idx]
+    }
+}
+
+impl<K, V, A: Augment<K, V>, S: Sharing<K, V, A>> Deref for Node<K, V, A, S> {
+    type Target = LeafNode<K, V, A>;
+
+    fn deref(&self) -> &LeafNode<K, V, A> {
+        match self {
+            Node::Leaf(node) => node,
+            Node::Internal(node) => &node.data,
+        }
+    }
+}
+
+/// Copies the node first if another version of the tree refers to it, see [`Sharing`]
+impl<K, V, A: Augment<K, V>, S: Sharing<K, V, A>> DerefMut for Node<K, V, A, S> {
+    fn deref_mut(&mut self) -> &mut LeafNode<K, V, A> {
+        self.parts_mut().0
+    }
+}
+
+impl<K: Ord, V, A: Augment<K, V>, S: Sharing<K, V, A>> Node<K, V, A, S> {
+    /// Moves the upper half of the node into a new node, returning it along with the median. Only
+    /// fails before the node is modified.
+    ///
+    /// # Safety
+    /// Must be full
+    unsafe fn split(&mut self) -> Result<((K, V), Self), AllocError> {
+        debug_assert!(self.is_full());
+        let mut new_node = self.try_new_sibling()?;
+        self.push_down();
+
+        let (node, children) = self.parts_mut();
+        let (new, new_children) = new_node.parts_mut();
+        let median = node.keys[MIN_DEGREE - 1].assume_init_read();
+        node.keys[MIN_DEGREE..].swap_with_slice(&mut new.keys[..MIN_DEGREE - 1]);
+        if let (Some(children), Some(new_children)) = (children, new_children) {
+            children.move_tail(MIN_DEGREE, new_children);
+        }
+        node.n = MIN_DEGREE - 1;
+        new.n = MIN_DEGREE - 1;
+
+        (self.aug_val, new_node.aug_val) = A::split(
+            self.pairs(),
+            new_node.pairs(),
+            &median,
+            self.children().iter().map(|n| &n.aug_val),
+            new_node.children().iter().map(|n| &n.aug_val),
+            &self.aug_val,
+        );
+
+        Ok((median, new_node))
+    }
+
+    /// Splits the node, which must be the root of its tree, into two children of a new root. Only
+    /// fails before the node is modified.
+    ///
+    /// # Safety
+    /// Must be full
+    unsafe fn split_root(&mut self) -> Result<(), AllocError> {
+        let root = Self::try_new_internal_in(self.alloc())?;
+        let (root_pair, child) = self.split()?;
+        let old_root = mem::replace(self, root);
+
+        self.aug_val = A::split_root(&root_pair, &old_root.aug_val, &child.aug_val);
+        self.keys[0] = MaybeUninit::new(root_pair);
+        self.children_mut().push(old_root);
+        self.children_mut().push(child);
+        self.n = 1;
+        Ok(())
+    }
+
+    /// Only fails before the node is modified
+    ///
     /// # Safety
     /// Child at `idx` must be full
-    unsafe fn split_child(&mut self, idx: usize) {
-        let (median, new_child) = self.children[idx].split();
+    unsafe fn split_child(&mut self, idx: usize) -> Result<(), AllocError> {
+        let (median, new_child) = self.child_mut(idx).split()?;
         self.insert_pair(idx, median);
-        self.insert_child(idx + 1, new_child);
+        self.children_mut().insert(idx + 1, new_child);
+        Ok(())
     }
 
-    fn insert_non_full(&mut self, key: K, value: V) -> Result<(), (K, V)> {
+    fn insert_non_full(&mut self, key: K, value: V) -> Result<(), InsertError<K, V>> {
         debug_assert!(!self.is_full());
+        self.push_down();
 
         // We ignore duplicates
         let mut idx = match self.find_key_idx(&key) {
-            Ok(_) => return Err((key, value)),
+            Ok(_) => return Err(InsertError::Duplicate(key, value)),
             Err(i) => i,
         };
 
         if self.is_leaf() {
-            self.aug_val = A::inserted_sub_tree(&key, &value, &self.aug_val);
-            self.insert_pair(idx, (key, value));
+            if A::RECOMPUTE_ALWAYS {
+                self.insert_pair(idx, (key, value));
+                self.recompute_aug_val();
+            } else {
+                self.aug_val = A::inserted_sub_tree(&key, &value, &self.aug_val);
+                self.insert_pair(idx, (key, value));
+            }
             Ok(())
         } else {
-            if self.children[idx].is_full() {
+            if self.children()[idx].is_full() {
                 // Safety: Child is definitely full and `split_child`
                 // ensures that `self.keys[idx]` is initialized
                 let split_key = unsafe {
-                    self.split_child(idx);
+                    if self.split_child(idx).is_err() {
+                        return Err(InsertError::Alloc(key, value));
+                    }
                     &self.keys[idx].assume_init_ref().0
                 };
 
                 match key.cmp(split_key) {
-                    Ordering::Equal => return Err((key, value)),
+                    Ordering::Equal => {
+                        if A::RECOMPUTE_ALWAYS {
+                            self.recompute_aug_val();
+                        }
+                        return Err(InsertError::Duplicate(key, value));
+                    }
                     Ordering::Greater => idx += 1,
                     Ordering::Less => {}
                 }
             }
 
+            if A::RECOMPUTE_ALWAYS {
+                // Splitting the child changed the node, even if the key turns out to be a duplicate
+                let res = self.child_mut(idx).insert_non_full(key, value);
+                self.recompute_aug_val();
+                return res;
+            }
+
             self.aug_val = A::inserted_sub_tree(&key, &value, &self.aug_val);
-            // If we end up not inserting the key, because it is a duplicate, undo the augment update
-            self.children[idx]
+            // If we end up not inserting the key, because it is a duplicate or a node could not be
+            // allocated, undo the augment update
+            self.child_mut(idx)
                 .insert_non_full(key, value)
-                .map_err(|(k, v)| {
-                    self.aug_val = A::deleted_sub_tree(&k, &v, &self.aug_val);
-                    (k, v)
+                .inspect_err(|err| {
+                    let (InsertError::Duplicate(k, v) | InsertError::Alloc(k, v)) = err;
+                    self.deleted_sub_tree(k, v);
                 })
         }
     }
 
-    /// # Safety
-    /// `idx` must be in the interval `[0; self.n)`
-    unsafe fn remove_pair(&mut self, idx: usize) -> (K, V) {
-        // Extract ownership of the key without using extra work
-        let pair = self.keys[idx].assume_init_read();
-        self.n -= 1;
-        for i in idx..self.n {
-            self.keys[i] = MaybeUninit::new(self.keys[i + 1].assume_init_read());
-        }
-        pair
-    }
-
     /// # Safety
     /// Must not be empty
     unsafe fn delete_max(&mut self) -> (K, V) {
+        self.push_down();
         if self.is_leaf() {
-            let (key, value) = self.remove_pair(self.n - 1);
-            self.aug_val = A::deleted_sub_tree(&key, &value, &self.aug_val);
+            let (key, value) = self.pop_pair();
+            self.deleted_sub_tree(&key, &value);
             return (key, value);
         }
 
-        if self.children[self.n].is_min() {
+        if self.children()[self.n].is_min() {
             self.make_space(self.n);
         }
 
-        let (key, value) = self.children[self.n].delete_max();
-        self.aug_val = A::deleted_sub_tree(&key, &value, &self.aug_val);
+        let (key, value) = self.child_mut(self.n).delete_max();
+        self.deleted_sub_tree(&key, &value);
         (key, value)
     }
 
     /// # Safety
     /// Must not be empty
     unsafe fn delete_min(&mut self) -> (K, V) {
+        self.push_down();
         if self.is_leaf() {
             let (key, value) = self.remove_pair(0);
-            self.aug_val = A::deleted_sub_tree(&key, &value, &self.aug_val);
+            self.deleted_sub_tree(&key, &value);
             return (key, value);
         }
 
-        if self.children[0].is_min() {
+        if self.children()[0].is_min() {
             self.make_space(0);
         }
 
-        let (key, value) = self.children[0].delete_min();
-        self.aug_val = A::deleted_sub_tree(&key, &value, &self.aug_val);
+        let (key, value) = self.child_mut(0).delete_min();
+        self.deleted_sub_tree(&key, &value);
         (key, value)
     }
 
     /// # Safety
-    /// Child `idx` and `idx + 1` must exist and have have mininum degree
+    /// Child `idx` and `idx + 1` must exist and fit in a single node along with the pair between
+    /// them, as they do if both have mininum degree
     unsafe fn merge_children(&mut self, idx: usize) {
+        self.child_mut(idx).push_down();
+        self.child_mut(idx + 1).push_down();
         let parent_pair = self.remove_pair(idx);
 
-        let mut right_child = self.children.remove(idx + 1);
-        let left_child = &mut self.children[idx];
+        let mut right_child = self.children_mut().remove(idx + 1);
+        let left_child = self.child_mut(idx);
 
-        left_child.aug_val = A::merge(&parent_pair, &left_child.aug_val, &right_child.aug_val);
+        if !A::RECOMPUTE_ALWAYS {
+            left_child.aug_val = A::merge(&parent_pair, &left_child.aug_val, &right_child.aug_val);
+        }
 
-        left_child.keys[MIN_DEGREE - 1] = MaybeUninit::new(parent_pair);
-        for i in 0..MIN_DEGREE - 1 {
-            let key = right_child.keys[i].assume_init_read();
-            left_child.keys[i + MIN_DEGREE] = MaybeUninit::new(key);
+        let (left, left_children) = left_child.parts_mut();
+        let (right, right_children) = right_child.parts_mut();
+        let left_n = left.n;
+        debug_assert!(left_n + right.n < 2 * MIN_DEGREE - 1);
+        left.keys[left_n] = MaybeUninit::new(parent_pair);
+        for i in 0..right.n {
+            let key = right.keys[i].assume_init_read();
+            left.keys[left_n + 1 + i] = MaybeUninit::new(key);
         }
-        left_child.n = 2 * MIN_DEGREE - 1;
+        left.n = left_n + 1 + right.n;
+        // The keys now belong to `left_child`, so they must not be dropped with `right_child`
+        right.n = 0;
 
-        if !left_child.is_leaf() {
-            left_child.children.append(&mut right_child.children);
+        if let (Some(left_children), Some(right_children)) = (left_children, right_children) {
+            left_children.append(right_children);
+        }
+        if A::RECOMPUTE_ALWAYS {
+            left_child.recompute_aug_val();
         }
     }
 
@@ -250,84 +644,91 @@ impl<K: Ord, V, A: Augment<K, V>> Node<K, V, A> {
     unsafe fn delete_own(&mut self, key: &K, idx: usize) -> V {
         let value = if self.is_leaf() {
             self.remove_pair(idx).1
-        } else if !self.children[idx].is_min() {
-            let (_, value) = self.keys[idx].assume_init_read();
-            self.keys[idx] = MaybeUninit::new(self.children[idx].delete_max());
-            value
-        } else if !self.children[idx + 1].is_min() {
-            let (_, value) = self.keys[idx].assume_init_read();
-            self.keys[idx] = MaybeUninit::new(self.children[idx + 1].delete_min());
-            value
+        } else if !self.children()[idx].is_min() {
+            // The pair is only replaced once the hooks run by the deletion are done
+            let pair = self.child_mut(idx).delete_max();
+            mem::replace(&mut self.keys[idx], MaybeUninit::new(pair))
+                .assume_init()
+                .1
+        } else if !self.children()[idx + 1].is_min() {
+            let pair = self.child_mut(idx + 1).delete_min();
+            mem::replace(&mut self.keys[idx], MaybeUninit::new(pair))
+                .assume_init()
+                .1
         } else {
             self.merge_children(idx);
-            self.children[idx].delete_own(key, MIN_DEGREE - 1)
+            self.child_mut(idx).delete_own(key, MIN_DEGREE - 1)
         };
 
-        self.aug_val = A::deleted_sub_tree(key, &value, &self.aug_val);
+        self.deleted_sub_tree(key, &value);
         value
     }
 
     /// # Safety
     /// Child with index `idx` must exist and not be full
     unsafe fn make_space(&mut self, mut idx: usize) -> usize {
-        if idx > 0 && !self.children[idx - 1].is_min() {
+        if idx > 0 && !self.children()[idx - 1].is_min() {
             // Steal a key from the left sibling (through parent)
-            let (victim_slice, thief_slice) = self.children.split_at_mut(idx);
+            let (node, Some(children)) = self.parts_mut() else {
+                unreachable!("a leaf has no siblings to steal from");
+            };
+            let (victim_slice, thief_slice) = children.split_at_mut(idx);
             let thief = &mut thief_slice[0];
             let victim = &mut victim_slice[idx - 1];
-
-            let parent_pair = self.keys[idx - 1].assume_init_read();
-            let sibling_pair = victim.remove_pair(victim.n - 1);
-
-            let stolen_child = if victim.is_leaf() {
-                None
-            } else {
-                Some(victim.children.pop().unwrap())
-            };
-
-            (thief.aug_val, victim.aug_val) = A::steal(
-                &parent_pair,
-                &sibling_pair,
-                stolen_child.as_ref().map(|c| &c.aug_val),
-                &thief.aug_val,
-                &victim.aug_val,
-            );
-
-            self.keys[idx - 1] = MaybeUninit::new(sibling_pair);
-            thief.insert_pair(0, parent_pair);
-
-            if let Some(child) = stolen_child {
-                thief.children.insert(0, child);
+            thief.push_down();
+            victim.push_down();
+
+            // The hook runs before any pair moves, so that every pair has a single owner if it
+            // panics
+            let aug_vals = (!A::RECOMPUTE_ALWAYS).then(|| {
+                A::steal(
+                    node.keys[idx - 1].assume_init_ref(),
+                    victim.keys[victim.n - 1].assume_init_ref(),
+                    victim.children().last().map(|c| &c.aug_val),
+                    &thief.aug_val,
+                    &victim.aug_val,
+                )
+            });
+
+            let sibling_pair = victim.pop_pair();
+            let parent_pair = mem::replace(&mut node.keys[idx - 1], MaybeUninit::new(sibling_pair));
+            thief.insert_pair(0, parent_pair.assume_init());
+            if !victim.is_leaf() {
+                thief
+                    .children_mut()
+                    .insert(0, victim.children_mut().pop().unwrap());
             }
-        } else if idx < self.n && !self.children[idx + 1].is_min() {
+
+            thief.updated_after_steal(victim, aug_vals);
+        } else if idx < self.n && !self.children()[idx + 1].is_min() {
             // Steal a key from the right sibling (through parent)
-            let (thief_slice, victim_slice) = self.children.split_at_mut(idx + 1);
+            let (node, Some(children)) = self.parts_mut() else {
+                unreachable!("a leaf has no siblings to steal from");
+            };
+            let (thief_slice, victim_slice) = children.split_at_mut(idx + 1);
             let thief = &mut thief_slice[idx];
             let victim = &mut victim_slice[0];
+            thief.push_down();
+            victim.push_down();
+
+            let aug_vals = (!A::RECOMPUTE_ALWAYS).then(|| {
+                A::steal(
+                    node.keys[idx].assume_init_ref(),
+                    victim.keys[0].assume_init_ref(),
+                    victim.children().first().map(|c| &c.aug_val),
+                    &thief.aug_val,
+                    &victim.aug_val,
+                )
+            });
 
-            let parent_pair = self.keys[idx].assume_init_read();
             let sibling_pair = victim.remove_pair(0);
-
-            let stolen_child = if victim.is_leaf() {
-                None
-            } else {
-                Some(victim.children.remove(0))
-            };
-
-            (thief.aug_val, victim.aug_val) = A::steal(
-                &parent_pair,
-                &sibling_pair,
-                stolen_child.as_ref().map(|c| &c.aug_val),
-                &thief.aug_val,
-                &victim.aug_val,
-            );
-
-            self.keys[idx] = MaybeUninit::new(sibling_pair);
-            thief.insert_pair(thief.n, parent_pair);
-
-            if let Some(child) = stolen_child {
-                thief.children.push(child);
+            let parent_pair = mem::replace(&mut node.keys[idx], MaybeUninit::new(sibling_pair));
+            thief.push_pair(parent_pair.assume_init());
+            if !victim.is_leaf() {
+                thief.children_mut().push(victim.children_mut().remove(0));
             }
+
+            thief.updated_after_steal(victim, aug_vals);
         } else if idx > 0 {
             // We can merge with the left sibling
             idx -= 1;
@@ -340,29 +741,77 @@ impl<K: Ord, V, A: Augment<K, V>> Node<K, V, A> {
         idx
     }
 
+    /// Updates the augment values of the node and its sibling `victim` after a pair was stolen
+    /// from the sibling, given the values [`Augment::steal`] returned unless they are recomputed
+    fn updated_after_steal(&mut self, victim: &mut Self, aug_vals: Option<(A::Value, A::Value)>) {
+        match aug_vals {
+            Some((thief, victim_val)) => {
+                self.aug_val = thief;
+                victim.aug_val = victim_val;
+                if A::RECOMPUTE_ON_DELETE {
+                    victim.recompute_aug_val();
+                }
+            }
+            None => {
+                self.recompute_aug_val();
+                victim.recompute_aug_val();
+            }
+        }
+    }
+
+    /// Steals from or merges with the siblings of child `idx` until it has at least
+    /// `MIN_DEGREE - 1` pairs, or is the only child left. Returns the new index of the child.
+    fn fill_child(&mut self, mut idx: usize) -> usize {
+        while self.n > 0 && self.children()[idx].n < MIN_DEGREE - 1 {
+            idx = unsafe { self.make_space(idx) };
+        }
+        idx
+    }
+
     fn delete_in_decendant(&mut self, mut idx: usize, key: &K) -> Option<V> {
         if self.is_leaf() {
             return None;
         }
 
-        if self.children[idx].is_min() {
+        if self.children()[idx].is_min() {
             idx = unsafe { self.make_space(idx) };
         }
 
-        self.children[idx].delete(key).map(|v| {
-            self.aug_val = A::deleted_sub_tree(key, &v, &self.aug_val);
-            v
-        })
+        let res = self.child_mut(idx).delete(key);
+        match &res {
+            Some(value) => self.deleted_sub_tree(key, value),
+            // Making space moved pairs around, even though the key was not found
+            None if A::RECOMPUTE_ALWAYS && self.n > 0 => self.recompute_aug_val(),
+            None => {}
+        }
+        res
     }
 
     fn delete(&mut self, key: &K) -> Option<V> {
+        self.push_down();
         match self.find_key_idx(key) {
             Ok(idx) => unsafe { Some(self.delete_own(key, idx)) },
             Err(idx) => self.delete_in_decendant(idx, key),
         }
     }
 
-    fn search(&self, key: &K, mut acc: A::Output) -> (Option<&V>, A::Output) {
+    /// Looks up `key` in the subtree without visiting its nodes, so that only the nodes on the path
+    /// to it are read
+    fn lookup(&self, key: &K) -> Option<&V> {
+        let mut node = self;
+        loop {
+            node.check_pending();
+            match (node.find_key_idx(key), node) {
+                (Ok(idx), _) => return Some(&node.pairs()[idx].1),
+                (Err(_), Node::Leaf(_)) => return None,
+                (Err(idx), Node::Internal(internal)) => node = &internal.children[idx],
+            }
+        }
+    }
+
+    /// Searches the subtree for `key`, visiting its nodes. `aug_val` is the augment value of the
+    /// node with the pending changes of its ancestors pushed down to it.
+    fn search(&self, key: &K, aug_val: &A::Value, mut acc: A::Output) -> (Option<&V>, A::Output) {
         let (idx, found) = match self.find_key_idx(key) {
             Ok(i) => (i, true),
             Err(i) => (i, false),
@@ -371,9 +820,9 @@ impl<K: Ord, V, A: Augment<K, V>> Node<K, V, A> {
         acc = A::visit(
             found,
             idx,
-            unsafe { mem::transmute(&self.keys[..self.n]) },
-            self.children.iter().map(|n| &n.aug_val),
-            &self.aug_val,
+            self.pairs(),
+            self.children().iter().map(|n| &n.aug_val),
+            aug_val,
             acc,
         );
 
@@ -382,114 +831,1250 @@ impl<K: Ord, V, A: Augment<K, V>> Node<K, V, A> {
         } else if self.is_leaf() {
             (None, acc)
         } else {
-            self.children[idx].search(key, acc)
+            let child = &self.children()[idx];
+            let pushed;
+            let child_aug_val = if A::has_pending(aug_val) {
+                pushed = A::pushed_down(aug_val, &child.aug_val);
+                &pushed
+            } else {
+                &child.aug_val
+            };
+            child.search(key, child_aug_val, acc)
         }
     }
 
-    fn is_min(&self) -> bool {
-        self.n < MIN_DEGREE
+    /// Combines the augment values of the pairs of the subtree within the bounds
+    fn augment_range(&self, start: Bound<&K>, end: Bound<&K>) -> A::Value {
+        self.check_pending();
+        let (first, last) = self.bounds_idx(start, end);
+        let pairs = &self.pairs()[first..last];
+        if self.is_leaf() {
+            return A::compute(pairs, core::iter::empty());
+        }
+        if first == last {
+            return self.children()[first].augment_range(start, end);
+        }
+
+        // Only the children at either end can stick out of the range, and only on one side
+        let (left, right);
+        let left = match start {
+            Bound::Unbounded => &self.children()[first].aug_val,
+            _ => {
+                left = self.children()[first].augment_range(start, Bound::Unbounded);
+                &left
+            }
+        };
+        let right = match end {
+            Bound::Unbounded => &self.children()[last].aug_val,
+            _ => {
+                right = self.children()[last].augment_range(Bound::Unbounded, end);
+                &right
+            }
+        };
+        let middle = self.children()[first + 1..last].iter().map(|c| &c.aug_val);
+        A::compute(pairs, core::iter::once(left).chain(middle).chain([right]))
     }
 
-    fn is_full(&self) -> bool {
-        self.n == 2 * MIN_DEGREE - 1
+    /// Returns the pair of the subtree with the largest key before `start`
+    fn last_before(&self, start: Bound<&K>) -> Option<&(K, V)> {
+        self.check_pending();
+        let (idx, _) = self.bounds_idx(start, Bound::Unbounded);
+        let in_child = if self.is_leaf() {
+            None
+        } else {
+            self.children()[idx].last_before(start)
+        };
+        in_child.or_else(|| idx.checked_sub(1).map(|i| &self.pairs()[i]))
     }
 
-    fn is_leaf(&self) -> bool {
-        self.children.is_empty()
+    /// Returns the pair of the subtree with the smallest key past `end`
+    fn first_past(&self, end: Bound<&K>) -> Option<&(K, V)> {
+        self.check_pending();
+        let (_, idx) = self.bounds_idx(Bound::Unbounded, end);
+        let in_child = if self.is_leaf() {
+            None
+        } else {
+            self.children()[idx].first_past(end)
+        };
+        in_child.or_else(|| self.pairs().get(idx))
     }
-}
 
-impl<K, V, A> Debug for Node<K, V, A>
-where
-    A: Augment<K, V>,
-    K: Debug,
-    V: Debug,
-    A::Value: Debug,
-{
-    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
-        f.debug_struct("Node")
-            .field(
-                "keys",
-                &format!("[{}]", unsafe {
-                    mem::transmute::<_, &[(K, V)]>(&self.keys[..self.n])
-                        .iter()
-                        .map(|(k, v)| format!("({k:?}, {v:?}), "))
-                        .collect::<String>()
-                        .strip_suffix(", ")
-                        .unwrap()
-                }),
-            )
-            .field("children", &self.children)
-            .field("aug_val", &self.aug_val)
-            .finish()
+    /// Panics if a change made by [`BTree::update_range`] has yet to be pushed down to the pairs
+    /// and children of the node, which would otherwise be read with their old values
+    fn check_pending(&self) {
+        assert!(
+            !A::has_pending(&self.aug_val),
+            "a change made by update_range is still pending, apply it first"
+        );
     }
-}
 
-/// BTree based on the "Introduction to Algorithms" book
-pub struct BTree<K, V, A: Augment<K, V> = ()> {
-    root: Node<K, V, A>,
-}
+    /// Whether a change made by [`BTree::update_range`] is pending anywhere in the subtree
+    fn has_pending_below(&self) -> bool {
+        A::has_pending(&self.aug_val) || self.children().iter().any(Self::has_pending_below)
+    }
 
-impl<K: Ord, V> BTree<K, V> {
-    pub fn new() -> Self {
-        Self {
-            root: Node::new_root(),
+    /// Pushes a pending change recorded in the augment value down to the pairs and children
+    fn push_down(&mut self) {
+        if A::has_pending(&self.aug_val) {
+            let (node, children) = self.parts_mut();
+            let pairs = unsafe { slice_assume_init_mut(&mut node.keys[..node.n]) };
+            let children = children
+                .into_iter()
+                .flat_map(|c| c.iter_mut())
+                .map(|c| &mut c.aug_val);
+            A::push_down(&mut node.aug_val, pairs, children);
         }
     }
 
-    pub fn with_augment<T: Augment<K, V>>() -> BTree<K, V, T> {
-        BTree {
-            root: Node::new_root(),
-        }
+    /// The maximum number of pairs a subtree of height `height` can hold
+    fn capacity(height: usize) -> usize {
+        (0..=height)
+            .fold(1usize, |cap, _| cap.saturating_mul(2 * MIN_DEGREE))
+            .saturating_sub(1)
     }
-}
 
-impl<K: Ord, V, A: Augment<K, V>> BTree<K, V, A> {
-    pub fn insert(&mut self, key: K, value: V) -> bool {
-        if self.root.is_full() {
-            let (root_pair, child) = unsafe { self.root.split() };
+    /// Builds a subtree of height `height` out of the next `len` pairs of `pairs`, which must be
+    /// sorted and free of duplicates. Only the root may have fewer than `MIN_DEGREE` children.
+    /// Unless `compute_aug` is set, the augment values are left for the caller to compute.
+    fn bulk_load(
+        pairs: &mut impl Iterator<Item = (K, V)>,
+        len: usize,
+        height: usize,
+        is_root: bool,
+        compute_aug: bool,
+        alloc: &S::Alloc,
+    ) -> Self {
+        debug_assert!(len <= Self::capacity(height));
+
+        let mut node = if height == 0 {
+            Self::new_in(alloc.clone())
+        } else {
+            Self::new_internal_in(alloc.clone())
+        };
+        if height == 0 {
+            for pair in pairs.by_ref().take(len) {
+                node.push_pair(pair);
+            }
+            debug_assert_eq!(node.n, len, "ran out of pairs");
+        } else {
+            let mut num_children = (len + 1).div_ceil(Self::capacity(height - 1) + 1);
+            if !is_root {
+                num_children = num_children.max(MIN_DEGREE);
+            }
 
-            let mut old_root = Node::new_root();
-            mem::swap(&mut self.root, &mut old_root);
+            // Spread the pairs that are not separators as evenly as possible among the children
+            let child_pairs = len - (num_children - 1);
+            for i in 0..num_children {
+                let child_len =
+                    child_pairs / num_children + usize::from(i < child_pairs % num_children);
+                let child =
+                    Self::bulk_load(pairs, child_len, height - 1, false, compute_aug, alloc);
+                node.children_mut().push(child);
+
+                if i < num_children - 1 {
+                    let pair = pairs.next().expect("ran out of pairs");
+                    node.push_pair(pair);
+                }
+            }
+        }
 
-            self.root.aug_val = A::split_root(&root_pair, &old_root.aug_val, &child.aug_val);
-            self.root.keys[0] = MaybeUninit::new(root_pair);
-            self.root.children.push(old_root);
-            self.root.children.push(child);
-            self.root.n = 1;
+        if compute_aug {
+            node.recompute_aug_val();
         }
+        node
+    }
 
-        self.root.insert_non_full(key, value).is_ok()
+    /// Builds the root of a tree out of pairs that are sorted by key and free of duplicates, see
+    /// [`Node::bulk_load`]
+    fn from_sorted(pairs: Vec<(K, V)>, compute_aug: bool, alloc: S::Alloc) -> Self {
+        let len = pairs.len();
+        let height = (0..).find(|&h| Self::capacity(h) >= len).unwrap();
+        Self::bulk_load(
+            &mut pairs.into_iter(),
+            len,
+            height,
+            true,
+            compute_aug,
+            &alloc,
+        )
     }
 
-    pub fn delete(&mut self, key: &K) -> Option<V> {
-        let res = self.root.delete(key);
-        if self.root.children.len() == 1 {
-            self.root = self.root.children.pop().unwrap();
+    /// The number of edges from the node down to its leaves
+    fn height(&self) -> usize {
+        let mut node = self;
+        let mut height = 0;
+        while !node.is_leaf() {
+            node = &node.children()[0];
+            height += 1;
         }
-        res
+        height
     }
 
-    pub fn search(&self, key: &K) -> Option<&V> {
-        self.root.search(key, A::initial_output()).0
+    /// The number of pairs in the subtree
+    fn count_pairs(&self) -> usize {
+        self.n
+            + self
+                .children()
+                .iter()
+                .map(|c| c.count_pairs())
+                .sum::<usize>()
     }
 
-    pub fn augment_search(&self, key: &K) -> A::Output {
-        self.root.search(key, A::initial_output()).1
+    /// Moves the pairs of the subtree into `out` in ascending key order
+    fn into_pairs(mut self, out: &mut Vec<(K, V)>) {
+        self.push_down();
+        // The pairs are moved out one by one, so they must not be dropped with the node
+        let (node, children) = self.parts_mut();
+        let n = mem::replace(&mut node.n, 0);
+        let mut children = children
+            .map(|c| mem::replace(c, Children::new()))
+            .into_iter()
+            .flatten();
+        for i in 0..n {
+            if let Some(child) = children.next() {
+                child.into_pairs(out);
+            }
+            out.push(unsafe { node.keys[i].assume_init_read() });
+        }
+        if let Some(child) = children.next() {
+            child.into_pairs(out);
+        }
     }
-}
 
-impl<K: Ord, V, A: Augment<K, V>> Default for BTree<K, V, A> {
-    fn default() -> Self {
+    /// Joins the trees `left` and `right` of heights `left_height` and `right_height` with `mid`
+    /// between them, returning the joined tree and its height
+    fn join(
+        mut left: Self,
+        mut left_height: usize,
+        mid: (K, V),
+        mut right: Self,
+        mut right_height: usize,
+    ) -> (Self, usize) {
+        if left_height > right_height {
+            if left.is_full() {
+                unsafe { left.split_root() }.unwrap_or_else(|err| Self::alloc_failed(err));
+                left_height += 1;
+            }
+            left.join_right(left_height, mid, right, right_height);
+            (left, left_height)
+        } else if right_height > left_height {
+            if right.is_full() {
+                unsafe { right.split_root() }.unwrap_or_else(|err| Self::alloc_failed(err));
+                right_height += 1;
+            }
+            right.join_left(right_height, left, left_height, mid);
+            (right, right_height)
+        } else {
+            let mut root = Self::new_internal_in(left.alloc());
+            root.insert_pair(0, mid);
+            root.children_mut().push(left);
+            root.children_mut().push(right);
+            root.fill_child(0);
+            if root.n > 0 {
+                root.fill_child(1);
+            }
+
+            if root.n == 0 {
+                (root.children_mut().pop().unwrap(), left_height)
+            } else {
+                root.recompute_aug_val();
+                (root, left_height + 1)
+            }
+        }
+    }
+
+    /// Attaches `right` below the right spine of the subtree, which must not be full and must be
+    /// higher than `right`
+    fn join_right(&mut self, height: usize, mid: (K, V), right: Self, right_height: usize) {
+        self.push_down();
+        if height == right_height + 1 {
+            self.push_pair(mid);
+            self.children_mut().push(right);
+            self.fill_child(self.n);
+        } else {
+            if self.children()[self.n].is_full() {
+                unsafe { self.split_child(self.n) }.unwrap_or_else(|err| Self::alloc_failed(err));
+            }
+            self.child_mut(self.n)
+                .join_right(height - 1, mid, right, right_height);
+        }
+        self.recompute_aug_val();
+    }
+
+    /// Attaches `left` below the left spine of the subtree, which must not be full and must be
+    /// higher than `left`
+    fn join_left(&mut self, height: usize, left: Self, left_height: usize, mid: (K, V)) {
+        self.push_down();
+        if height == left_height + 1 {
+            self.insert_pair(0, mid);
+            self.children_mut().insert(0, left);
+            self.fill_child(0);
+        } else {
+            if self.children()[0].is_full() {
+                unsafe { self.split_child(0) }.unwrap_or_else(|err| Self::alloc_failed(err));
+            }
+            self.child_mut(0)
+                .join_left(height - 1, left, left_height, mid);
+        }
+        self.recompute_aug_val();
+    }
+
+    /// Joins the trees `left` and `right` of heights `left_height` and `right_height`, where all
+    /// keys of `left` are smaller than those of `right`, returning the joined tree and its height
+    fn concat(
+        left: Self,
+        left_height: usize,
+        mut right: Self,
+        mut right_height: usize,
+    ) -> (Self, usize) {
+        // Only an empty root has no pairs
+        if right.n == 0 {
+            return (left, left_height);
+        }
+
+        let mid = unsafe { right.delete_min() };
+        if right.n == 0 && !right.is_leaf() {
+            right = right.children_mut().pop().unwrap();
+            right_height -= 1;
+        }
+        Self::join(left, left_height, mid, right, right_height)
+    }
+
+    /// Splits the subtree of height `height` in two at a gap between its pairs, returning both
+    /// halves along with their heights. `find_gap` locates `gap` within a node, as the index of
+    /// the child holding it, or of the pair after it in a leaf, along with the gap to look for
+    /// within that child.
+    fn split_at_gap<G>(
+        mut self,
+        height: usize,
+        gap: G,
+        find_gap: &impl Fn(&Self, G) -> (usize, G),
+    ) -> ((Self, usize), (Self, usize)) {
+        self.push_down();
+        let (i, gap) = find_gap(&self, gap);
+
+        let mut right = self
+            .try_new_sibling()
+            .unwrap_or_else(|err| Self::alloc_failed(err));
+        let (node, _) = self.parts_mut();
+        let (new, _) = right.parts_mut();
+        for j in i..node.n {
+            new.keys[j - i] = MaybeUninit::new(unsafe { node.keys[j].assume_init_read() });
+        }
+        new.n = node.n - i;
+        node.n = i;
+
+        if self.is_leaf() {
+            self.recompute_aug_val();
+            right.recompute_aug_val();
+            return ((self, 0), (right, 0));
+        }
+
+        // Split the child holding the gap, and join each half with what is left of the node on
+        // its side
+        self.children_mut().move_tail(i + 1, right.children_mut());
+        let child = self.children_mut().pop().unwrap();
+        let ((child_left, child_left_height), (child_right, child_right_height)) =
+            child.split_at_gap(height - 1, gap, find_gap);
+
+        let right = if right.n == 0 {
+            (child_right, child_right_height)
+        } else {
+            let mid = unsafe { right.remove_pair(0) };
+            let (rest, rest_height) = right.into_root(height);
+            Self::join(child_right, child_right_height, mid, rest, rest_height)
+        };
+        let left = if self.n == 0 {
+            (child_left, child_left_height)
+        } else {
+            let mid = unsafe { self.pop_pair() };
+            let (rest, rest_height) = self.into_root(height);
+            Self::join(rest, rest_height, mid, child_left, child_left_height)
+        };
+        (left, right)
+    }
+
+    /// Splits the subtree of height `height` into the pairs with keys before `start` and the rest,
+    /// returning both along with their heights
+    fn split_at_key(self, height: usize, start: Bound<&K>) -> ((Self, usize), (Self, usize)) {
+        self.split_at_gap(height, start, &|node, start| {
+            (node.bounds_idx(start, Bound::Unbounded).0, start)
+        })
+    }
+
+    /// Turns what is left of an internal node of height `height` into the root of a tree,
+    /// replacing it by its only child if it has no pairs left
+    fn into_root(mut self, height: usize) -> (Self, usize) {
+        if self.n == 0 {
+            (self.children_mut().pop().unwrap(), height - 1)
+        } else {
+            self.recompute_aug_val();
+            (self, height)
+        }
+    }
+
+    fn recompute_aug_val(&mut self) {
+        self.aug_val = self.computed_aug_val();
+    }
+
+    /// Computes the augment value from the pairs and the children's augment values alone
+    fn computed_aug_val(&self) -> A::Value {
+        A::compute(self.pairs(), self.children().iter().map(|c| &c.aug_val))
+    }
+
+    /// Updates the augment value after `key` and `value` were removed from the subtree, see
+    /// [`Augment::RECOMPUTE_ON_DELETE`]
+    fn deleted_sub_tree(&mut self, key: &K, value: &V) {
+        if !A::RECOMPUTE_ON_DELETE && !A::RECOMPUTE_ALWAYS {
+            self.aug_val = A::deleted_sub_tree(key, value, &self.aug_val);
+        } else if self.n > 0 || self.is_leaf() {
+            // A root that lost its last pair is replaced by its only child right away
+            self.recompute_aug_val();
+        }
+    }
+}
+
+impl<K: Ord, V, A: RangeUpdate<K, V>, S: Sharing<K, V, A>> Node<K, V, A, S> {
+    /// Applies `delta` to the values in the subtree with keys within the bounds. Children that lie
+    /// completely within them only have the change recorded in their augment value.
+    fn update_range(&mut self, start: Bound<&K>, end: Bound<&K>, delta: &A::Delta) {
+        self.push_down();
+        let (first, last) = self.bounds_idx(start, end);
+        for (_, value) in &mut self.pairs_mut()[first..last] {
+            *value = A::updated_value(delta, value);
+        }
+
+        if !self.is_leaf() {
+            for idx in first + 1..last {
+                let child = self.child_mut(idx);
+                child.aug_val = A::updated_sub_tree(delta, &child.aug_val);
+            }
+            // Only the children at the ends of the range can lie partly outside of it
+            if first == last {
+                self.child_mut(first).update_range(start, end, delta);
+            } else {
+                self.child_mut(first)
+                    .update_range(start, Bound::Unbounded, delta);
+                self.child_mut(last)
+                    .update_range(Bound::Unbounded, end, delta);
+            }
+        }
+        self.recompute_aug_val();
+    }
+
+    /// Pushes the pending changes on the path to `key` down, up to the node holding it
+    fn push_down_path(&mut self, key: &K) {
+        self.push_down();
+        if let Err(idx) = self.find_key_idx(key) {
+            if !self.is_leaf() {
+                self.child_mut(idx).push_down_path(key);
+            }
+        }
+    }
+
+    /// Pushes every pending change in the subtree down to the pairs
+    fn apply_pending(&mut self) {
+        self.push_down();
+        for idx in 0..self.children().len() {
+            let child = &self.children()[idx];
+            if !child.is_leaf() || A::has_pending(&child.aug_val) {
+                self.child_mut(idx).apply_pending();
+            }
+        }
+    }
+}
+
+impl<K, V, A, S> Debug for Node<K, V, A, S>
+where
+    A: Augment<K, V>,
+    S: Sharing<K, V, A>,
+    K: Debug,
+    V: Debug,
+    A::Value: Debug,
+{
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        f.debug_struct("Node")
+            .field(
+                "keys",
+                &format!("[{}]", unsafe {
+                    slice_assume_init(&self.keys[..self.n])
+                        .iter()
+                        .map(|(k, v)| format!("({k:?}, {v:?}), "))
+                        .collect::<String>()
+                        .strip_suffix(", ")
+                        .unwrap()
+                }),
+            )
+            .field("children", &self.children())
+            .field("aug_val", &self.aug_val)
+            .finish()
+    }
+}
+
+impl<K: Clone, V: Clone, A: Augment<K, V>> Clone for LeafNode<K, V, A>
+where
+    A::Value: Clone,
+{
+    fn clone(&self) -> Self {
+        let mut node = Self {
+            n: 0,
+            keys: [const { MaybeUninit::uninit() }; 2 * MIN_DEGREE - 1],
+            aug_val: self.aug_val.clone(),
+        };
+        // Count the pairs as they are cloned, so a panicking clone drops only the finished ones
+        for pair in self.pairs() {
+            node.keys[node.n] = MaybeUninit::new(pair.clone());
+            node.n += 1;
+        }
+        node
+    }
+}
+
+impl<K: Clone, V: Clone, A: Augment<K, V>, S: Sharing<K, V, A>> Clone for InternalNode<K, V, A, S>
+where
+    A::Value: Clone,
+{
+    fn clone(&self) -> Self {
+        let mut children = Children::new();
+        for child in &self.children {
+            children.push(S::share(child));
+        }
         Self {
-            root: Node::new_root(),
+            data: self.data.clone(),
+            children,
+        }
+    }
+}
+
+impl<K, V, A: Augment<K, V>> Drop for LeafNode<K, V, A> {
+    fn drop(&mut self) {
+        for pair in &mut self.keys[..self.n] {
+            unsafe { pair.assume_init_drop() };
+        }
+    }
+}
+
+/// BTree based on the "Introduction to Algorithms" book
+///
+/// # Panic safety
+/// Key comparisons and [`Augment`] hooks are user code that runs while nodes are only partially
+/// updated. If one of them panics during a modifying operation, the tree is *poisoned*: no
+/// undefined behaviour occurs and no pair is dropped twice, but the contents of the tree can no
+/// longer be trusted. Every later operation on a poisoned tree panics, and dropping it leaks the
+/// remaining pairs instead of dropping them. Use [`BTree::is_poisoned`] to check for this state.
+///
+/// # Sharing
+/// By default every node is owned by its parent. With [`Shared`](persistent::Shared) nodes, the
+/// tree becomes a [`PersistentBTree`](persistent::PersistentBTree) whose snapshots share all
+/// unmodified nodes.
+///
+/// # Allocation
+/// [`Unique`] nodes allocate with the [`allocator_api2`] allocator given to [`BTree::new_in`], and
+/// [`BTree::try_insert`] reports a failed allocation instead of aborting.
+pub struct BTree<K, V, A: Augment<K, V> = (), S: Sharing<K, V, A> = Unique> {
+    root: ManuallyDrop<Node<K, V, A, S>>,
+    len: usize,
+    poisoned: bool,
+}
+
+impl<K: Ord, V> BTree<K, V> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_augment<T: Augment<K, V>>() -> BTree<K, V, T> {
+        BTree::default()
+    }
+}
+
+impl<K: Ord, V, A: Augment<K, V>, Al: Allocator + Clone> BTree<K, V, A, Unique<Al>> {
+    /// Like [`BTree::insert`], but hands the pair back instead of aborting if a node cannot be
+    /// allocated. Nodes that were split on the way down stay split, but the tree is otherwise
+    /// unchanged and can still be used.
+    pub fn try_insert(&mut self, key: K, value: V) -> Result<bool, TryInsertError<K, V>> {
+        self.insert_fallible(key, value)
+            .map_err(|(key, value)| TryInsertError { key, value })
+    }
+}
+
+/// The error returned by [`BTree::try_insert`] when a node could not be allocated
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TryInsertError<K, V> {
+    /// The key that was not inserted
+    pub key: K,
+    /// The value that was not inserted
+    pub value: V,
+}
+
+impl<K, V> Display for TryInsertError<K, V> {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        f.write_str("could not allocate a node for the pair")
+    }
+}
+
+impl<K: Debug, V: Debug> Error for TryInsertError<K, V> {}
+
+impl<K: Ord, V, A: Augment<K, V>, S: Sharing<K, V, A>> BTree<K, V, A, S> {
+    /// Creates an empty tree whose nodes allocate with `alloc`
+    pub fn new_in(alloc: S::Alloc) -> Self {
+        Self {
+            root: ManuallyDrop::new(Node::new_in(alloc)),
+            len: 0,
+            poisoned: false,
+        }
+    }
+
+    pub fn insert(&mut self, key: K, value: V) -> bool {
+        self.insert_fallible(key, value)
+            .unwrap_or_else(|_| Node::<K, V, A, S>::alloc_failed(AllocError))
+    }
+
+    /// Inserts the pair unless its key is already present, handing it back if a node could not be
+    /// allocated
+    fn insert_fallible(&mut self, key: K, value: V) -> Result<bool, (K, V)> {
+        self.poison_on_unwind(|tree| {
+            let root = tree.root_mut();
+            if root.is_full() && unsafe { root.split_root() }.is_err() {
+                return Err((key, value));
+            }
+
+            match root.insert_non_full(key, value) {
+                Ok(()) => {
+                    tree.len += 1;
+                    Ok(true)
+                }
+                Err(InsertError::Duplicate(..)) => Ok(false),
+                Err(InsertError::Alloc(key, value)) => Err((key, value)),
+            }
+        })
+    }
+
+    pub fn delete(&mut self, key: &K) -> Option<V> {
+        self.poison_on_unwind(|tree| {
+            let res = tree.root_mut().delete(key);
+            if tree.root.children().len() == 1 {
+                *tree.root = tree.root_mut().children_mut().pop().unwrap();
+            }
+            tree.len -= usize::from(res.is_some());
+            res
+        })
+    }
+
+    /// Returns the value of `key`
+    ///
+    /// # Panics
+    /// Panics if a change made by [`BTree::update_range`] is still pending on the path to `key`.
+    /// Use [`BTree::search_updated`] instead.
+    pub fn search(&self, key: &K) -> Option<&V> {
+        self.check_poison();
+        self.root.lookup(key)
+    }
+
+    pub fn augment_search(&self, key: &K) -> A::Output {
+        self.check_poison();
+        self.root
+            .search(key, &self.root.aug_val, A::initial_output())
+            .1
+    }
+
+    /// Combines the augment values of the pairs with keys in `range` with [`Augment::compute`], as
+    /// if they made up a node of their own, in `O(log n)` time
+    ///
+    /// # Panics
+    /// Panics if a change made by [`BTree::update_range`] is still pending in a node that has to
+    /// be visited. Use [`BTree::apply_pending`] first.
+    pub fn augment_range(&self, range: impl RangeBounds<K>) -> A::Value {
+        self.check_poison();
+        self.root
+            .augment_range(range.start_bound(), range.end_bound())
+    }
+
+    /// Returns the pair with the smallest key
+    pub fn first_key_value(&self) -> Option<(&K, &V)> {
+        self.check_poison();
+        let mut node = &*self.root;
+        node.check_pending();
+        while !node.is_leaf() {
+            node = &node.children()[0];
+            node.check_pending();
+        }
+        node.pairs().first().map(|(k, v)| (k, v))
+    }
+
+    /// Returns the pair with the largest key
+    pub fn last_key_value(&self) -> Option<(&K, &V)> {
+        self.check_poison();
+        let mut node = &*self.root;
+        node.check_pending();
+        while !node.is_leaf() {
+            node = &node.children()[node.n];
+            node.check_pending();
+        }
+        node.pairs().last().map(|(k, v)| (k, v))
+    }
+
+    /// Removes and returns the pair with the smallest key
+    pub fn pop_first(&mut self) -> Option<(K, V)> {
+        // Safety: The tree is not empty
+        self.pop_with(|root| unsafe { root.delete_min() })
+    }
+
+    /// Removes and returns the pair with the largest key
+    pub fn pop_last(&mut self) -> Option<(K, V)> {
+        // Safety: The tree is not empty
+        self.pop_with(|root| unsafe { root.delete_max() })
+    }
+
+    /// Removes the pair `delete` takes out of the root, unless the tree is empty
+    fn pop_with(&mut self, delete: impl FnOnce(&mut Node<K, V, A, S>) -> (K, V)) -> Option<(K, V)> {
+        self.poison_on_unwind(|tree| {
+            if tree.len == 0 {
+                return None;
+            }
+
+            let pair = delete(tree.root_mut());
+            if tree.root.children().len() == 1 {
+                *tree.root = tree.root_mut().children_mut().pop().unwrap();
+            }
+            tree.len -= 1;
+            Some(pair)
+        })
+    }
+
+    /// Returns the pair with the largest key smaller than or equal to `key`, like the latest
+    /// reading at or before a point in time
+    ///
+    /// Unlike `std::lower_bound` in C++, which finds the first element *not less* than `key`,
+    /// this looks below `key`. The C++ meaning is what [`BTree::upper_bound`] returns.
+    pub fn lower_bound(&self, key: &K) -> Option<(&K, &V)> {
+        self.check_poison();
+        self.root
+            .last_before(Bound::Excluded(key))
+            .map(|(k, v)| (k, v))
+    }
+
+    /// Returns the pair with the smallest key larger than or equal to `key`
+    ///
+    /// Unlike `std::upper_bound` in C++, which finds the first element *greater* than `key`, this
+    /// also returns a pair with a key equal to `key`. The C++ meaning is what
+    /// [`BTree::successor`] returns.
+    pub fn upper_bound(&self, key: &K) -> Option<(&K, &V)> {
+        self.check_poison();
+        self.root
+            .first_past(Bound::Excluded(key))
+            .map(|(k, v)| (k, v))
+    }
+
+    /// Returns the pair with the largest key smaller than `key`
+    pub fn predecessor(&self, key: &K) -> Option<(&K, &V)> {
+        self.check_poison();
+        self.root
+            .last_before(Bound::Included(key))
+            .map(|(k, v)| (k, v))
+    }
+
+    /// Returns the pair with the smallest key larger than `key`
+    pub fn successor(&self, key: &K) -> Option<(&K, &V)> {
+        self.check_poison();
+        self.root
+            .first_past(Bound::Included(key))
+            .map(|(k, v)| (k, v))
+    }
+
+    /// Returns the pair whose key is closest to `key`, measuring the distance between keys by
+    /// subtracting the smaller from the larger. Ties go to the smaller key.
+    pub fn nearest<D: Ord>(&self, key: &K) -> Option<(&K, &V)>
+    where
+        for<'a> &'a K: Sub<&'a K, Output = D>,
+    {
+        match (self.lower_bound(key), self.upper_bound(key)) {
+            (Some(below), Some(above)) if above.0 - key < key - below.0 => Some(above),
+            (below, above) => below.or(above),
+        }
+    }
+
+    /// Returns the number of pairs in the tree
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Returns an iterator over the pairs of the tree in ascending key order
+    pub fn iter(&self) -> Iter<'_, K, V, A, S> {
+        self.check_poison();
+        let mut iter = Iter {
+            stack: Vec::new(),
+            remaining: self.len,
+        };
+        iter.push_leftmost(&self.root);
+        iter
+    }
+
+    /// Returns an iterator over the pairs of the tree with keys in `range`, in ascending key order
+    pub fn range(&self, range: impl RangeBounds<K>) -> Range<'_, K, V, A, S> {
+        self.check_poison();
+        let mut iter = Iter {
+            stack: Vec::new(),
+            remaining: self.len,
+        };
+        if !is_empty_range(range.start_bound(), range.end_bound()) {
+            // Stop at the first pair with a key in the range, like `push_leftmost` stops at the
+            // smallest pair
+            let mut node = &*self.root;
+            loop {
+                node.check_pending();
+                let (first, _) = node.bounds_idx(range.start_bound(), Bound::Unbounded);
+                iter.stack.push((node, first));
+                if node.is_leaf() {
+                    break;
+                }
+                node = &node.children()[first];
+            }
+        }
+
+        let end = self.root.first_past(range.end_bound()).map(|(key, _)| key);
+        Range { iter, end }
+    }
+
+    /// Removes the pairs with keys in `range`, returning how many there were
+    ///
+    /// Subtrees that lie completely within the range are cut out as a whole, so only the nodes on
+    /// the paths to the two ends of the range are rebalanced and have their augment values
+    /// recomputed. This takes `O(log n)` time on top of dropping the pairs.
+    pub fn remove_range(&mut self, range: impl RangeBounds<K>) -> usize {
+        self.replace_range(range.start_bound(), range.end_bound(), |cut| {
+            let removed = cut.count_pairs();
+            (Node::new_in(cut.alloc()), removed)
+        })
+    }
+
+    /// Removes the pairs with keys in `range` like [`BTree::remove_range`], returning an iterator
+    /// over them in ascending key order
+    pub fn drain_range(&mut self, range: impl RangeBounds<K>) -> Drain<K, V> {
+        let pairs = self.replace_range(range.start_bound(), range.end_bound(), |cut| {
+            let empty = Node::new_in(cut.alloc());
+            let mut pairs = Vec::new();
+            cut.into_pairs(&mut pairs);
+            (empty, pairs)
+        });
+        Drain(pairs.into_iter())
+    }
+
+    /// Keeps only the pairs for which `keep` returns `true`, visiting them in ascending key order
+    ///
+    /// The tree is rebuilt in bulk out of the pairs that are kept, which takes `O(n)` time.
+    pub fn retain(&mut self, mut keep: impl FnMut(&K, &mut V) -> bool) {
+        self.filter_range(Bound::Unbounded, Bound::Unbounded, |key, value| {
+            !keep(key, value)
+        });
+    }
+
+    /// Removes the pairs with keys in `range` for which `pred` returns `true`, returning an
+    /// iterator over them in ascending key order
+    ///
+    /// Unlike `extract_if` on the standard collections, this is eager: `pred` sees every pair in
+    /// the range and the pairs are removed before the iterator is returned, so dropping the
+    /// iterator early only drops the rest of the removed pairs. The range is cut out like by
+    /// [`BTree::remove_range`], and the pairs that are kept are put back in bulk, which takes
+    /// `O(m + log n)` time for `m` pairs in the range.
+    pub fn extract_if(
+        &mut self,
+        range: impl RangeBounds<K>,
+        pred: impl FnMut(&K, &mut V) -> bool,
+    ) -> Drain<K, V> {
+        let removed = self.filter_range(range.start_bound(), range.end_bound(), pred);
+        Drain(removed.into_iter())
+    }
+
+    /// Removes the pairs with keys within the bounds for which `pred` returns `true` and returns
+    /// them in ascending key order
+    fn filter_range(
+        &mut self,
+        start: Bound<&K>,
+        end: Bound<&K>,
+        mut pred: impl FnMut(&K, &mut V) -> bool,
+    ) -> Vec<(K, V)> {
+        self.replace_range(start, end, |cut| {
+            let alloc = cut.alloc();
+            let mut pairs = Vec::new();
+            cut.into_pairs(&mut pairs);
+
+            let mut kept = Vec::with_capacity(pairs.len());
+            let mut removed = Vec::new();
+            for (key, mut value) in pairs {
+                if pred(&key, &mut value) {
+                    removed.push((key, value));
+                } else {
+                    kept.push((key, value));
+                }
+            }
+            (Node::from_sorted(kept, true, alloc), removed)
+        })
+    }
+
+    /// Cuts the pairs with keys within the bounds out of the tree and puts the tree returned by
+    /// `replace`, which is given them, in their place. The keys of the new tree must lie within the
+    /// bounds as well.
+    ///
+    /// Only the nodes on the paths to the two ends of the range are rebalanced, by splitting the
+    /// tree at them and joining the pieces back together.
+    fn replace_range<R>(
+        &mut self,
+        start: Bound<&K>,
+        end: Bound<&K>,
+        replace: impl FnOnce(Node<K, V, A, S>) -> (Node<K, V, A, S>, R),
+    ) -> R {
+        self.check_poison();
+        if is_empty_range(start, end) {
+            let (new, res) = replace(Node::new_in(self.root.alloc()));
+            debug_assert_eq!(new.n, 0, "keys of the new tree lie outside of the range");
+            return res;
+        }
+
+        self.poison_on_unwind(|tree| {
+            // The pieces are no longer part of the tree, so they are leaked like the rest of its
+            // pairs if a hook panics before they are joined back together
+            let root = tree.take_root();
+            let height = root.height();
+            let ((left, left_height), (rest, rest_height)) = root.split_at_key(height, start);
+            let left = ManuallyDrop::new(left);
+            let ((cut, _), (right, right_height)) = match end {
+                Bound::Included(end) => rest.split_at_key(rest_height, Bound::Excluded(end)),
+                Bound::Excluded(end) => rest.split_at_key(rest_height, Bound::Included(end)),
+                Bound::Unbounded => {
+                    let empty = Node::new_in(rest.alloc());
+                    ((rest, rest_height), (empty, 0))
+                }
+            };
+            let right = ManuallyDrop::new(right);
+
+            tree.len -= cut.count_pairs();
+            let (new, res) = replace(cut);
+            tree.len += new.count_pairs();
+
+            let new_height = new.height();
+            let left = ManuallyDrop::into_inner(left);
+            let (left, left_height) = Node::concat(left, left_height, new, new_height);
+            let right = ManuallyDrop::into_inner(right);
+            *tree.root_mut() = Node::concat(left, left_height, right, right_height).0;
+            res
+        })
+    }
+
+    /// Builds a tree out of pairs that are sorted by key and free of duplicates. If `compute_aug`
+    /// is set, every augment value is computed from scratch, and otherwise left for the caller.
+    fn from_sorted(pairs: Vec<(K, V)>, compute_aug: bool, alloc: S::Alloc) -> Self {
+        let len = pairs.len();
+        let root = Node::from_sorted(pairs, compute_aug, alloc);
+
+        Self {
+            root: ManuallyDrop::new(root),
+            len,
+            poisoned: false,
+        }
+    }
+
+    /// Returns `true` if a change made by [`BTree::update_range`] has yet to reach some of the
+    /// values. Takes `O(n)` time in the worst case.
+    pub fn has_pending_changes(&self) -> bool {
+        self.check_poison();
+        self.root.has_pending_below()
+    }
+
+    /// Returns `true` if a key comparison or augment hook panicked while the tree was being
+    /// modified. See the [panic safety](BTree#panic-safety) section for details.
+    pub fn is_poisoned(&self) -> bool {
+        self.poisoned
+    }
+
+    fn root_mut(&mut self) -> &mut Node<K, V, A, S> {
+        &mut self.root
+    }
+
+    /// Moves the root out of the tree, leaving an empty one in its place
+    fn take_root(&mut self) -> Node<K, V, A, S> {
+        let empty = Node::new_in(self.root.alloc());
+        mem::replace(self.root_mut(), empty)
+    }
+
+    fn check_poison(&self) {
+        if self.poisoned {
+            panic!("BTree is poisoned: a previous operation panicked while modifying it");
+        }
+    }
+
+    /// Runs `op`, leaving the tree poisoned if it unwinds
+    fn poison_on_unwind<R>(&mut self, op: impl FnOnce(&mut Self) -> R) -> R {
+        self.check_poison();
+        self.poisoned = true;
+        let res = op(self);
+        self.poisoned = false;
+        res
+    }
+}
+
+impl<K: Ord, V, A: RangeUpdate<K, V>, S: Sharing<K, V, A>> BTree<K, V, A, S> {
+    /// Applies `delta` to every value with a key in `range`
+    ///
+    /// This takes `O(log n)` time, as the change is only recorded in the augment values of the
+    /// subtrees that lie completely inside the range. [`BTree::augment_search`] takes pending
+    /// changes into account right away, but they only reach the values themselves once later
+    /// modifications push them down. Until then, methods that read values panic rather than return
+    /// old ones if they come across a pending change, and [`BTree::write_to`] and serializing the
+    /// tree fail. Use [`BTree::search_updated`] to look up a single value, or
+    /// [`BTree::apply_pending`] to bring every value up to date.
+    pub fn update_range(&mut self, range: impl RangeBounds<K>, delta: &A::Delta) {
+        self.poison_on_unwind(|tree| {
+            tree.root_mut()
+                .update_range(range.start_bound(), range.end_bound(), delta)
+        });
+    }
+
+    /// Looks up `key` like [`BTree::search`], pushing the pending changes made by
+    /// [`BTree::update_range`] on the way down to it first, in `O(log n)` time. Nodes that are
+    /// [`Shared`](persistent::Shared) with snapshots are copied on the way.
+    pub fn search_updated(&mut self, key: &K) -> Option<&V> {
+        self.poison_on_unwind(|tree| tree.root_mut().push_down_path(key));
+        self.root.lookup(key)
+    }
+
+    /// Pushes every pending change made by [`BTree::update_range`] down to the values, in `O(n)`
+    /// time. Nodes that are [`Shared`](persistent::Shared) with snapshots are copied on the way.
+    pub fn apply_pending(&mut self) {
+        self.poison_on_unwind(|tree| tree.root_mut().apply_pending());
+    }
+}
+
+impl<K: Ord, V, A: Augment<K, V>, S: Sharing<K, V, A>> Default for BTree<K, V, A, S>
+where
+    S::Alloc: Default,
+{
+    fn default() -> Self {
+        Self::new_in(S::Alloc::default())
+    }
+}
+
+/// Copies the whole tree, unless its nodes are [`Shared`](persistent::Shared), in which case this
+/// takes constant time
+impl<K: Ord + Clone, V: Clone, A: Augment<K, V>, S: Sharing<K, V, A>> Clone for BTree<K, V, A, S>
+where
+    A::Value: Clone,
+{
+    fn clone(&self) -> Self {
+        self.check_poison();
+        Self {
+            root: ManuallyDrop::new(S::share(&self.root)),
+            len: self.len,
+            poisoned: false,
+        }
+    }
+}
+
+/// Builds the tree in `O(n log n)` time by sorting the pairs and bulk loading them. As with
+/// [`BTree::insert`], only the first pair with a given key is kept.
+impl<K: Ord, V, A: Augment<K, V>, S: Sharing<K, V, A>> FromIterator<(K, V)> for BTree<K, V, A, S>
+where
+    S::Alloc: Default,
+{
+    fn from_iter<I: IntoIterator<Item = (K, V)>>(iter: I) -> Self {
+        let mut pairs: Vec<_> = iter.into_iter().collect();
+        // The sort is stable, so the first occurrence of each key is the one that survives
+        pairs.sort_by(|(a, _), (b, _)| a.cmp(b));
+        pairs.dedup_by(|(a, _), (b, _)| a == b);
+        Self::from_sorted(pairs, true, S::Alloc::default())
+    }
+}
+
+impl<'a, K: Ord, V, A: Augment<K, V>, S: Sharing<K, V, A>> IntoIterator for &'a BTree<K, V, A, S> {
+    type Item = (&'a K, &'a V);
+    type IntoIter = Iter<'a, K, V, A, S>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
+
+/// A node along with the index of one of its pairs
+type Position<'a, K, V, A, S> = (&'a Node<K, V, A, S>, usize);
+
+/// An iterator over the pairs of a [`BTree`] in ascending key order, created by [`BTree::iter`]
+pub struct Iter<'a, K, V, A: Augment<K, V> = (), S: Sharing<K, V, A> = Unique> {
+    /// The nodes on the path to the next pair, along with the index of their next pair
+    stack: Vec<Position<'a, K, V, A, S>>,
+    remaining: usize,
+}
+
+impl<'a, K: Ord, V, A: Augment<K, V>, S: Sharing<K, V, A>> Iter<'a, K, V, A, S> {
+    fn push_leftmost(&mut self, mut node: &'a Node<K, V, A, S>) {
+        node.check_pending();
+        self.stack.push((node, 0));
+        while !node.is_leaf() {
+            node = &node.children()[0];
+            node.check_pending();
+            self.stack.push((node, 0));
+        }
+    }
+}
+
+impl<'a, K: Ord, V, A: Augment<K, V>, S: Sharing<K, V, A>> Iterator for Iter<'a, K, V, A, S> {
+    type Item = (&'a K, &'a V);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while let Some((node, idx)) = self.stack.last_mut() {
+            let node = *node;
+            if *idx < node.n {
+                let (key, value) = &node.pairs()[*idx];
+                *idx += 1;
+                if !node.is_leaf() {
+                    let next = &node.children()[*idx];
+                    self.push_leftmost(next);
+                }
+                self.remaining -= 1;
+                return Some((key, value));
+            }
+            self.stack.pop();
+        }
+        None
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.remaining, Some(self.remaining))
+    }
+}
+
+impl<K: Ord, V, A: Augment<K, V>, S: Sharing<K, V, A>> ExactSizeIterator for Iter<'_, K, V, A, S> {}
+
+/// An iterator over the pairs of a [`BTree`] with keys in a range, in ascending key order,
+/// created by [`BTree::range`]
+pub struct Range<'a, K, V, A: Augment<K, V> = (), S: Sharing<K, V, A> = Unique> {
+    iter: Iter<'a, K, V, A, S>,
+    /// The key of the first pair past the range
+    end: Option<&'a K>,
+}
+
+impl<'a, K: Ord, V, A: Augment<K, V>, S: Sharing<K, V, A>> Iterator for Range<'a, K, V, A, S> {
+    type Item = (&'a K, &'a V);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let (key, value) = self.iter.next()?;
+        if self.end.is_some_and(|end| ptr::eq(end, key)) {
+            self.iter.stack.clear();
+            return None;
+        }
+        Some((key, value))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (0, Some(self.iter.remaining))
+    }
+}
+
+/// An iterator over the pairs removed from a [`BTree`] in ascending key order, created by
+/// [`BTree::drain_range`] and [`BTree::extract_if`]
+pub struct Drain<K, V>(alloc::vec::IntoIter<(K, V)>);
+
+impl<K, V> Iterator for Drain<K, V> {
+    type Item = (K, V);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.0.next()
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.0.size_hint()
+    }
+}
+
+impl<K, V> DoubleEndedIterator for Drain<K, V> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        self.0.next_back()
+    }
+}
+
+impl<K, V> ExactSizeIterator for Drain<K, V> {}
+
+impl<K, V, A: Augment<K, V>, S: Sharing<K, V, A>> Drop for BTree<K, V, A, S> {
+    fn drop(&mut self) {
+        // A poisoned tree may hold pairs that have already been moved out or dropped, so leaking
+        // is the only safe option
+        if !self.poisoned {
+            unsafe { ManuallyDrop::drop(&mut self.root) };
         }
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::BTree;
+    use std::alloc::Layout;
+    use std::cell::{Cell, RefCell};
+    use std::cmp::Ordering;
+    use std::collections::{BTreeMap, HashSet};
+    use std::ops::{Bound, RangeBounds};
+    use std::panic::{self, AssertUnwindSafe};
+    use std::ptr::NonNull;
+    use std::rc::Rc;
+    use std::vec::Vec;
+
+    use allocator_api2::alloc::{AllocError, Allocator, Global};
+
+    use crate::augments::{HashAugment, SumAugment};
+    use crate::persistent::{PersistentBTree, Sharing, Unique};
+    use crate::{Augment, BTree, Node, TryInsertError, MIN_DEGREE};
+
+    /// Checks the structural invariants of the subtree and that every augment value agrees with
+    /// one computed from scratch. Returns the height of the subtree.
+    fn check_node<K: Ord, V, A, S>(node: &Node<K, V, A, S>, is_root: bool) -> usize
+    where
+        A: Augment<K, V>,
+        S: Sharing<K, V, A>,
+        A::Value: PartialEq + std::fmt::Debug,
+    {
+        assert!(node.n < 2 * MIN_DEGREE);
+        assert!(is_root || node.n >= MIN_DEGREE - 1);
+        assert!(node.pairs().windows(2).all(|w| w[0].0 < w[1].0));
+        assert_eq!(node.aug_val, node.computed_aug_val());
+
+        if node.is_leaf() {
+            return 0;
+        }
+
+        assert_eq!(node.children().len(), node.n + 1);
+        let height = check_node(&node.children()[0], false);
+        for (i, child) in node.children().iter().enumerate() {
+            assert_eq!(check_node(child, false), height);
+            if i > 0 {
+                assert!(child.pairs()[0].0 > node.pairs()[i - 1].0);
+            }
+            if i < node.n {
+                assert!(child.pairs()[child.n - 1].0 < node.pairs()[i].0);
+            }
+        }
+        height + 1
+    }
+
+    pub(crate) fn check_tree<K: Ord, V, A, S>(tree: &BTree<K, V, A, S>)
+    where
+        A: Augment<K, V>,
+        S: Sharing<K, V, A>,
+        A::Value: PartialEq + std::fmt::Debug,
+    {
+        check_node(&tree.root, true);
+        assert_eq!(tree.iter().count(), tree.len());
+    }
+
+    /// Deterministic xorshift generator, so failures are reproducible. Yields the upper half of
+    /// the state, which fits in any `T` that can hold a `u32`.
+    pub(crate) fn rng<T: TryFrom<u32>>(mut state: u64) -> impl FnMut() -> T {
+        move || {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            T::try_from((state >> 32) as u32).unwrap_or_else(|_| unreachable!())
+        }
+    }
 
     fn setup_tree_set() -> BTree<i32, (), ()> {
         let mut tree = BTree::new();
@@ -563,4 +2148,585 @@ mod tests {
             assert_eq!(tree.search(&i), Some(&(i * 2)));
         }
     }
+
+    thread_local! {
+        /// The hook to panic in, and how many calls to it to let through first
+        pub(crate) static PANIC_AT: Cell<Option<(&'static str, usize)>> = const { Cell::new(None) };
+        pub(crate) static LIVE: RefCell<HashSet<u64>> = RefCell::new(HashSet::new());
+        static NEXT_ID: Cell<u64> = const { Cell::new(0) };
+        pub(crate) static DOUBLE_DROPS: Cell<usize> = const { Cell::new(0) };
+    }
+
+    pub(crate) fn maybe_panic(hook: &'static str) {
+        PANIC_AT.with(|at| match at.get() {
+            Some((h, 0)) if h == hook => {
+                at.set(None);
+                panic!("injected panic in `{hook}`");
+            }
+            Some((h, n)) if h == hook => at.set(Some((h, n - 1))),
+            _ => {}
+        });
+    }
+
+    /// Records its drops and can be made to panic when compared
+    pub(crate) struct Tracked {
+        key: u32,
+        id: u64,
+    }
+
+    impl Tracked {
+        pub(crate) fn new(key: u32) -> Self {
+            let id = NEXT_ID.with(|next| next.replace(next.get() + 1));
+            LIVE.with(|live| live.borrow_mut().insert(id));
+            Self { key, id }
+        }
+    }
+
+    impl Drop for Tracked {
+        fn drop(&mut self) {
+            if !LIVE.with(|live| live.borrow_mut().remove(&self.id)) {
+                DOUBLE_DROPS.with(|d| d.set(d.get() + 1));
+            }
+        }
+    }
+
+    impl PartialEq for Tracked {
+        fn eq(&self, other: &Self) -> bool {
+            self.cmp(other) == Ordering::Equal
+        }
+    }
+
+    impl Eq for Tracked {}
+
+    impl PartialOrd for Tracked {
+        fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+            Some(self.cmp(other))
+        }
+    }
+
+    impl Ord for Tracked {
+        fn cmp(&self, other: &Self) -> Ordering {
+            maybe_panic("cmp");
+            self.key.cmp(&other.key)
+        }
+    }
+
+    /// Counts the pairs below a point, and can be made to panic in any hook
+    pub(crate) struct PanickyAugment;
+
+    impl<K, V> Augment<K, V> for PanickyAugment {
+        type Value = usize;
+        type Output = usize;
+
+        fn initial_value() -> Self::Value {
+            maybe_panic("initial_value");
+            0
+        }
+
+        fn initial_output() -> Self::Output {
+            maybe_panic("initial_output");
+            0
+        }
+
+        fn inserted_sub_tree(_: &K, _: &V, old: &Self::Value) -> Self::Value {
+            maybe_panic("inserted_sub_tree");
+            old + 1
+        }
+
+        fn deleted_sub_tree(_: &K, _: &V, old: &Self::Value) -> Self::Value {
+            maybe_panic("deleted_sub_tree");
+            old - 1
+        }
+
+        fn split<'a>(
+            left_keys: &[(K, V)],
+            _: &[(K, V)],
+            _: &(K, V),
+            left_children: impl Iterator<Item = &'a Self::Value>,
+            _: impl Iterator<Item = &'a Self::Value>,
+            old: &Self::Value,
+        ) -> (Self::Value, Self::Value) {
+            maybe_panic("split");
+            let left = left_keys.len() + left_children.sum::<usize>();
+            (left, old - left - 1)
+        }
+
+        fn split_root(_: &(K, V), left: &Self::Value, right: &Self::Value) -> Self::Value {
+            maybe_panic("split_root");
+            left + right + 1
+        }
+
+        fn merge(_: &(K, V), left: &Self::Value, right: &Self::Value) -> Self::Value {
+            maybe_panic("merge");
+            left + right + 1
+        }
+
+        fn steal(
+            _: &(K, V),
+            _: &(K, V),
+            stolen_child: Option<&Self::Value>,
+            thief: &Self::Value,
+            victim: &Self::Value,
+        ) -> (Self::Value, Self::Value) {
+            maybe_panic("steal");
+            let moved = 1 + stolen_child.copied().unwrap_or(0);
+            (thief + moved, victim - moved)
+        }
+
+        fn visit<'a>(
+            found: bool,
+            idx: usize,
+            _: &[(K, V)],
+            children: impl Iterator<Item = &'a Self::Value>,
+            _: &Self::Value,
+            acc: Self::Output,
+        ) -> Self::Output {
+            maybe_panic("visit");
+            let num_keys = idx + found as usize;
+            acc + num_keys + children.take(num_keys).sum::<usize>()
+        }
+    }
+
+    fn panicky_workload(tree: &mut Option<BTree<Tracked, Tracked, PanickyAugment>>) {
+        let tree = tree.insert(BTree::with_augment());
+        for i in 0..300 {
+            tree.insert(Tracked::new(i), Tracked::new(i));
+        }
+        for i in (0..300).step_by(3) {
+            tree.delete(&Tracked::new(i));
+        }
+        for i in 0..300 {
+            tree.search(&Tracked::new(i));
+        }
+        assert_eq!(tree.augment_search(&Tracked::new(300)), 200);
+    }
+
+    /// Builds a tree before arming the injected panic, so that it hits the range operations or the
+    /// predicates given to them
+    fn panicky_range_workload(tree: &mut Option<BTree<Tracked, Tracked, PanickyAugment>>) {
+        let armed = PANIC_AT.with(Cell::take);
+        let tree = tree.insert(BTree::with_augment());
+        for i in 0..300 {
+            tree.insert(Tracked::new(i), Tracked::new(i));
+        }
+        PANIC_AT.with(|at| at.set(armed));
+
+        assert_eq!(tree.remove_range(Tracked::new(20)..Tracked::new(60)), 40);
+        assert_eq!(
+            tree.drain_range(Tracked::new(100)..Tracked::new(120))
+                .count(),
+            20
+        );
+        tree.retain(|key, _| {
+            maybe_panic("pred");
+            key.key % 5 != 0
+        });
+        let extracted = tree.extract_if(Tracked::new(150).., |key, _| {
+            maybe_panic("pred");
+            key.key % 2 == 0
+        });
+        assert_eq!(extracted.count(), 60);
+        for i in 0..300 {
+            tree.search(&Tracked::new(i));
+        }
+        assert_eq!(tree.augment_search(&Tracked::new(300)), 132);
+    }
+
+    #[test]
+    fn dropping_tree_drops_all_pairs() {
+        let mut tree = None;
+        panicky_workload(&mut tree);
+        drop(tree);
+
+        assert!(LIVE.with(|live| live.borrow().is_empty()));
+        assert_eq!(DOUBLE_DROPS.with(Cell::get), 0);
+    }
+
+    #[test]
+    fn panicking_hooks_poison_without_double_drops() {
+        let hooks = [
+            "cmp",
+            "initial_value",
+            "initial_output",
+            "inserted_sub_tree",
+            "deleted_sub_tree",
+            "split",
+            "split_root",
+            "merge",
+            "steal",
+            "visit",
+            "pred",
+        ];
+
+        let workloads: [(bool, fn(&mut _)); 2] =
+            [(false, panicky_workload), (true, panicky_range_workload)];
+        for hook in hooks {
+            let mut triggered_any = false;
+
+            for ((ranged, workload), countdown) in workloads
+                .into_iter()
+                .flat_map(|w| [0, 1, 7, 40, 150].map(|countdown| (w, countdown)))
+            {
+                LIVE.with(|live| live.borrow_mut().clear());
+                PANIC_AT.with(|at| at.set(Some((hook, countdown))));
+
+                let mut tree = None;
+                let res = panic::catch_unwind(AssertUnwindSafe(|| workload(&mut tree)));
+                let triggered = PANIC_AT.with(Cell::take).is_none();
+                assert_eq!(res.is_err(), triggered, "hook `{hook}`");
+                triggered_any |= triggered;
+
+                if let Some(mut tree) = tree.filter(|_| triggered) {
+                    // Lookups never modify the tree, so they must not poison it
+                    let read_only = ["initial_output", "visit"].contains(&hook);
+                    // Range operations compare their bounds before modifying the tree
+                    if !(ranged && hook == "cmp") {
+                        assert_eq!(tree.is_poisoned(), !read_only, "hook `{hook}`");
+                    }
+
+                    if tree.is_poisoned() {
+                        let search = panic::catch_unwind(AssertUnwindSafe(|| {
+                            tree.search(&Tracked::new(0));
+                        }));
+                        assert!(search.is_err());
+                        let insert = panic::catch_unwind(AssertUnwindSafe(|| {
+                            tree.insert(Tracked::new(1000), Tracked::new(1000));
+                        }));
+                        assert!(insert.is_err());
+                        let remove_nothing = panic::catch_unwind(AssertUnwindSafe(|| {
+                            tree.remove_range(Tracked::new(5)..Tracked::new(5));
+                        }));
+                        assert!(remove_nothing.is_err());
+                    } else {
+                        assert!(tree.insert(Tracked::new(1000), Tracked::new(1000)));
+                    }
+                }
+
+                assert_eq!(DOUBLE_DROPS.with(Cell::get), 0, "hook `{hook}`");
+            }
+
+            assert!(triggered_any, "hook `{hook}` was never called");
+        }
+    }
+
+    #[test]
+    fn iter_yields_pairs_in_order() {
+        let mut tree = setup_tree_set();
+        for i in (0..4000).step_by(7) {
+            tree.delete(&i);
+        }
+
+        let expected: Vec<_> = (0..4000).filter(|i| i % 7 != 0).collect();
+        assert_eq!(tree.len(), expected.len());
+        assert_eq!(tree.iter().len(), expected.len());
+        assert!(tree.iter().map(|(k, _)| *k).eq(expected));
+        check_tree(&tree);
+    }
+
+    #[test]
+    fn from_iter_bulk_loads_valid_tree() {
+        let sizes = [0, 1, 10, 11, 12, 100, 143, 144, 145, 1727, 1728, 5000];
+        for len in sizes {
+            let tree: BTree<_, _, SumAugment> = (0..len).rev().map(|i| (i, i)).collect();
+
+            check_tree(&tree);
+            assert_eq!(tree.len(), len as usize);
+            assert!(tree.iter().map(|(k, _)| *k).eq(0..len));
+            assert_eq!(tree.augment_search(&(len / 2)), (0..=len / 2).sum::<i32>());
+        }
+    }
+
+    #[test]
+    fn from_iter_keeps_first_duplicate() {
+        let tree: BTree<_, _> = [(3, 'a'), (1, 'b'), (3, 'c'), (2, 'd'), (1, 'e')]
+            .into_iter()
+            .collect();
+
+        assert_eq!(tree.len(), 3);
+        assert!(tree.iter().eq([(&1, &'b'), (&2, &'d'), (&3, &'a')]));
+    }
+
+    #[test]
+    fn bulk_loaded_tree_supports_updates() {
+        let mut tree: BTree<_, _, SumAugment> = (0..2000).map(|i| (2 * i, 1)).collect();
+
+        for i in 0..2000 {
+            assert!(tree.insert(2 * i + 1, 1));
+        }
+        for i in (0..4000).step_by(3) {
+            assert_eq!(tree.delete(&i), Some(1));
+        }
+
+        check_tree(&tree);
+        assert_eq!(tree.augment_search(&4000), 4000 - 1334);
+    }
+
+    #[test]
+    fn range_matches_std() {
+        let tree: BTree<u32, u32> = (0..3000).map(|i| (3 * i, i)).collect();
+        let model: BTreeMap<u32, u32> = tree.iter().map(|(&k, &v)| (k, v)).collect();
+
+        let bounds = |i: u32| match i % 3 {
+            0 => Bound::Included(i * 7 % 9100),
+            1 => Bound::Excluded(i * 7 % 9100),
+            _ => Bound::Unbounded,
+        };
+        for i in 0..300 {
+            let (start, end) = (bounds(i), bounds(i * 13 + 1));
+            let is_empty = match (start, end) {
+                (Bound::Included(s), Bound::Included(e)) => s > e,
+                (
+                    Bound::Included(s) | Bound::Excluded(s),
+                    Bound::Included(e) | Bound::Excluded(e),
+                ) => s >= e,
+                _ => false,
+            };
+            if is_empty {
+                assert_eq!(tree.range((start, end)).next(), None);
+            } else {
+                assert!(tree.range((start, end)).eq(model.range((start, end))));
+            }
+        }
+    }
+
+    #[test]
+    fn augment_range_matches_model() {
+        let mut tree = BTree::with_augment::<SumAugment>();
+        for i in 0..3000 {
+            tree.insert(i * 7 % 3001, i64::from(i));
+        }
+        for i in (0..3000).step_by(4) {
+            tree.delete(&i);
+        }
+        let model: Vec<_> = tree.iter().map(|(&k, &v)| (k, v)).collect();
+
+        let bounds = |i: u32| match i % 3 {
+            0 => Bound::Included(i * 11 % 3100),
+            1 => Bound::Excluded(i * 11 % 3100),
+            _ => Bound::Unbounded,
+        };
+        for i in 0..300 {
+            let range = (bounds(i), bounds(i * 17 + 2));
+            let sum: i64 = model
+                .iter()
+                .filter(|(k, _)| range.contains(k))
+                .map(|(_, v)| v)
+                .sum();
+            assert_eq!(tree.augment_range(range), sum);
+        }
+    }
+
+    #[test]
+    fn pop_first_and_last_match_std() {
+        let mut tree: PersistentBTree<u32, u32, HashAugment> = (0..2000).map(|i| (i, i)).collect();
+        let snapshot = tree.snapshot();
+        let mut model: BTreeMap<_, _> = (0..2000).map(|i| (i, i)).collect();
+        for i in 0..2100 {
+            assert_eq!(tree.first_key_value(), model.first_key_value());
+            assert_eq!(tree.last_key_value(), model.last_key_value());
+            if i % 3 == 0 {
+                assert_eq!(tree.pop_last(), model.pop_last());
+            } else {
+                assert_eq!(tree.pop_first(), model.pop_first());
+            }
+            if i % 100 == 0 {
+                check_tree(&tree);
+            }
+        }
+        assert!(tree.is_empty());
+        check_tree(&tree);
+        assert_eq!(snapshot.len(), 2000);
+        check_tree(&snapshot);
+    }
+
+    #[test]
+    fn neighbour_queries_match_std() {
+        let model: BTreeMap<u32, u32> = (0..3000).map(|i| (i * 7 % 3001 * 3, i)).collect();
+        let tree: BTree<u32, u32> = model.iter().map(|(&k, &v)| (k, v)).collect();
+
+        for key in 0..9100 {
+            assert_eq!(tree.lower_bound(&key), model.range(..=key).next_back());
+            assert_eq!(tree.upper_bound(&key), model.range(key..).next());
+            assert_eq!(tree.predecessor(&key), model.range(..key).next_back());
+            let after = (Bound::Excluded(key), Bound::Unbounded);
+            assert_eq!(tree.successor(&key), model.range(after).next());
+
+            let nearest = model.iter().min_by_key(|(&k, _)| k.abs_diff(key)).unwrap();
+            assert_eq!(tree.nearest(&key), Some(nearest));
+        }
+        assert_eq!(BTree::<u32, u32>::new().nearest(&5), None);
+    }
+
+    #[test]
+    fn remove_range_matches_model() {
+        let mut tree = BTree::with_augment::<SumAugment>();
+        let mut hashed = BTree::with_augment::<HashAugment>();
+        let mut model = BTreeMap::new();
+
+        let mut next = rng::<u32>(0x7e1e_7e5a);
+        let bound = |next: &mut dyn FnMut() -> u32| match next() % 8 {
+            0 => Bound::Unbounded,
+            1..=3 => Bound::Excluded(next() % 5000),
+            _ => Bound::Included(next() % 5000),
+        };
+        for step in 0..150 {
+            for _ in 0..next() % 400 {
+                let key = next() % 5000;
+                tree.insert(key, i64::from(key));
+                hashed.insert(key, key);
+                model.insert(key, i64::from(key));
+            }
+
+            let range = (bound(&mut next), bound(&mut next));
+            let expected: Vec<_> = model
+                .iter()
+                .filter(|(k, _)| range.contains(k))
+                .map(|(&k, &v)| (k, v))
+                .collect();
+            model.retain(|k, _| !range.contains(k));
+            if step % 2 == 0 {
+                assert_eq!(tree.remove_range(range), expected.len());
+            } else {
+                assert!(tree.drain_range(range).eq(expected.iter().copied()));
+            }
+            assert_eq!(hashed.remove_range(range), expected.len());
+
+            check_tree(&tree);
+            check_tree(&hashed);
+            assert!(tree.iter().map(|(&k, &v)| (k, v)).eq(model.clone()));
+            assert_eq!(tree.augment_search(&u32::MAX), model.values().sum::<i64>());
+        }
+    }
+
+    #[test]
+    fn retain_and_extract_if_match_model() {
+        let mut tree = BTree::with_augment::<SumAugment>();
+        let mut hashed = BTree::with_augment::<HashAugment>();
+        let mut model = BTreeMap::new();
+        for i in 0..6000 {
+            let key = i * 7919 % 6007;
+            tree.insert(key, i64::from(i));
+            hashed.insert(key, i);
+            model.insert(key, i64::from(i));
+        }
+
+        tree.retain(|&k, v| {
+            *v += 1;
+            k % 3 != 0
+        });
+        hashed.retain(|&k, v| {
+            *v += 1;
+            k % 3 != 0
+        });
+        model.retain(|&k, v| {
+            *v += 1;
+            k % 3 != 0
+        });
+        check_tree(&tree);
+        check_tree(&hashed);
+        assert!(tree.iter().map(|(&k, &v)| (k, v)).eq(model.clone()));
+
+        for i in 0..30 {
+            let (start, end) = (i * 397 % 6000, i * 397 % 6000 + i * 31);
+            let extracted: Vec<_> = tree
+                .extract_if(start..end, |&k, v| (k + i) % 4 == 0 || *v % 5 == 0)
+                .collect();
+            assert_eq!(
+                hashed
+                    .extract_if(start..end, |&k, v| (k + i) % 4 == 0 || *v % 5 == 0)
+                    .len(),
+                extracted.len()
+            );
+
+            let expected: Vec<_> = model
+                .range(start..end)
+                .filter(|&(&k, &v)| (k + i) % 4 == 0 || v % 5 == 0)
+                .map(|(&k, &v)| (k, v))
+                .collect();
+            for (key, _) in &expected {
+                model.remove(key);
+            }
+            assert_eq!(extracted, expected);
+
+            check_tree(&tree);
+            check_tree(&hashed);
+            assert_eq!(tree.augment_search(&u32::MAX), model.values().sum::<i64>());
+        }
+        assert!(tree.iter().map(|(&k, &v)| (k, v)).eq(model));
+
+        tree.retain(|_, _| false);
+        assert!(tree.is_empty());
+        check_tree(&tree);
+    }
+
+    #[test]
+    fn remove_range_keeps_snapshots_intact() {
+        let mut tree: PersistentBTree<u32, u32> = (0..10_000).map(|i| (i, i)).collect();
+        let snapshot = tree.snapshot();
+        assert_eq!(tree.remove_range(1000..9000), 8000);
+
+        check_tree(&tree);
+        check_tree(&snapshot);
+        assert!(tree
+            .iter()
+            .map(|(&k, _)| k)
+            .eq((0..1000).chain(9000..10_000)));
+        assert!(snapshot.iter().map(|(&k, _)| k).eq(0..10_000));
+        assert_eq!(tree.drain_range(..).len(), 2000);
+        assert!(tree.is_empty());
+        check_tree(&tree);
+    }
+
+    /// Allocates with the global allocator as long as the shared budget of allocations lasts
+    #[derive(Clone)]
+    struct Capped(Rc<Cell<usize>>);
+
+    unsafe impl Allocator for Capped {
+        fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+            let budget = self.0.get().checked_sub(1).ok_or(AllocError)?;
+            self.0.set(budget);
+            Global.allocate(layout)
+        }
+
+        unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
+            Global.deallocate(ptr, layout)
+        }
+    }
+
+    #[test]
+    fn try_insert_hands_back_pair_when_allocation_fails() {
+        let budget = Rc::new(Cell::new(1));
+        let mut tree: BTree<u32, i64, SumAugment, Unique<Capped>> =
+            BTree::new_in(Capped(budget.clone()));
+        let mut model = BTreeMap::new();
+
+        let mut step = 0;
+        for round in 1..=20 {
+            budget.set(round * 3);
+            let failed = loop {
+                step += 1;
+                // Revisit some keys to exercise duplicates as well
+                let key = (step * 7919 % 100_003) / 2;
+                let value = i64::from(key % 97);
+                match tree.try_insert(key, value) {
+                    Ok(inserted) => assert_eq!(inserted, model.insert(key, value).is_none()),
+                    Err(err) => break err,
+                }
+            };
+            let key = (step * 7919 % 100_003) / 2;
+            let value = i64::from(key % 97);
+            assert_eq!(failed, TryInsertError { key, value });
+            assert_eq!(budget.get(), 0);
+
+            check_tree(&tree);
+            assert_eq!(tree.len(), model.len());
+            assert!(tree.iter().map(|(&k, &v)| (k, v)).eq(model.clone()));
+            assert_eq!(tree.augment_search(&u32::MAX), model.values().sum::<i64>());
+        }
+
+        budget.set(usize::MAX);
+        assert_eq!(tree.try_insert(100_000, 1), Ok(true));
+        assert!(!tree.insert(100_000, 2));
+        check_tree(&tree);
+    }
 }
@@ -0,0 +1,220 @@
+//! A map that keeps every value inserted under a key
+//!
+//! [`BTreeMultiMap`] stores every value as a pair of its own in a [`BTree`], keyed by its key along
+//! with a sequence number that grows with every insert. The values of a key therefore stay in
+//! insertion order, and the augment of the map folds over every value rather than one per key.
+//! A [`CountAugment`] runs alongside it to count the values of a key in `O(log n)` time.
+//!
+//! ```
+//! use b_tree::augments::SumAugment;
+//! use b_tree::multimap::BTreeMultiMap;
+//!
+//! let mut log = BTreeMultiMap::with_augment::<SumAugment>();
+//! log.insert(1000, 5);
+//! log.insert(1000, 7);
+//! log.insert(2000, 1);
+//!
+//! assert!(log.get_all(&1000).eq(&[5, 7]));
+//! assert_eq!(log.count(&1000), 2);
+//! assert_eq!(log.augment_search(&1000), 12);
+//! ```
+
+use alloc::vec::Vec;
+
+use crate::augments::CountAugment;
+use crate::{Augment, BTree, Iter, Range};
+
+/// A map from keys to lists of values, see the [module documentation](self)
+///
+/// The augment `A` sees every value as a pair keyed by the key of the value and its sequence
+/// number.
+pub struct BTreeMultiMap<K, V, A = ()>
+where
+    A: Augment<(K, u64), V>,
+    A::Value: 'static,
+{
+    tree: BTree<(K, u64), V, (CountAugment, A)>,
+    /// The sequence number of the next value. Starts at 1, so `(key, 0)` comes before every value
+    /// of `key`.
+    next_seq: u64,
+}
+
+impl<K: Ord + Clone, V> BTreeMultiMap<K, V> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_augment<T: Augment<(K, u64), V>>() -> BTreeMultiMap<K, V, T>
+    where
+        T::Value: 'static,
+    {
+        BTreeMultiMap::default()
+    }
+}
+
+impl<K: Ord + Clone, V, A: Augment<(K, u64), V>> BTreeMultiMap<K, V, A>
+where
+    A::Value: 'static,
+{
+    /// Adds `value` after the values already stored under `key`
+    pub fn insert(&mut self, key: K, value: V) {
+        self.tree.insert((key, self.next_seq), value);
+        self.next_seq += 1;
+    }
+
+    /// Returns an iterator over the values of `key` in insertion order
+    pub fn get_all(&self, key: &K) -> Values<'_, K, V, A> {
+        Values(self.tree.range((key.clone(), 0)..=(key.clone(), u64::MAX)))
+    }
+
+    /// Removes the value of `key` that was inserted first
+    pub fn remove_one(&mut self, key: &K) -> Option<V> {
+        let (first, _) = self.get_all(key).0.next()?;
+        let first = first.clone();
+        self.tree.delete(&first)
+    }
+
+    /// Removes every value of `key`, returning them in insertion order
+    pub fn remove_all(&mut self, key: &K) -> Vec<V> {
+        self.tree
+            .drain_range((key.clone(), 0)..=(key.clone(), u64::MAX))
+            .map(|(_, value)| value)
+            .collect()
+    }
+
+    /// Returns the number of values of `key`
+    pub fn count(&self, key: &K) -> usize {
+        let (up_to, _) = self.tree.augment_search(&(key.clone(), u64::MAX));
+        let (below, _) = self.tree.augment_search(&(key.clone(), 0));
+        up_to - below
+    }
+
+    /// Returns the output of the augment for all values with keys smaller than or equal to `key`
+    pub fn augment_search(&self, key: &K) -> A::Output {
+        self.tree.augment_search(&(key.clone(), u64::MAX)).1
+    }
+
+    /// Returns the number of values in the map
+    pub fn len(&self) -> usize {
+        self.tree.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tree.is_empty()
+    }
+
+    /// Returns an iterator over the keys and values of the map, in ascending key order and with
+    /// the values of a key in insertion order
+    pub fn iter(&self) -> MultiIter<'_, K, V, A> {
+        MultiIter(self.tree.iter())
+    }
+}
+
+impl<K: Ord + Clone, V, A: Augment<(K, u64), V>> Default for BTreeMultiMap<K, V, A>
+where
+    A::Value: 'static,
+{
+    fn default() -> Self {
+        Self {
+            tree: BTree::default(),
+            next_seq: 1,
+        }
+    }
+}
+
+/// An iterator over the values of a key of a [`BTreeMultiMap`], created by
+/// [`BTreeMultiMap::get_all`]
+pub struct Values<'a, K, V, A>(Range<'a, (K, u64), V, (CountAugment, A)>)
+where
+    A: Augment<(K, u64), V>,
+    A::Value: 'static;
+
+impl<'a, K: Ord, V, A: Augment<(K, u64), V>> Iterator for Values<'a, K, V, A>
+where
+    A::Value: 'static,
+{
+    type Item = &'a V;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.0.next().map(|(_, value)| value)
+    }
+}
+
+/// An iterator over the keys and values of a [`BTreeMultiMap`], created by
+/// [`BTreeMultiMap::iter`]
+pub struct MultiIter<'a, K, V, A>(Iter<'a, (K, u64), V, (CountAugment, A)>)
+where
+    A: Augment<(K, u64), V>,
+    A::Value: 'static;
+
+impl<'a, K: Ord, V, A: Augment<(K, u64), V>> Iterator for MultiIter<'a, K, V, A>
+where
+    A::Value: 'static,
+{
+    type Item = (&'a K, &'a V);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.0.next().map(|((key, _), value)| (key, value))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.0.size_hint()
+    }
+}
+
+impl<K: Ord, V, A: Augment<(K, u64), V>> ExactSizeIterator for MultiIter<'_, K, V, A> where
+    A::Value: 'static
+{
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::{BTreeMap, VecDeque};
+    use std::vec::Vec;
+
+    use crate::augments::SumAugment;
+    use crate::multimap::BTreeMultiMap;
+    use crate::tests::rng;
+
+    #[test]
+    fn random_operations_match_model() {
+        let mut map = BTreeMultiMap::with_augment::<SumAugment>();
+        let mut model: BTreeMap<u32, VecDeque<i64>> = BTreeMap::new();
+
+        let mut next = rng::<u32>(0x0bad_cafe);
+        for step in 0..5000 {
+            let key = next() % 100;
+            match next() % 10 {
+                0 => {
+                    let removed = model.remove(&key).unwrap_or_default();
+                    assert_eq!(map.remove_all(&key), Vec::from(removed));
+                }
+                1..=3 => {
+                    let removed = model.get_mut(&key).and_then(VecDeque::pop_front);
+                    assert_eq!(map.remove_one(&key), removed);
+                }
+                _ => {
+                    let value = i64::from(next() % 1000);
+                    map.insert(key, value);
+                    model.entry(key).or_default().push_back(value);
+                }
+            }
+
+            if step % 250 == 0 {
+                let len: usize = model.values().map(VecDeque::len).sum();
+                assert_eq!(map.len(), len);
+                assert!(map.iter().eq(model
+                    .iter()
+                    .flat_map(|(key, values)| values.iter().map(move |v| (key, v)))));
+                for key in 0..100 {
+                    let values = model.get(&key).cloned().unwrap_or_default();
+                    assert!(map.get_all(&key).eq(&values));
+                    assert_eq!(map.count(&key), values.len());
+
+                    let sum: i64 = model.range(..=key).flat_map(|(_, v)| v).sum();
+                    assert_eq!(map.augment_search(&key), sum);
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,1002 @@
+//! A B-tree whose nodes live in fixed-size pages of a file instead of in memory
+//!
+//! [`PagedBTree`] runs the same algorithm as [`BTree`](crate::BTree), calling the [`Augment`]
+//! hooks in the same way, but a node refers to its children by page number, and only the nodes in
+//! a small LRU buffer pool are kept in memory. Modified nodes are written back to the file when
+//! they are evicted from the pool, or when the tree is [flushed](PagedBTree::flush). Keys, values
+//! and augment values are stored using their [`Encode`] and [`Decode`] implementations, and a node
+//! must fit in a single page of [`PAGE_SIZE`] bytes.
+//!
+//! Page 0 of the file is a header holding the root page and the number of pairs. Pages that are
+//! no longer in use, because their node was merged into a sibling, are kept in a free list and
+//! reused by later splits.
+//!
+//! # Write-ahead log
+//! A tree created with [`PagedBTree::create_with_wal`] or opened with
+//! [`PagedBTree::open_with_wal`] survives crashes. Every insert and delete is recorded in a log
+//! file before it is applied, and the log is synced once every `sync_every` operations, so a
+//! crash loses at most the operations logged since the last sync. [`PagedBTree::sync`] forces a
+//! sync.
+//!
+//! In this mode modified pages are never written to the file one at a time. Instead, once the
+//! buffer pool fills up with modified pages, or when the tree is flushed, a checkpoint logs the
+//! contents of every modified page, syncs the log, and only then writes the pages to the file and
+//! empties the log. Recovery on open writes the pages of a logged checkpoint again, in case the
+//! crash interrupted it, and then replays the operations logged after it, which rebuilds every
+//! augment value through the usual [`Augment`] hooks.
+
+use std::cmp::Ordering;
+use std::fs::File;
+use std::io::{self, SeekFrom};
+use std::mem;
+
+use crate::snapshot::{Decode, Encode};
+use crate::{Augment, MIN_DEGREE};
+
+mod pool;
+mod wal;
+
+use pool::{invalid_data, BufferPool, PageId, PageNode};
+pub use pool::{PageFile, PAGE_SIZE};
+use wal::{Record, Wal};
+
+const MAGIC: [u8; 8] = *b"BTREEPGD";
+const VERSION: u32 = 1;
+
+/// The largest encoded size of a key and value together that [`PagedBTree::insert`] accepts. This
+/// leaves room in every page for the child pointers and the augment value of a full node.
+pub const MAX_PAIR_SIZE: usize = (PAGE_SIZE - 512) / (2 * MIN_DEGREE - 1) - 8;
+
+/// A B-tree stored in pages of a [`PageFile`]. See the [module documentation](self) for details.
+///
+/// Without a write-ahead log, changes are only guaranteed to be in the file once
+/// [`PagedBTree::flush`] or [`PagedBTree::close`] returns, and dropping the tree without calling
+/// either can leave the file inconsistent. If an I/O error or a panic interrupts a modifying
+/// operation, the tree is poisoned like [`BTree`](crate::BTree#panic-safety) is: every later
+/// operation fails, and the tree refuses to flush, so the file is not made any more inconsistent.
+pub struct PagedBTree<K, V, A: Augment<K, V> = (), F = File> {
+    pool: BufferPool<K, V, A, F>,
+    wal: Option<Wal<F>>,
+    root: PageId,
+    len: u64,
+    poisoned: bool,
+}
+
+impl<K, V, A, F> PagedBTree<K, V, A, F>
+where
+    K: Ord + Encode + Decode,
+    V: Encode + Decode,
+    A: Augment<K, V>,
+    A::Value: Encode + Decode,
+    F: PageFile,
+{
+    /// Creates an empty tree in `file`, overwriting whatever it contains. At most `cache_pages`
+    /// nodes are kept in memory at a time.
+    pub fn create(file: F, cache_pages: usize) -> io::Result<Self> {
+        let mut tree = Self {
+            pool: BufferPool::new(file, cache_pages, 1, 0),
+            wal: None,
+            root: 0,
+            len: 0,
+            poisoned: false,
+        };
+        tree.root = tree.pool.alloc(PageNode::new_leaf())?;
+        tree.flush()?;
+        Ok(tree)
+    }
+
+    /// Opens a tree previously created in `file`. At most `cache_pages` nodes are kept in memory
+    /// at a time.
+    pub fn open(file: F, cache_pages: usize) -> io::Result<Self> {
+        let mut pool = BufferPool::new(file, cache_pages, 0, 0);
+        let header = pool.read_raw(0)?;
+
+        let u32_at = |i: usize| u32::from_le_bytes(header[i..i + 4].try_into().unwrap());
+        let u64_at = |i: usize| u64::from_le_bytes(header[i..i + 8].try_into().unwrap());
+        if header[..8] != MAGIC {
+            return Err(invalid_data("file is not a paged BTree"));
+        }
+        if u32_at(8) != VERSION {
+            return Err(invalid_data("unsupported paged BTree version"));
+        }
+        if u32_at(12) as usize != PAGE_SIZE || u32_at(16) as usize != MIN_DEGREE {
+            return Err(invalid_data(
+                "paged BTree has a different page size or degree",
+            ));
+        }
+        let (root, len) = (u64_at(20), u64_at(28));
+        (pool.page_count, pool.free_head) = (u64_at(36), u64_at(44));
+
+        Ok(Self {
+            pool,
+            wal: None,
+            root,
+            len,
+            poisoned: false,
+        })
+    }
+
+    /// Like [`PagedBTree::create`], but logs every operation to `log`, syncing it every
+    /// `sync_every` operations. See the [module documentation](self#write-ahead-log).
+    pub fn create_with_wal(
+        file: F,
+        log: F,
+        cache_pages: usize,
+        sync_every: usize,
+    ) -> io::Result<Self> {
+        let mut tree = Self::create(file, cache_pages)?;
+        // Anything already in the log belongs to whatever was in the file before
+        let (mut wal, _) = Wal::open::<K, V>(log, sync_every)?;
+        wal.reset()?;
+
+        tree.pool.no_steal = true;
+        tree.wal = Some(wal);
+        Ok(tree)
+    }
+
+    /// Like [`PagedBTree::open`], but recovers from a crash using `log` first, and logs every
+    /// operation to it from then on, syncing it every `sync_every` operations. `log` may also be
+    /// empty, for a tree that was not using a log before. See the
+    /// [module documentation](self#write-ahead-log).
+    pub fn open_with_wal(
+        mut file: F,
+        log: F,
+        cache_pages: usize,
+        sync_every: usize,
+    ) -> io::Result<Self> {
+        let (wal, mut records) = Wal::open::<K, V>(log, sync_every)?;
+
+        // If a checkpoint was logged, the crash may have happened while its pages were being
+        // written, so write them all again. The operations before it are part of those pages.
+        let checkpoint = records
+            .iter()
+            .rposition(|r| matches!(r, Record::Checkpoint));
+        if let Some(end) = checkpoint {
+            for record in records.drain(..=end) {
+                if let Record::Page(id, page) = record {
+                    file.seek(SeekFrom::Start(id * PAGE_SIZE as u64))?;
+                    file.write_all(&page)?;
+                }
+            }
+            file.sync()?;
+        }
+
+        let mut tree = Self::open(file, cache_pages)?;
+        tree.pool.no_steal = true;
+        for record in records {
+            match record {
+                Record::Insert(key, value) => {
+                    tree.insert_unlogged(key, value)?;
+                }
+                Record::Delete(key) => {
+                    tree.delete_unlogged(&key)?;
+                }
+                // Part of a checkpoint that was cut short, so the file was never touched
+                Record::Page(..) | Record::Checkpoint => {}
+            }
+        }
+
+        tree.wal = Some(wal);
+        tree.checkpoint()?;
+        Ok(tree)
+    }
+
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn is_poisoned(&self) -> bool {
+        self.poisoned
+    }
+
+    /// Inserts the pair, unless the key is already present. Returns whether the pair was inserted.
+    pub fn insert(&mut self, key: K, value: V) -> io::Result<bool> {
+        let mut buf = Vec::new();
+        key.encode(&mut buf);
+        value.encode(&mut buf);
+        if buf.len() > MAX_PAIR_SIZE {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "pair is too large to store in a page",
+            ));
+        }
+
+        self.poison_on_failure(|tree| {
+            if let Some(wal) = &mut tree.wal {
+                wal.log_insert(&key, &value)?;
+            }
+            let inserted = tree.insert_unlogged(key, value)?;
+            tree.checkpoint_if_full()?;
+            Ok(inserted)
+        })
+    }
+
+    pub fn delete(&mut self, key: &K) -> io::Result<Option<V>> {
+        self.poison_on_failure(|tree| {
+            if let Some(wal) = &mut tree.wal {
+                wal.log_delete(key)?;
+            }
+            let res = tree.delete_unlogged(key)?;
+            tree.checkpoint_if_full()?;
+            Ok(res)
+        })
+    }
+
+    pub fn search(&mut self, key: &K) -> io::Result<Option<&V>> {
+        self.check_poison()?;
+
+        let mut id = self.root;
+        let idx = loop {
+            let node = self.pool.get(id)?;
+            match find_key_idx(node, key) {
+                Ok(idx) => break idx,
+                Err(_) if node.is_leaf() => return Ok(None),
+                Err(idx) => id = node.children[idx],
+            }
+        };
+
+        // The node was just loaded, so it is still cached
+        Ok(Some(&self.pool.peek(id).pairs[idx].1))
+    }
+
+    pub fn augment_search(&mut self, key: &K) -> io::Result<A::Output> {
+        self.check_poison()?;
+
+        let mut id = self.root;
+        let mut acc = A::initial_output();
+        loop {
+            let mut pages = self.pool.get(id)?.children.clone();
+            pages.push(id);
+            self.pool.load_all(&pages)?;
+
+            let node = self.pool.peek(id);
+            let (idx, found) = match find_key_idx(node, key) {
+                Ok(i) => (i, true),
+                Err(i) => (i, false),
+            };
+
+            acc = A::visit(
+                found,
+                idx,
+                &node.pairs,
+                node.children.iter().map(|&c| &self.pool.peek(c).aug_val),
+                &node.aug_val,
+                acc,
+            );
+
+            if found || node.is_leaf() {
+                return Ok(acc);
+            }
+            id = node.children[idx];
+        }
+    }
+
+    /// Writes all cached changes and the header to the file, and syncs it. With a write-ahead
+    /// log, this is a checkpoint that also empties the log.
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.check_poison()?;
+
+        if self.wal.is_some() {
+            return self.checkpoint();
+        }
+        self.pool.write_back()?;
+        let header = self.header_page();
+        self.pool.write_raw(0, &header)?;
+        self.pool.file_mut().sync()
+    }
+
+    /// Syncs the write-ahead log, making every operation so far durable. Without a log, this is
+    /// the same as [`PagedBTree::flush`].
+    pub fn sync(&mut self) -> io::Result<()> {
+        self.check_poison()?;
+
+        match &mut self.wal {
+            Some(wal) => wal.sync(),
+            None => self.flush(),
+        }
+    }
+
+    /// Flushes the tree and returns the underlying file. The log, if any, is empty at that point
+    /// and is dropped.
+    pub fn close(mut self) -> io::Result<F> {
+        self.flush()?;
+        Ok(self.pool.into_file())
+    }
+
+    fn header_page(&self) -> Vec<u8> {
+        let mut header = vec![0; PAGE_SIZE];
+        header[..8].copy_from_slice(&MAGIC);
+        header[8..12].copy_from_slice(&VERSION.to_le_bytes());
+        header[12..16].copy_from_slice(&(PAGE_SIZE as u32).to_le_bytes());
+        header[16..20].copy_from_slice(&(MIN_DEGREE as u32).to_le_bytes());
+        header[20..28].copy_from_slice(&self.root.to_le_bytes());
+        header[28..36].copy_from_slice(&self.len.to_le_bytes());
+        header[36..44].copy_from_slice(&self.pool.page_count.to_le_bytes());
+        header[44..52].copy_from_slice(&self.pool.free_head.to_le_bytes());
+        header
+    }
+
+    /// Logs every modified page and the header, then writes them to the file and empties the log
+    fn checkpoint(&mut self) -> io::Result<()> {
+        let mut pages = self.pool.dirty_pages()?;
+        pages.push((0, self.header_page()));
+        let wal = self.wal.as_mut().expect("checkpoint without a log");
+        wal.log_checkpoint(&pages)?;
+
+        for (id, page) in &pages {
+            self.pool.write_raw(*id, page)?;
+        }
+        self.pool.file_mut().sync()?;
+        self.pool.mark_clean();
+
+        self.wal.as_mut().unwrap().reset()
+    }
+
+    /// Checkpoints if the buffer pool has filled up with modified pages, which it cannot evict
+    fn checkpoint_if_full(&mut self) -> io::Result<()> {
+        if self.wal.is_some() && self.pool.dirty_count() >= self.pool.capacity() {
+            self.checkpoint()?;
+        }
+        Ok(())
+    }
+
+    fn insert_unlogged(&mut self, key: K, value: V) -> io::Result<bool> {
+        if self.pool.get(self.root)?.is_full() {
+            let mut old_root = self.pool.take(self.root)?;
+            let (root_pair, child) = self.split(&mut old_root)?;
+
+            let mut new_root = PageNode::new_leaf();
+            new_root.aug_val = A::split_root(&root_pair, &old_root.aug_val, &child.aug_val);
+            new_root.pairs.push(root_pair);
+            new_root.children.push(self.root);
+            new_root.children.push(self.pool.alloc(child)?);
+
+            self.pool.put(self.root, old_root)?;
+            self.root = self.pool.alloc(new_root)?;
+        }
+
+        let inserted = self.insert_non_full(self.root, key, value)?.is_ok();
+        self.len += u64::from(inserted);
+        Ok(inserted)
+    }
+
+    fn delete_unlogged(&mut self, key: &K) -> io::Result<Option<V>> {
+        let res = self.delete_from(self.root, key)?;
+
+        let root = self.pool.get(self.root)?;
+        if root.pairs.is_empty() && root.children.len() == 1 {
+            let child = root.children[0];
+            self.pool.free(self.root)?;
+            self.root = child;
+        }
+
+        self.len -= u64::from(res.is_some());
+        Ok(res)
+    }
+
+    fn check_poison(&self) -> io::Result<()> {
+        if self.poisoned {
+            Err(io::Error::other(
+                "paged BTree is poisoned: a previous operation failed while modifying it",
+            ))
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Runs `op`, leaving the tree poisoned if it fails or unwinds
+    fn poison_on_failure<R>(
+        &mut self,
+        op: impl FnOnce(&mut Self) -> io::Result<R>,
+    ) -> io::Result<R> {
+        self.check_poison()?;
+        self.poisoned = true;
+        let res = op(self)?;
+        self.poisoned = false;
+        Ok(res)
+    }
+
+    fn split(&mut self, node: &mut PageNode<K, V, A>) -> io::Result<SplitNode<K, V, A>> {
+        debug_assert!(node.is_full());
+
+        let right_pairs = node.pairs.split_off(MIN_DEGREE);
+        let median = node.pairs.pop().unwrap();
+        let right_children = if node.is_leaf() {
+            Vec::new()
+        } else {
+            node.children.split_off(MIN_DEGREE)
+        };
+
+        let children: Vec<_> = node
+            .children
+            .iter()
+            .chain(&right_children)
+            .copied()
+            .collect();
+        self.pool.load_all(&children)?;
+
+        let pool = &self.pool;
+        let right_aug_val;
+        (node.aug_val, right_aug_val) = A::split(
+            &node.pairs,
+            &right_pairs,
+            &median,
+            node.children.iter().map(|&c| &pool.peek(c).aug_val),
+            right_children.iter().map(|&c| &pool.peek(c).aug_val),
+            &node.aug_val,
+        );
+
+        let right = PageNode {
+            pairs: right_pairs,
+            children: right_children,
+            aug_val: right_aug_val,
+        };
+        Ok((median, right))
+    }
+
+    fn split_child(&mut self, node: &mut PageNode<K, V, A>, idx: usize) -> io::Result<()> {
+        let child_id = node.children[idx];
+        let mut child = self.pool.take(child_id)?;
+        let (median, new_child) = self.split(&mut child)?;
+        self.pool.put(child_id, child)?;
+
+        node.pairs.insert(idx, median);
+        node.children.insert(idx + 1, self.pool.alloc(new_child)?);
+        Ok(())
+    }
+
+    fn insert_non_full(&mut self, id: PageId, key: K, value: V) -> io::Result<Result<(), (K, V)>> {
+        let mut node = self.pool.take(id)?;
+        let res = self.insert_into(&mut node, key, value);
+        self.pool.put(id, node)?;
+        res
+    }
+
+    fn insert_into(
+        &mut self,
+        node: &mut PageNode<K, V, A>,
+        key: K,
+        value: V,
+    ) -> io::Result<Result<(), (K, V)>> {
+        debug_assert!(!node.is_full());
+
+        // We ignore duplicates
+        let mut idx = match find_key_idx(node, &key) {
+            Ok(_) => return Ok(Err((key, value))),
+            Err(i) => i,
+        };
+
+        if node.is_leaf() {
+            if A::RECOMPUTE_ALWAYS {
+                node.pairs.insert(idx, (key, value));
+                node.aug_val = self.computed_aug_val(node)?;
+            } else {
+                node.aug_val = A::inserted_sub_tree(&key, &value, &node.aug_val);
+                node.pairs.insert(idx, (key, value));
+            }
+            return Ok(Ok(()));
+        }
+
+        if self.pool.get(node.children[idx])?.is_full() {
+            self.split_child(node, idx)?;
+            match key.cmp(&node.pairs[idx].0) {
+                Ordering::Equal => {
+                    if A::RECOMPUTE_ALWAYS {
+                        node.aug_val = self.computed_aug_val(node)?;
+                    }
+                    return Ok(Err((key, value)));
+                }
+                Ordering::Greater => idx += 1,
+                Ordering::Less => {}
+            }
+        }
+
+        if A::RECOMPUTE_ALWAYS {
+            // Splitting the child changed the node, even if the key turns out to be a duplicate
+            let res = self.insert_non_full(node.children[idx], key, value)?;
+            node.aug_val = self.computed_aug_val(node)?;
+            return Ok(res);
+        }
+
+        node.aug_val = A::inserted_sub_tree(&key, &value, &node.aug_val);
+        // If we end up not inserting the key, because it is a duplicate, undo the augment update
+        let res = self.insert_non_full(node.children[idx], key, value)?;
+        if let Err((k, v)) = &res {
+            self.deleted_sub_tree(node, k, v)?;
+        }
+        Ok(res)
+    }
+
+    fn delete_from(&mut self, id: PageId, key: &K) -> io::Result<Option<V>> {
+        let mut node = self.pool.take(id)?;
+        let res = match find_key_idx(&node, key) {
+            Ok(idx) => self.delete_own(&mut node, key, idx).map(Some),
+            Err(idx) => self.delete_in_descendant(&mut node, idx, key),
+        };
+        self.pool.put(id, node)?;
+        res
+    }
+
+    fn delete_own(&mut self, node: &mut PageNode<K, V, A>, key: &K, idx: usize) -> io::Result<V> {
+        let value = if node.is_leaf() {
+            node.pairs.remove(idx).1
+        } else if !self.pool.get(node.children[idx])?.is_min() {
+            let pair = self.delete_extreme(node.children[idx], true)?;
+            mem::replace(&mut node.pairs[idx], pair).1
+        } else if !self.pool.get(node.children[idx + 1])?.is_min() {
+            let pair = self.delete_extreme(node.children[idx + 1], false)?;
+            mem::replace(&mut node.pairs[idx], pair).1
+        } else {
+            self.merge_children(node, idx)?;
+            let child_id = node.children[idx];
+            let mut child = self.pool.take(child_id)?;
+            let res = self.delete_own(&mut child, key, MIN_DEGREE - 1);
+            self.pool.put(child_id, child)?;
+            res?
+        };
+
+        self.deleted_sub_tree(node, key, &value)?;
+        Ok(value)
+    }
+
+    /// Deletes the largest pair of the subtree if `max` is set, and the smallest one otherwise
+    fn delete_extreme(&mut self, id: PageId, max: bool) -> io::Result<(K, V)> {
+        let mut node = self.pool.take(id)?;
+        let res = self.delete_extreme_in(&mut node, max);
+        self.pool.put(id, node)?;
+        res
+    }
+
+    fn delete_extreme_in(&mut self, node: &mut PageNode<K, V, A>, max: bool) -> io::Result<(K, V)> {
+        let (key, value) = if node.is_leaf() {
+            if max {
+                node.pairs.pop().unwrap()
+            } else {
+                node.pairs.remove(0)
+            }
+        } else {
+            let idx = if max { node.pairs.len() } else { 0 };
+            let idx = if self.pool.get(node.children[idx])?.is_min() {
+                self.make_space(node, idx)?
+            } else {
+                idx
+            };
+            self.delete_extreme(node.children[idx], max)?
+        };
+
+        self.deleted_sub_tree(node, &key, &value)?;
+        Ok((key, value))
+    }
+
+    /// Merges child `idx + 1` and the pair separating it from child `idx` into child `idx`
+    fn merge_children(&mut self, node: &mut PageNode<K, V, A>, idx: usize) -> io::Result<()> {
+        let parent_pair = node.pairs.remove(idx);
+        let right_id = node.children.remove(idx + 1);
+        let right = self.pool.take(right_id)?;
+        let left_id = node.children[idx];
+        let mut left = self.pool.take(left_id)?;
+
+        if !A::RECOMPUTE_ALWAYS {
+            left.aug_val = A::merge(&parent_pair, &left.aug_val, &right.aug_val);
+        }
+        left.pairs.push(parent_pair);
+        left.pairs.extend(right.pairs);
+        left.children.extend(right.children);
+        if A::RECOMPUTE_ALWAYS {
+            left.aug_val = self.computed_aug_val(&left)?;
+        }
+
+        self.pool.put(left_id, left)?;
+        self.pool.free(right_id)
+    }
+
+    /// Makes sure child `idx` has more than the minimum number of pairs, by stealing from or
+    /// merging with a sibling. Returns the new index of the child.
+    fn make_space(&mut self, node: &mut PageNode<K, V, A>, mut idx: usize) -> io::Result<usize> {
+        if idx > 0 && !self.pool.get(node.children[idx - 1])?.is_min() {
+            // Steal a key from the left sibling (through parent)
+            let (victim_id, thief_id) = (node.children[idx - 1], node.children[idx]);
+            let mut victim = self.pool.take(victim_id)?;
+            let mut thief = self.pool.take(thief_id)?;
+
+            let sibling_pair = victim.pairs.pop().unwrap();
+            let stolen_child = victim.children.pop();
+            self.steal(
+                &mut node.pairs[idx - 1],
+                sibling_pair,
+                stolen_child,
+                &mut thief,
+                &mut victim,
+                true,
+            )?;
+
+            self.pool.put(victim_id, victim)?;
+            self.pool.put(thief_id, thief)?;
+        } else if idx < node.pairs.len() && !self.pool.get(node.children[idx + 1])?.is_min() {
+            // Steal a key from the right sibling (through parent)
+            let (thief_id, victim_id) = (node.children[idx], node.children[idx + 1]);
+            let mut victim = self.pool.take(victim_id)?;
+            let mut thief = self.pool.take(thief_id)?;
+
+            let sibling_pair = victim.pairs.remove(0);
+            let stolen_child = (!victim.is_leaf()).then(|| victim.children.remove(0));
+            self.steal(
+                &mut node.pairs[idx],
+                sibling_pair,
+                stolen_child,
+                &mut thief,
+                &mut victim,
+                false,
+            )?;
+
+            self.pool.put(victim_id, victim)?;
+            self.pool.put(thief_id, thief)?;
+        } else if idx > 0 {
+            // We can merge with the left sibling
+            idx -= 1;
+            self.merge_children(node, idx)?;
+        } else {
+            // Merge with right sibling
+            self.merge_children(node, idx)?;
+        }
+
+        Ok(idx)
+    }
+
+    /// Moves `parent_pair` into `thief` and replaces it by `sibling_pair` taken from `victim`,
+    /// along with `stolen_child`. `from_left` tells which side of `thief` the victim is on.
+    fn steal(
+        &mut self,
+        parent_pair: &mut (K, V),
+        sibling_pair: (K, V),
+        stolen_child: Option<PageId>,
+        thief: &mut PageNode<K, V, A>,
+        victim: &mut PageNode<K, V, A>,
+        from_left: bool,
+    ) -> io::Result<()> {
+        if !A::RECOMPUTE_ALWAYS {
+            let stolen = match stolen_child {
+                Some(child) => Some(self.pool.get(child)?),
+                None => None,
+            };
+            (thief.aug_val, victim.aug_val) = A::steal(
+                parent_pair,
+                &sibling_pair,
+                stolen.map(|c| &c.aug_val),
+                &thief.aug_val,
+                &victim.aug_val,
+            );
+            if A::RECOMPUTE_ON_DELETE {
+                victim.aug_val = self.computed_aug_val(victim)?;
+            }
+        }
+
+        let parent_pair = mem::replace(parent_pair, sibling_pair);
+        if from_left {
+            thief.pairs.insert(0, parent_pair);
+            thief.children.splice(0..0, stolen_child);
+        } else {
+            thief.pairs.push(parent_pair);
+            thief.children.extend(stolen_child);
+        }
+        if A::RECOMPUTE_ALWAYS {
+            thief.aug_val = self.computed_aug_val(thief)?;
+            victim.aug_val = self.computed_aug_val(victim)?;
+        }
+        Ok(())
+    }
+
+    fn delete_in_descendant(
+        &mut self,
+        node: &mut PageNode<K, V, A>,
+        mut idx: usize,
+        key: &K,
+    ) -> io::Result<Option<V>> {
+        if node.is_leaf() {
+            return Ok(None);
+        }
+
+        if self.pool.get(node.children[idx])?.is_min() {
+            idx = self.make_space(node, idx)?;
+        }
+
+        let res = self.delete_from(node.children[idx], key)?;
+        match &res {
+            Some(value) => self.deleted_sub_tree(node, key, value)?,
+            // Making space moved pairs around, even though the key was not found
+            None if A::RECOMPUTE_ALWAYS && !node.pairs.is_empty() => {
+                node.aug_val = self.computed_aug_val(node)?;
+            }
+            None => {}
+        }
+        Ok(res)
+    }
+
+    /// Updates the augment value of `node` after `key` and `value` were removed from its subtree,
+    /// see [`Augment::RECOMPUTE_ON_DELETE`]
+    fn deleted_sub_tree(
+        &mut self,
+        node: &mut PageNode<K, V, A>,
+        key: &K,
+        value: &V,
+    ) -> io::Result<()> {
+        if !A::RECOMPUTE_ON_DELETE && !A::RECOMPUTE_ALWAYS {
+            node.aug_val = A::deleted_sub_tree(key, value, &node.aug_val);
+        } else if !node.pairs.is_empty() || node.is_leaf() {
+            // A root that lost its last pair is replaced by its only child right away
+            node.aug_val = self.computed_aug_val(node)?;
+        }
+        Ok(())
+    }
+
+    fn computed_aug_val(&mut self, node: &PageNode<K, V, A>) -> io::Result<A::Value> {
+        self.pool.load_all(&node.children)?;
+        let pool = &self.pool;
+        let children = node.children.iter().map(|&c| &pool.peek(c).aug_val);
+        Ok(A::compute(&node.pairs, children))
+    }
+}
+
+/// The median pair of a split node, and the new node holding the upper half
+type SplitNode<K, V, A> = ((K, V), PageNode<K, V, A>);
+
+fn find_key_idx<K: Ord, V, A: Augment<K, V>>(
+    node: &PageNode<K, V, A>,
+    key: &K,
+) -> Result<usize, usize> {
+    node.pairs.binary_search_by(|(k, _)| k.cmp(key))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+    use std::fmt::Debug;
+    use std::io::{self, Cursor, Read, Seek, SeekFrom, Write};
+
+    use crate::augments::{HashAugment, IntervalAugment, SumAugment};
+    use crate::paged::{PageFile, PageId, PagedBTree, MAX_PAIR_SIZE};
+    use crate::snapshot::{Decode, Encode};
+    use crate::tests::rng;
+    use crate::Augment;
+
+    type Tree = PagedBTree<i64, i64, SumAugment, Cursor<Vec<u8>>>;
+
+    fn check_against_model(tree: &mut Tree, model: &BTreeMap<i64, i64>) {
+        assert_eq!(tree.len(), model.len() as u64);
+        for probe in (-10..1010).step_by(17) {
+            assert_eq!(tree.search(&probe).unwrap(), model.get(&probe));
+            let sum: i64 = model.range(..=probe).map(|(_, v)| v).sum();
+            assert_eq!(tree.augment_search(&probe).unwrap(), sum);
+        }
+    }
+
+    #[test]
+    fn random_operations_match_model() {
+        let mut tree = Tree::create(Cursor::new(Vec::new()), 3).unwrap();
+        let mut model = BTreeMap::new();
+        let mut next = rng::<u64>(0x5eed);
+
+        for round in 0..6000 {
+            let key = (next() % 1000) as i64;
+            if next().is_multiple_of(3) {
+                assert_eq!(tree.delete(&key).unwrap(), model.remove(&key));
+            } else {
+                let inserted = tree.insert(key, key * 7 - 300).unwrap();
+                assert_eq!(inserted, !model.contains_key(&key));
+                model.entry(key).or_insert(key * 7 - 300);
+            }
+
+            if round % 500 == 0 {
+                check_against_model(&mut tree, &model);
+            }
+        }
+        check_against_model(&mut tree, &model);
+    }
+
+    #[test]
+    fn recomputing_augment_matches_model() {
+        let mut tree =
+            PagedBTree::<u32, u32, IntervalAugment, _>::create(Cursor::new(Vec::new()), 3).unwrap();
+        let mut model = BTreeMap::new();
+        let mut next = rng::<u64>(0xface);
+
+        for round in 0..4000 {
+            let start = (next() % 1000) as u32;
+            if next().is_multiple_of(3) {
+                assert_eq!(tree.delete(&start).unwrap(), model.remove(&start));
+            } else {
+                let end = start + 1 + (next() % 100) as u32;
+                assert_eq!(
+                    tree.insert(start, end).unwrap(),
+                    !model.contains_key(&start)
+                );
+                model.entry(start).or_insert(end);
+            }
+
+            if round % 250 == 0 {
+                for probe in (0..1100).step_by(13) {
+                    let largest = model.range(..=probe).map(|(_, &end)| end).max();
+                    assert_eq!(tree.augment_search(&probe).unwrap(), largest);
+                }
+            }
+        }
+    }
+
+    /// Checks that the augment value of every node in the subtree agrees with one computed from
+    /// scratch
+    fn check_aug_vals<A: Augment<u32, u32>>(
+        tree: &mut PagedBTree<u32, u32, A, Cursor<Vec<u8>>>,
+        id: PageId,
+    ) where
+        A::Value: Encode + Decode + PartialEq + Debug,
+    {
+        let node = tree.pool.take(id).unwrap();
+        for &child in &node.children {
+            check_aug_vals(tree, child);
+        }
+        assert_eq!(node.aug_val, tree.computed_aug_val(&node).unwrap());
+        tree.pool.put(id, node).unwrap();
+    }
+
+    #[test]
+    fn hash_augment_stays_up_to_date() {
+        let mut tree =
+            PagedBTree::<u32, u32, HashAugment, _>::create(Cursor::new(Vec::new()), 3).unwrap();
+        let mut next = rng::<u64>(0xcafe);
+
+        for round in 0..4000 {
+            let key = (next() % 1000) as u32;
+            if next().is_multiple_of(3) {
+                tree.delete(&key).unwrap();
+            } else {
+                tree.insert(key, key / 2).unwrap();
+            }
+
+            if round % 500 == 0 {
+                let root = tree.root;
+                check_aug_vals(&mut tree, root);
+            }
+        }
+        let root = tree.root;
+        check_aug_vals(&mut tree, root);
+    }
+
+    #[test]
+    fn reopen_after_close() {
+        let mut tree = Tree::create(Cursor::new(Vec::new()), 8).unwrap();
+        let mut model = BTreeMap::new();
+        for i in 0..1000 {
+            tree.insert(i, -i).unwrap();
+            model.insert(i, -i);
+        }
+        for i in (0..1000).step_by(3) {
+            tree.delete(&i).unwrap();
+            model.remove(&i);
+        }
+
+        let file = tree.close().unwrap();
+        let mut tree = Tree::open(file, 8).unwrap();
+        check_against_model(&mut tree, &model);
+    }
+
+    #[test]
+    fn freed_pages_are_reused() {
+        let mut tree = Tree::create(Cursor::new(Vec::new()), 8).unwrap();
+        for i in 0..2000 {
+            tree.insert(i, i).unwrap();
+        }
+        let pages = tree.pool.page_count;
+
+        for round in 0..3 {
+            for i in 0..2000 {
+                assert_eq!(tree.delete(&i).unwrap(), Some(i), "round {round}");
+            }
+            assert!(tree.is_empty());
+            for i in 0..2000 {
+                tree.insert(i, i).unwrap();
+            }
+        }
+        assert!(tree.pool.page_count <= pages + 2);
+    }
+
+    #[test]
+    fn works_with_real_file() {
+        let path = std::env::temp_dir().join(format!("b-tree-paged-{}", std::process::id()));
+        let file = || {
+            std::fs::OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(false)
+                .open(&path)
+                .unwrap()
+        };
+
+        let mut tree = PagedBTree::<i64, i64, SumAugment>::create(file(), 4).unwrap();
+        for i in 0..500 {
+            tree.insert(i, 2).unwrap();
+        }
+        tree.close().unwrap();
+
+        let mut tree = PagedBTree::<i64, i64, SumAugment>::open(file(), 4).unwrap();
+        let res = tree.augment_search(&249);
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(res.unwrap(), 500);
+    }
+
+    #[test]
+    fn oversized_pairs_are_rejected() {
+        let mut tree =
+            PagedBTree::<i64, Vec<u8>, (), _>::create(Cursor::new(Vec::new()), 4).unwrap();
+
+        let err = tree.insert(1, vec![0; MAX_PAIR_SIZE]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        assert!(!tree.is_poisoned());
+
+        for i in 0..100 {
+            tree.insert(i, vec![i as u8; MAX_PAIR_SIZE - 8]).unwrap();
+        }
+        assert_eq!(
+            tree.search(&42).unwrap(),
+            Some(&vec![42; MAX_PAIR_SIZE - 8])
+        );
+    }
+
+    /// Fails every write once `writes_left` runs out
+    struct FailingFile {
+        inner: Cursor<Vec<u8>>,
+        writes_left: usize,
+    }
+
+    impl Read for FailingFile {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.inner.read(buf)
+        }
+    }
+
+    impl Write for FailingFile {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            if self.writes_left == 0 {
+                return Err(io::Error::other("disk full"));
+            }
+            self.writes_left -= 1;
+            self.inner.write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Seek for FailingFile {
+        fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+            self.inner.seek(pos)
+        }
+    }
+
+    impl PageFile for FailingFile {
+        fn sync(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn io_errors_poison_the_tree() {
+        let file = FailingFile {
+            inner: Cursor::new(Vec::new()),
+            writes_left: 20,
+        };
+        let mut tree = PagedBTree::<i64, i64, SumAugment, _>::create(file, 2).unwrap();
+
+        let err = (0..1000).find_map(|i| tree.insert(i, i).err());
+        assert!(err.is_some());
+        assert!(tree.is_poisoned());
+        assert!(tree.search(&0).is_err());
+        assert!(tree.insert(5000, 0).is_err());
+        assert!(tree.flush().is_err());
+    }
+}
@@ -0,0 +1,469 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fs::File;
+use std::io::{self, Cursor, Read, Seek, SeekFrom, Write};
+
+use crate::snapshot::{Decode, Encode};
+use crate::{Augment, MIN_DEGREE};
+
+/// Identifies a page by its position in the file, in units of [`PAGE_SIZE`]
+pub(crate) type PageId = u64;
+
+/// The size of every page in the file, including the header page
+pub const PAGE_SIZE: usize = 4096;
+
+const NODE_TAG: u8 = 1;
+const FREE_TAG: u8 = 2;
+
+/// The file that a [`PagedBTree`](super::PagedBTree) stores its pages in
+pub trait PageFile: Read + Write + Seek {
+    /// Makes everything written so far durable
+    fn sync(&mut self) -> io::Result<()>;
+}
+
+impl PageFile for File {
+    fn sync(&mut self) -> io::Result<()> {
+        self.sync_data()
+    }
+}
+
+impl PageFile for Cursor<Vec<u8>> {
+    fn sync(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+pub(crate) fn invalid_data(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// The in-memory form of a node page. Children are referred to by page instead of being owned.
+pub(crate) struct PageNode<K, V, A: Augment<K, V>> {
+    pub pairs: Vec<(K, V)>,
+    pub children: Vec<PageId>,
+    pub aug_val: A::Value,
+}
+
+impl<K, V, A: Augment<K, V>> PageNode<K, V, A> {
+    pub fn new_leaf() -> Self {
+        Self {
+            pairs: Vec::with_capacity(2 * MIN_DEGREE - 1),
+            children: Vec::new(),
+            aug_val: A::initial_value(),
+        }
+    }
+
+    pub fn is_min(&self) -> bool {
+        self.pairs.len() < MIN_DEGREE
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.pairs.len() == 2 * MIN_DEGREE - 1
+    }
+
+    pub fn is_leaf(&self) -> bool {
+        self.children.is_empty()
+    }
+}
+
+impl<K, V, A> PageNode<K, V, A>
+where
+    K: Encode + Decode,
+    V: Encode + Decode,
+    A: Augment<K, V>,
+    A::Value: Encode + Decode,
+{
+    /// Layout: tag, pair count (u16), child count (u16), child pages (u64 each), augment value,
+    /// then every key and value. The augment value, keys and values are prefixed by their length
+    /// (u32).
+    fn encode(&self, page: &mut Vec<u8>) -> io::Result<()> {
+        page.clear();
+        page.push(NODE_TAG);
+        page.extend_from_slice(&(self.pairs.len() as u16).to_le_bytes());
+        page.extend_from_slice(&(self.children.len() as u16).to_le_bytes());
+        for child in &self.children {
+            page.extend_from_slice(&child.to_le_bytes());
+        }
+        encode_chunk(page, &self.aug_val);
+        for (key, value) in &self.pairs {
+            encode_chunk(page, key);
+            encode_chunk(page, value);
+        }
+
+        if page.len() > PAGE_SIZE {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "node does not fit in a page",
+            ));
+        }
+        page.resize(PAGE_SIZE, 0);
+        Ok(())
+    }
+
+    fn decode(page: &[u8]) -> io::Result<Self> {
+        let mut reader = PageReader { page, pos: 0 };
+        if reader.bytes::<1>()? != [NODE_TAG] {
+            return Err(invalid_data("page is not a node"));
+        }
+        let num_pairs = u16::from_le_bytes(reader.bytes()?) as usize;
+        let num_children = u16::from_le_bytes(reader.bytes()?) as usize;
+        if num_pairs >= 2 * MIN_DEGREE || num_children > num_pairs + 1 {
+            return Err(invalid_data("node page has too many entries"));
+        }
+
+        let children = (0..num_children)
+            .map(|_| Ok(u64::from_le_bytes(reader.bytes()?)))
+            .collect::<io::Result<_>>()?;
+        let aug_val = reader.chunk()?;
+        let mut pairs = Vec::with_capacity(2 * MIN_DEGREE - 1);
+        for _ in 0..num_pairs {
+            pairs.push((reader.chunk()?, reader.chunk()?));
+        }
+
+        Ok(Self {
+            pairs,
+            children,
+            aug_val,
+        })
+    }
+}
+
+pub(crate) fn encode_chunk(page: &mut Vec<u8>, value: &impl Encode) {
+    let start = page.len();
+    page.extend_from_slice(&[0; 4]);
+    value.encode(page);
+    let len = (page.len() - start - 4) as u32;
+    page[start..start + 4].copy_from_slice(&len.to_le_bytes());
+}
+
+pub(crate) struct PageReader<'a> {
+    pub page: &'a [u8],
+    pub pos: usize,
+}
+
+impl PageReader<'_> {
+    pub fn slice(&mut self, len: usize) -> io::Result<&[u8]> {
+        let bytes = self
+            .page
+            .get(self.pos..self.pos + len)
+            .ok_or_else(|| invalid_data("page is truncated"))?;
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    pub fn bytes<const N: usize>(&mut self) -> io::Result<[u8; N]> {
+        Ok(self.slice(N)?.try_into().unwrap())
+    }
+
+    pub fn chunk<T: Decode>(&mut self) -> io::Result<T> {
+        let len = u32::from_le_bytes(self.bytes()?) as usize;
+        T::decode(self.slice(len)?).ok_or_else(|| invalid_data("failed to decode page contents"))
+    }
+}
+
+struct Frame<K, V, A: Augment<K, V>> {
+    node: PageNode<K, V, A>,
+    dirty: bool,
+    last_used: u64,
+}
+
+/// Caches decoded node pages, evicting the least recently used one when full. Modified pages are
+/// only written back when they are evicted or flushed.
+///
+/// The clean and the modified pages are each kept in a set ordered by when they were last used, so
+/// finding the page to evict takes `O(log n)` time.
+///
+/// With `no_steal` set, modified pages are never written to the file by the pool itself. They
+/// stay cached, past the capacity if need be, until they are collected by
+/// [`dirty_pages`](Self::dirty_pages) and written out as a whole.
+pub(crate) struct BufferPool<K, V, A: Augment<K, V>, F> {
+    file: F,
+    capacity: usize,
+    frames: HashMap<PageId, Frame<K, V, A>>,
+    /// The clean cached pages by when they were last used
+    clean: BTreeSet<(u64, PageId)>,
+    /// The modified cached pages by when they were last used
+    dirty: BTreeSet<(u64, PageId)>,
+    tick: u64,
+    pub no_steal: bool,
+    /// Free pages waiting to be written while `no_steal` is set
+    pending_free: BTreeMap<PageId, Vec<u8>>,
+    /// The number of pages in the file, including the header and free pages
+    pub page_count: u64,
+    /// The first page of the free list, or 0 if it is empty
+    pub free_head: PageId,
+    buf: Vec<u8>,
+}
+
+impl<K, V, A, F> BufferPool<K, V, A, F>
+where
+    K: Encode + Decode,
+    V: Encode + Decode,
+    A: Augment<K, V>,
+    A::Value: Encode + Decode,
+    F: PageFile,
+{
+    pub fn new(file: F, capacity: usize, page_count: u64, free_head: PageId) -> Self {
+        Self {
+            file,
+            capacity: capacity.max(1),
+            frames: HashMap::new(),
+            clean: BTreeSet::new(),
+            dirty: BTreeSet::new(),
+            tick: 0,
+            no_steal: false,
+            pending_free: BTreeMap::new(),
+            page_count,
+            free_head,
+            buf: Vec::with_capacity(PAGE_SIZE),
+        }
+    }
+
+    pub fn read_raw(&mut self, id: PageId) -> io::Result<&[u8]> {
+        if let Some(page) = self.pending_free.get(&id) {
+            return Ok(page);
+        }
+
+        self.buf.resize(PAGE_SIZE, 0);
+        self.file.seek(SeekFrom::Start(id * PAGE_SIZE as u64))?;
+        self.file.read_exact(&mut self.buf)?;
+        Ok(&self.buf)
+    }
+
+    pub fn write_raw(&mut self, id: PageId, page: &[u8]) -> io::Result<()> {
+        debug_assert_eq!(page.len(), PAGE_SIZE);
+        self.file.seek(SeekFrom::Start(id * PAGE_SIZE as u64))?;
+        self.file.write_all(page)
+    }
+
+    fn write_node(&mut self, id: PageId, node: &PageNode<K, V, A>) -> io::Result<()> {
+        let mut page = std::mem::take(&mut self.buf);
+        let res = node
+            .encode(&mut page)
+            .and_then(|_| self.write_raw(id, &page));
+        self.buf = page;
+        res
+    }
+
+    fn recency(&mut self, dirty: bool) -> &mut BTreeSet<(u64, PageId)> {
+        if dirty {
+            &mut self.dirty
+        } else {
+            &mut self.clean
+        }
+    }
+
+    fn insert_frame(&mut self, id: PageId, frame: Frame<K, V, A>) {
+        self.remove_frame(id);
+        self.recency(frame.dirty).insert((frame.last_used, id));
+        self.frames.insert(id, frame);
+    }
+
+    fn remove_frame(&mut self, id: PageId) -> Option<Frame<K, V, A>> {
+        let frame = self.frames.remove(&id)?;
+        self.recency(frame.dirty).remove(&(frame.last_used, id));
+        Some(frame)
+    }
+
+    /// Evicts the least recently used page not in `pinned` if the pool is full. If everything is
+    /// pinned, the pool grows past its capacity for a moment instead.
+    fn make_room(&mut self, pinned: &[PageId]) -> io::Result<()> {
+        debug_assert_eq!(self.frames.len(), self.clean.len() + self.dirty.len());
+        if self.frames.len() < self.capacity {
+            return Ok(());
+        }
+
+        // At most `pinned.len()` pages are skipped in either set
+        let oldest = |pages: &BTreeSet<(u64, PageId)>| {
+            pages.iter().find(|(_, id)| !pinned.contains(id)).copied()
+        };
+        let clean = oldest(&self.clean);
+        let dirty = if self.no_steal {
+            None
+        } else {
+            oldest(&self.dirty)
+        };
+        let victim = match (clean, dirty) {
+            (Some(clean), Some(dirty)) => Some(clean.min(dirty)),
+            (clean, dirty) => clean.or(dirty),
+        };
+        if let Some((_, victim)) = victim {
+            let frame = self.remove_frame(victim).unwrap();
+            if frame.dirty {
+                self.write_node(victim, &frame.node)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Makes sure page `id` is cached without evicting any page in `pinned`
+    fn load(&mut self, id: PageId, pinned: &[PageId]) -> io::Result<()> {
+        self.tick += 1;
+        if let Some(frame) = self.frames.get_mut(&id) {
+            let (tick, last_used, dirty) = (self.tick, frame.last_used, frame.dirty);
+            frame.last_used = tick;
+            let recency = self.recency(dirty);
+            recency.remove(&(last_used, id));
+            recency.insert((tick, id));
+            return Ok(());
+        }
+
+        self.make_room(pinned)?;
+        let node = PageNode::decode(self.read_raw(id)?)?;
+        self.insert_frame(
+            id,
+            Frame {
+                node,
+                dirty: false,
+                last_used: self.tick,
+            },
+        );
+        Ok(())
+    }
+
+    pub fn get(&mut self, id: PageId) -> io::Result<&PageNode<K, V, A>> {
+        self.load(id, &[])?;
+        Ok(&self.frames[&id].node)
+    }
+
+    /// Makes sure all of `ids` are cached at the same time, so they can be [`peek`](Self::peek)ed
+    pub fn load_all(&mut self, ids: &[PageId]) -> io::Result<()> {
+        for &id in ids {
+            self.load(id, ids)?;
+        }
+        Ok(())
+    }
+
+    /// # Panics
+    /// If page `id` is not cached
+    pub fn peek(&self, id: PageId) -> &PageNode<K, V, A> {
+        &self.frames[&id].node
+    }
+
+    /// Removes page `id` from the pool to be modified. It must be [`put`](Self::put) back.
+    pub fn take(&mut self, id: PageId) -> io::Result<PageNode<K, V, A>> {
+        self.load(id, &[])?;
+        Ok(self.remove_frame(id).unwrap().node)
+    }
+
+    /// Caches `node` as the new contents of page `id`
+    pub fn put(&mut self, id: PageId, node: PageNode<K, V, A>) -> io::Result<()> {
+        self.tick += 1;
+        self.make_room(&[id])?;
+        self.insert_frame(
+            id,
+            Frame {
+                node,
+                dirty: true,
+                last_used: self.tick,
+            },
+        );
+        Ok(())
+    }
+
+    /// Stores `node` in a free page, or at the end of the file if there is none
+    pub fn alloc(&mut self, node: PageNode<K, V, A>) -> io::Result<PageId> {
+        let id = if self.free_head != 0 {
+            let id = self.free_head;
+            let page = self.read_raw(id)?;
+            if page[0] != FREE_TAG {
+                return Err(invalid_data("free list points to a page in use"));
+            }
+            self.free_head = u64::from_le_bytes(page[1..9].try_into().unwrap());
+            // A page freed since the last checkpoint must not be written as both a free page and
+            // the new node
+            self.pending_free.remove(&id);
+            id
+        } else {
+            self.page_count += 1;
+            self.page_count - 1
+        };
+
+        self.put(id, node)?;
+        Ok(id)
+    }
+
+    /// Drops page `id` and adds it to the free list
+    pub fn free(&mut self, id: PageId) -> io::Result<()> {
+        self.remove_frame(id);
+
+        let mut page = vec![0; PAGE_SIZE];
+        page[0] = FREE_TAG;
+        page[1..9].copy_from_slice(&self.free_head.to_le_bytes());
+        if self.no_steal {
+            self.pending_free.insert(id, page);
+        } else {
+            self.write_raw(id, &page)?;
+        }
+        self.free_head = id;
+        Ok(())
+    }
+
+    /// The number of pages that have been modified since they were last written
+    pub fn dirty_count(&self) -> usize {
+        self.dirty.len() + self.pending_free.len()
+    }
+
+    /// Encodes every modified page, in order. They remain modified until
+    /// [`mark_clean`](Self::mark_clean) is called.
+    pub fn dirty_pages(&mut self) -> io::Result<Vec<(PageId, Vec<u8>)>> {
+        let mut pages = Vec::with_capacity(self.dirty_count());
+        for &(_, id) in &self.dirty {
+            let mut page = Vec::with_capacity(PAGE_SIZE);
+            self.frames[&id].node.encode(&mut page)?;
+            pages.push((id, page));
+        }
+        pages.extend(
+            self.pending_free
+                .iter()
+                .map(|(&id, page)| (id, page.clone())),
+        );
+        pages.sort_unstable_by_key(|&(id, _)| id);
+        debug_assert!(
+            pages.windows(2).all(|pair| pair[0].0 != pair[1].0),
+            "page written twice in one checkpoint"
+        );
+        Ok(pages)
+    }
+
+    /// Marks every page as written, after the pages from [`dirty_pages`](Self::dirty_pages) have
+    /// been written to the file
+    pub fn mark_clean(&mut self) {
+        for (_, id) in &self.dirty {
+            self.frames.get_mut(id).unwrap().dirty = false;
+        }
+        self.clean.append(&mut self.dirty);
+        self.pending_free.clear();
+    }
+
+    /// Writes every modified page back to the file, without syncing it
+    pub fn write_back(&mut self) -> io::Result<()> {
+        let mut dirty: Vec<_> = self.dirty.iter().map(|&(_, id)| id).collect();
+        dirty.sort_unstable();
+
+        for id in dirty {
+            let frame = self.remove_frame(id).unwrap();
+            let res = self.write_node(id, &frame.node);
+            self.insert_frame(
+                id,
+                Frame {
+                    dirty: res.is_err(),
+                    ..frame
+                },
+            );
+            res?;
+        }
+        Ok(())
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    pub fn file_mut(&mut self) -> &mut F {
+        &mut self.file
+    }
+
+    pub fn into_file(self) -> F {
+        self.file
+    }
+}
@@ -0,0 +1,544 @@
+use std::io::{self, SeekFrom};
+
+use super::pool::{encode_chunk, PageFile, PageId, PageReader, PAGE_SIZE};
+use crate::snapshot::{Crc32, Decode, Encode};
+
+const MAGIC: [u8; 8] = *b"BTREEWAL";
+const SLOT_LEN: u64 = 12;
+const HEADER_LEN: u64 = MAGIC.len() as u64 + 2 * SLOT_LEN;
+
+const INSERT_TAG: u8 = 1;
+const DELETE_TAG: u8 = 2;
+const PAGE_TAG: u8 = 3;
+const CHECKPOINT_TAG: u8 = 4;
+
+pub(crate) enum Record<K, V> {
+    Insert(K, V),
+    Delete(K),
+    /// The new contents of a page, written as part of a checkpoint
+    Page(PageId, Vec<u8>),
+    /// Marks that every page of the checkpoint has been logged
+    Checkpoint,
+}
+
+/// A write-ahead log. Each record is stored as its payload length (u32), a CRC-32 of the epoch
+/// and the payload (u32), and the payload itself.
+///
+/// The log is emptied by starting a new epoch instead of truncating the file, which makes every
+/// older record fail its checksum. The epoch is kept in one of two header slots, alternating
+/// between them, so a torn header write still leaves the previous epoch readable.
+pub(crate) struct Wal<F> {
+    file: F,
+    epoch: u64,
+    /// Where the next record goes
+    end: u64,
+    sync_every: usize,
+    unsynced: usize,
+    buf: Vec<u8>,
+}
+
+fn slot_checksum(epoch: u64) -> u32 {
+    let mut crc = Crc32::new();
+    crc.update(&epoch.to_le_bytes());
+    crc.finish()
+}
+
+impl<F: PageFile> Wal<F> {
+    /// Opens the log in `file` and reads its records, stopping at the first incomplete one. A file
+    /// that does not contain a log is treated as an empty one.
+    pub fn open<K: Decode, V: Decode>(
+        mut file: F,
+        sync_every: usize,
+    ) -> io::Result<(Self, Vec<Record<K, V>>)> {
+        let mut contents = Vec::new();
+        file.seek(SeekFrom::Start(0))?;
+        file.read_to_end(&mut contents)?;
+
+        let mut epoch = None;
+        if contents.len() as u64 >= HEADER_LEN && contents[..MAGIC.len()] == MAGIC {
+            for slot in contents[MAGIC.len()..HEADER_LEN as usize].chunks(SLOT_LEN as usize) {
+                let slot_epoch = u64::from_le_bytes(slot[..8].try_into().unwrap());
+                let checksum = u32::from_le_bytes(slot[8..].try_into().unwrap());
+                if checksum == slot_checksum(slot_epoch) && epoch < Some(slot_epoch) {
+                    epoch = Some(slot_epoch);
+                }
+            }
+        }
+
+        let mut wal = Self {
+            file,
+            epoch: epoch.unwrap_or(0),
+            end: HEADER_LEN,
+            sync_every: sync_every.max(1),
+            unsynced: 0,
+            buf: Vec::new(),
+        };
+
+        let mut records = Vec::new();
+        if epoch.is_some() {
+            while let Some((record, len)) = wal.parse_record(&contents[wal.end as usize..]) {
+                records.push(record);
+                wal.end += len;
+            }
+        }
+        Ok((wal, records))
+    }
+
+    /// Parses the record at the start of `bytes`, returning it and its length
+    fn parse_record<K: Decode, V: Decode>(&self, bytes: &[u8]) -> Option<(Record<K, V>, u64)> {
+        let len = u32::from_le_bytes(bytes.get(..4)?.try_into().unwrap()) as usize;
+        let checksum = u32::from_le_bytes(bytes.get(4..8)?.try_into().unwrap());
+        let payload = bytes.get(8..8 + len)?;
+
+        let mut crc = Crc32::new();
+        crc.update(&self.epoch.to_le_bytes());
+        crc.update(payload);
+        if len == 0 || crc.finish() != checksum {
+            return None;
+        }
+
+        let mut reader = PageReader {
+            page: payload,
+            pos: 1,
+        };
+        let record = match payload[0] {
+            INSERT_TAG => Record::Insert(reader.chunk().ok()?, reader.chunk().ok()?),
+            DELETE_TAG => Record::Delete(reader.chunk().ok()?),
+            PAGE_TAG => {
+                let id = u64::from_le_bytes(reader.bytes().ok()?);
+                Record::Page(id, reader.slice(PAGE_SIZE).ok()?.to_vec())
+            }
+            CHECKPOINT_TAG => Record::Checkpoint,
+            _ => return None,
+        };
+        Some((record, 8 + len as u64))
+    }
+
+    /// Appends a record with the payload written by `write_payload`, without syncing
+    fn append(&mut self, write_payload: impl FnOnce(&mut Vec<u8>)) -> io::Result<()> {
+        let mut buf = std::mem::take(&mut self.buf);
+        buf.clear();
+        buf.extend_from_slice(&[0; 8]);
+        write_payload(&mut buf);
+
+        let mut crc = Crc32::new();
+        crc.update(&self.epoch.to_le_bytes());
+        crc.update(&buf[8..]);
+        let len = (buf.len() - 8) as u32;
+        buf[..4].copy_from_slice(&len.to_le_bytes());
+        buf[4..8].copy_from_slice(&crc.finish().to_le_bytes());
+
+        let res = self
+            .file
+            .seek(SeekFrom::Start(self.end))
+            .and_then(|_| self.file.write_all(&buf));
+        self.end += buf.len() as u64;
+        self.buf = buf;
+        res
+    }
+
+    /// Appends an insert record, syncing the log if enough operations have piled up since the
+    /// last sync
+    pub fn log_insert<K: Encode, V: Encode>(&mut self, key: &K, value: &V) -> io::Result<()> {
+        self.append(|buf| {
+            buf.push(INSERT_TAG);
+            encode_chunk(buf, key);
+            encode_chunk(buf, value);
+        })?;
+        self.operation_logged()
+    }
+
+    /// Appends a delete record, syncing the log if enough operations have piled up since the
+    /// last sync
+    pub fn log_delete<K: Encode>(&mut self, key: &K) -> io::Result<()> {
+        self.append(|buf| {
+            buf.push(DELETE_TAG);
+            encode_chunk(buf, key);
+        })?;
+        self.operation_logged()
+    }
+
+    fn operation_logged(&mut self) -> io::Result<()> {
+        self.unsynced += 1;
+        if self.unsynced >= self.sync_every {
+            self.sync()?;
+        }
+        Ok(())
+    }
+
+    /// Logs a checkpoint consisting of `pages` and syncs the log
+    pub fn log_checkpoint(&mut self, pages: &[(PageId, Vec<u8>)]) -> io::Result<()> {
+        for (id, page) in pages {
+            self.append(|buf| {
+                buf.push(PAGE_TAG);
+                buf.extend_from_slice(&id.to_le_bytes());
+                buf.extend_from_slice(page);
+            })?;
+        }
+        self.append(|buf| buf.push(CHECKPOINT_TAG))?;
+        self.sync()
+    }
+
+    pub fn sync(&mut self) -> io::Result<()> {
+        self.file.sync()?;
+        self.unsynced = 0;
+        Ok(())
+    }
+
+    /// Empties the log by moving to the next epoch
+    pub fn reset(&mut self) -> io::Result<()> {
+        let epoch = self.epoch + 1;
+        let mut slot = [0; SLOT_LEN as usize];
+        slot[..8].copy_from_slice(&epoch.to_le_bytes());
+        slot[8..].copy_from_slice(&slot_checksum(epoch).to_le_bytes());
+
+        self.file.seek(SeekFrom::Start(0))?;
+        self.file.write_all(&MAGIC)?;
+        let slot_pos = MAGIC.len() as u64 + epoch % 2 * SLOT_LEN;
+        self.file.seek(SeekFrom::Start(slot_pos))?;
+        self.file.write_all(&slot)?;
+        self.file.sync()?;
+
+        self.epoch = epoch;
+        self.end = HEADER_LEN;
+        self.unsynced = 0;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::collections::BTreeMap;
+    use std::io::{self, Read, Seek, SeekFrom, Write};
+    use std::rc::Rc;
+
+    use crate::augments::SumAugment;
+    use crate::paged::{PageFile, PagedBTree};
+    use crate::tests::rng;
+
+    /// What a crash-free disk holds, and what survives a crash because it was synced
+    #[derive(Default)]
+    struct Disk {
+        current: Vec<u8>,
+        durable: Vec<u8>,
+    }
+
+    /// Shared by every file, so the crash hits them all at once
+    #[derive(Default)]
+    struct Power {
+        writes: usize,
+        /// The write that the crash tears in half. Every write after it fails.
+        crash_at: Option<usize>,
+    }
+
+    impl Power {
+        fn is_off(&self) -> bool {
+            self.crash_at.is_some_and(|at| self.writes > at)
+        }
+    }
+
+    struct SimFile {
+        disk: Rc<RefCell<Disk>>,
+        power: Rc<RefCell<Power>>,
+        pos: usize,
+    }
+
+    impl Read for SimFile {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let disk = self.disk.borrow();
+            let available = disk.current.get(self.pos..).unwrap_or_default();
+            let len = buf.len().min(available.len());
+            buf[..len].copy_from_slice(&available[..len]);
+            self.pos += len;
+            Ok(len)
+        }
+    }
+
+    impl Write for SimFile {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            let mut power = self.power.borrow_mut();
+            if power.is_off() {
+                return Err(io::Error::other("power is off"));
+            }
+            let torn = power.crash_at == Some(power.writes);
+            power.writes += 1;
+
+            let buf = if torn { &buf[..buf.len() / 2] } else { buf };
+            let mut disk = self.disk.borrow_mut();
+            if disk.current.len() < self.pos + buf.len() {
+                disk.current.resize(self.pos + buf.len(), 0);
+            }
+            disk.current[self.pos..self.pos + buf.len()].copy_from_slice(buf);
+            self.pos += buf.len();
+
+            if torn {
+                Err(io::Error::other("power failed mid-write"))
+            } else {
+                Ok(buf.len())
+            }
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Seek for SimFile {
+        fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+            self.pos = match pos {
+                SeekFrom::Start(pos) => pos as usize,
+                SeekFrom::End(offset) => {
+                    (self.disk.borrow().current.len() as i64 + offset) as usize
+                }
+                SeekFrom::Current(offset) => (self.pos as i64 + offset) as usize,
+            };
+            Ok(self.pos as u64)
+        }
+    }
+
+    impl PageFile for SimFile {
+        fn sync(&mut self) -> io::Result<()> {
+            if self.power.borrow().is_off() {
+                return Err(io::Error::other("power is off"));
+            }
+            let mut disk = self.disk.borrow_mut();
+            disk.durable = disk.current.clone();
+            Ok(())
+        }
+    }
+
+    type Tree = PagedBTree<u32, i64, SumAugment, SimFile>;
+
+    const CACHE_PAGES: usize = 4;
+    const SYNC_EVERY: usize = 3;
+
+    enum Op {
+        Insert(u32, i64),
+        Delete(u32),
+    }
+
+    fn operations() -> Vec<Op> {
+        let mut next = rng::<u64>(0x2545_f491_4f6c_dd1d);
+
+        (0..150)
+            .map(|_| {
+                let key = (next() % 80) as u32;
+                if next().is_multiple_of(3) {
+                    Op::Delete(key)
+                } else {
+                    Op::Insert(key, next() as i64 % 1000)
+                }
+            })
+            .collect()
+    }
+
+    /// The expected contents after each prefix of `ops`
+    fn model_states(ops: &[Op]) -> Vec<BTreeMap<u32, i64>> {
+        let mut model = BTreeMap::new();
+        let mut states = vec![model.clone()];
+        for op in ops {
+            match *op {
+                Op::Insert(key, value) => {
+                    model.entry(key).or_insert(value);
+                }
+                Op::Delete(key) => {
+                    model.remove(&key);
+                }
+            }
+            states.push(model.clone());
+        }
+        states
+    }
+
+    fn sim_file(disk: Disk, power: &Rc<RefCell<Power>>) -> SimFile {
+        SimFile {
+            disk: Rc::new(RefCell::new(disk)),
+            power: Rc::clone(power),
+            pos: 0,
+        }
+    }
+
+    /// Reads back every pair, checking that the augment values agree with them
+    fn contents(tree: &mut Tree) -> BTreeMap<u32, i64> {
+        let mut contents = BTreeMap::new();
+        let mut sum = 0;
+        for key in 0..80 {
+            if let Some(&value) = tree.search(&key).unwrap() {
+                contents.insert(key, value);
+                sum += value;
+            }
+            assert_eq!(tree.augment_search(&key).unwrap(), sum);
+        }
+        assert_eq!(tree.len(), contents.len() as u64);
+        contents
+    }
+
+    struct CrashRun {
+        data: Rc<RefCell<Disk>>,
+        log: Rc<RefCell<Disk>>,
+        /// Operations that returned successfully
+        completed: usize,
+        /// Operations that were started
+        attempted: usize,
+        /// Operations that had reached the disk when they returned
+        synced: usize,
+        flushed: bool,
+        writes: usize,
+    }
+
+    fn run_until_crash(ops: &[Op], crash_at: Option<usize>) -> CrashRun {
+        let power = Rc::new(RefCell::new(Power::default()));
+        let data = sim_file(Disk::default(), &power);
+        let log = sim_file(Disk::default(), &power);
+        let (data_disk, log_disk) = (Rc::clone(&data.disk), Rc::clone(&log.disk));
+
+        let mut tree = Tree::create_with_wal(data, log, CACHE_PAGES, SYNC_EVERY).unwrap();
+        *power.borrow_mut() = Power {
+            writes: 0,
+            crash_at,
+        };
+
+        let mut run = CrashRun {
+            data: data_disk,
+            log: log_disk,
+            completed: 0,
+            attempted: 0,
+            synced: 0,
+            flushed: false,
+            writes: 0,
+        };
+        for op in ops {
+            run.attempted += 1;
+            let res = match *op {
+                Op::Insert(key, value) => tree.insert(key, value).map(drop),
+                Op::Delete(key) => tree.delete(&key).map(drop),
+            };
+            if res.is_err() {
+                break;
+            }
+            run.completed += 1;
+
+            let log = run.log.borrow();
+            if log.current == log.durable {
+                run.synced = run.completed;
+            }
+        }
+        run.flushed = run.completed == ops.len() && tree.flush().is_ok();
+        run.writes = power.borrow().writes;
+        run
+    }
+
+    #[test]
+    fn recovers_from_crash_at_every_write() {
+        let ops = operations();
+        let states = model_states(&ops);
+        let total_writes = run_until_crash(&ops, None).writes;
+
+        for crash_at in 0..total_writes {
+            let run = run_until_crash(&ops, Some(crash_at));
+            // Synced operations must survive, and unsynced ones may
+            let min_ops = if run.flushed { ops.len() } else { run.synced };
+
+            for synced_only in [true, false] {
+                let image = |disk: &Rc<RefCell<Disk>>| Disk {
+                    current: if synced_only {
+                        disk.borrow().durable.clone()
+                    } else {
+                        disk.borrow().current.clone()
+                    },
+                    durable: Vec::new(),
+                };
+                let power = Rc::new(RefCell::new(Power::default()));
+                let data = sim_file(image(&run.data), &power);
+                let log = sim_file(image(&run.log), &power);
+
+                let mut tree = Tree::open_with_wal(data, log, CACHE_PAGES, SYNC_EVERY).unwrap();
+                let recovered = contents(&mut tree);
+                assert!(
+                    states[min_ops..=run.attempted].contains(&recovered),
+                    "crash at write {crash_at} (synced only: {synced_only}) recovered a state \
+                     that never existed"
+                );
+
+                // The recovered tree must keep working
+                for key in 0..80 {
+                    tree.insert(key, 1).unwrap();
+                }
+                contents(&mut tree);
+            }
+        }
+    }
+
+    #[test]
+    fn unsynced_operations_are_replayed() {
+        let power = Rc::new(RefCell::new(Power::default()));
+        let data = sim_file(Disk::default(), &power);
+        let log = sim_file(Disk::default(), &power);
+        let (data_disk, log_disk) = (Rc::clone(&data.disk), Rc::clone(&log.disk));
+
+        let mut tree = Tree::create_with_wal(data, log, 64, 1000).unwrap();
+        for key in 0..40 {
+            tree.insert(key, key as i64).unwrap();
+        }
+        tree.delete(&7).unwrap();
+        // Simulate a crash where the log writes made it to disk but the tree never checkpointed
+        drop(tree);
+
+        let copy = |disk: &Rc<RefCell<Disk>>| Disk {
+            current: disk.borrow().current.clone(),
+            durable: Vec::new(),
+        };
+        let mut tree = Tree::open_with_wal(
+            sim_file(copy(&data_disk), &power),
+            sim_file(copy(&log_disk), &power),
+            64,
+            1000,
+        )
+        .unwrap();
+
+        let expected: BTreeMap<_, _> = (0..40).filter(|&k| k != 7).map(|k| (k, k as i64)).collect();
+        assert_eq!(contents(&mut tree), expected);
+    }
+
+    #[test]
+    fn pages_freed_and_reused_before_checkpoint_survive_reopening() {
+        let power = Rc::new(RefCell::new(Power::default()));
+        let data = sim_file(Disk::default(), &power);
+        let log = sim_file(Disk::default(), &power);
+        let mut tree = Tree::create_with_wal(data, log, 64, 10_000).unwrap();
+        for key in 0..1000 {
+            tree.insert(key, 1).unwrap();
+        }
+        for key in 0..800 {
+            tree.delete(&key).unwrap();
+        }
+        for key in 1000..1700 {
+            tree.insert(key, 1).unwrap();
+        }
+        let data = tree.close().unwrap();
+
+        let log = sim_file(Disk::default(), &power);
+        let mut tree = Tree::open_with_wal(data, log, 64, 10_000).unwrap();
+        assert_eq!(tree.len(), 900);
+        for key in 800..1700 {
+            assert_eq!(tree.search(&key).unwrap(), Some(&1));
+        }
+        assert_eq!(tree.augment_search(&2000).unwrap(), 900);
+    }
+
+    #[test]
+    fn wal_can_be_added_to_existing_tree() {
+        let power = Rc::new(RefCell::new(Power::default()));
+        let mut tree = Tree::create(sim_file(Disk::default(), &power), 8).unwrap();
+        for key in 0..50 {
+            tree.insert(key, 2).unwrap();
+        }
+        let data = tree.close().unwrap();
+
+        let log = sim_file(Disk::default(), &power);
+        let mut tree = Tree::open_with_wal(data, log, 8, 1).unwrap();
+        tree.delete(&0).unwrap();
+        assert_eq!(tree.augment_search(&100).unwrap(), 98);
+    }
+}
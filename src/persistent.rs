@@ -0,0 +1,273 @@
+//! Persistent trees that share structure between versions
+//!
+//! A [`PersistentBTree`] keeps its nodes behind [`Arc`]s. Taking a
+//! [`snapshot`](PersistentBTree::snapshot) only bumps the reference count of the root, and every
+//! later modification copies the nodes on its root-to-leaf path that are still shared with another
+//! version before touching them. Old versions therefore stay valid and fully queryable, including
+//! [`augment_search`](BTree::augment_search), no matter what happens to the tree they were taken
+//! from.
+//!
+//! ```
+//! use b_tree::augments::SumAugment;
+//! use b_tree::persistent::PersistentBTree;
+//!
+//! let mut tree: PersistentBTree<u32, i64, SumAugment> = (0..100).map(|i| (i, 1)).collect();
+//! let before = tree.snapshot();
+//! tree.delete(&10);
+//!
+//! assert_eq!(tree.augment_search(&99), 99);
+//! assert_eq!(before.augment_search(&99), 100);
+//! ```
+
+use alloc::sync::Arc;
+use core::marker::PhantomData;
+use core::ops::Deref;
+
+use allocator_api2::alloc::{AllocError, Allocator, Global};
+use allocator_api2::boxed::Box;
+
+use crate::{Augment, BTree, InternalNode, LeafNode, Node};
+
+mod sealed {
+    pub trait Sealed {}
+}
+
+/// How the nodes of a [`BTree`] hold on to their children. Implemented by [`Unique`] and
+/// [`Shared`] only.
+pub trait Sharing<K, V, A: Augment<K, V>>: sealed::Sealed + Sized {
+    #[doc(hidden)]
+    type Leaf: Deref<Target = LeafNode<K, V, A>>;
+
+    #[doc(hidden)]
+    type Internal: Deref<Target = InternalNode<K, V, A, Self>>;
+
+    /// The allocator the nodes are allocated with
+    type Alloc: Allocator + Clone;
+
+    /// Fails instead of aborting if the leaf cannot be allocated
+    #[doc(hidden)]
+    fn try_wrap_leaf(node: LeafNode<K, V, A>, alloc: Self::Alloc)
+        -> Result<Self::Leaf, AllocError>;
+
+    /// Fails instead of aborting if the node cannot be allocated
+    #[doc(hidden)]
+    fn try_wrap_internal(
+        node: InternalNode<K, V, A, Self>,
+        alloc: Self::Alloc,
+    ) -> Result<Self::Internal, AllocError>;
+
+    /// Gives exclusive access to the leaf, copying it first if another version refers to it
+    #[doc(hidden)]
+    fn leaf_mut(leaf: &mut Self::Leaf) -> &mut LeafNode<K, V, A>;
+
+    /// Gives exclusive access to the node, copying it first if another version refers to it
+    #[doc(hidden)]
+    fn internal_mut(node: &mut Self::Internal) -> &mut InternalNode<K, V, A, Self>;
+
+    /// The allocator the node was allocated with
+    #[doc(hidden)]
+    fn alloc(node: &Node<K, V, A, Self>) -> Self::Alloc;
+
+    /// Clones the pointer to the node, copying the node itself only if it cannot be shared
+    #[doc(hidden)]
+    fn share(node: &Node<K, V, A, Self>) -> Node<K, V, A, Self>
+    where
+        K: Clone,
+        V: Clone,
+        A::Value: Clone;
+}
+
+/// Every node is owned by its parent. This is the default for [`BTree`], and cloning such a tree
+/// copies all of it.
+///
+/// The nodes are boxed and allocated with `Al`, see [`BTree::new_in`].
+pub struct Unique<Al = Global>(PhantomData<Al>);
+
+/// Nodes are reference counted and shared between versions of the tree. See [`PersistentBTree`].
+///
+/// The nodes are allocated with the global allocator.
+pub struct Shared;
+
+impl<Al> sealed::Sealed for Unique<Al> {}
+
+impl sealed::Sealed for Shared {}
+
+impl<K, V, A: Augment<K, V>, Al: Allocator + Clone> Sharing<K, V, A> for Unique<Al> {
+    type Leaf = Box<LeafNode<K, V, A>, Al>;
+    type Internal = Box<InternalNode<K, V, A, Self>, Al>;
+    type Alloc = Al;
+
+    fn try_wrap_leaf(node: LeafNode<K, V, A>, alloc: Al) -> Result<Self::Leaf, AllocError> {
+        Box::try_new_in(node, alloc)
+    }
+
+    fn try_wrap_internal(
+        node: InternalNode<K, V, A, Self>,
+        alloc: Al,
+    ) -> Result<Self::Internal, AllocError> {
+        Box::try_new_in(node, alloc)
+    }
+
+    fn leaf_mut(leaf: &mut Self::Leaf) -> &mut LeafNode<K, V, A> {
+        leaf
+    }
+
+    fn internal_mut(node: &mut Self::Internal) -> &mut InternalNode<K, V, A, Self> {
+        node
+    }
+
+    fn alloc(node: &Node<K, V, A, Self>) -> Al {
+        match node {
+            Node::Leaf(leaf) => Box::allocator(leaf).clone(),
+            Node::Internal(node) => Box::allocator(node).clone(),
+        }
+    }
+
+    fn share(node: &Node<K, V, A, Self>) -> Node<K, V, A, Self>
+    where
+        K: Clone,
+        V: Clone,
+        A::Value: Clone,
+    {
+        match node {
+            Node::Leaf(leaf) => {
+                Node::Leaf(Box::new_in((**leaf).clone(), Box::allocator(leaf).clone()))
+            }
+            Node::Internal(node) => {
+                Node::Internal(Box::new_in((**node).clone(), Box::allocator(node).clone()))
+            }
+        }
+    }
+}
+
+impl<K: Clone, V: Clone, A: Augment<K, V>> Sharing<K, V, A> for Shared
+where
+    A::Value: Clone,
+{
+    type Leaf = Arc<LeafNode<K, V, A>>;
+    type Internal = Arc<InternalNode<K, V, A, Self>>;
+    type Alloc = Global;
+
+    fn try_wrap_leaf(node: LeafNode<K, V, A>, _: Global) -> Result<Self::Leaf, AllocError> {
+        Ok(Arc::new(node))
+    }
+
+    fn try_wrap_internal(
+        node: InternalNode<K, V, A, Self>,
+        _: Global,
+    ) -> Result<Self::Internal, AllocError> {
+        Ok(Arc::new(node))
+    }
+
+    fn leaf_mut(leaf: &mut Self::Leaf) -> &mut LeafNode<K, V, A> {
+        Arc::make_mut(leaf)
+    }
+
+    fn internal_mut(node: &mut Self::Internal) -> &mut InternalNode<K, V, A, Self> {
+        Arc::make_mut(node)
+    }
+
+    fn alloc(_: &Node<K, V, A, Self>) -> Global {
+        Global
+    }
+
+    fn share(node: &Node<K, V, A, Self>) -> Node<K, V, A, Self> {
+        match node {
+            Node::Leaf(leaf) => Node::Leaf(Arc::clone(leaf)),
+            Node::Internal(node) => Node::Internal(Arc::clone(node)),
+        }
+    }
+}
+
+/// A [`BTree`] whose versions share their unmodified nodes
+///
+/// Keys, values and augment values must be [`Clone`], as a node that is shared with a snapshot is
+/// copied the first time it is modified.
+pub type PersistentBTree<K, V, A = ()> = BTree<K, V, A, Shared>;
+
+impl<K: Ord + Clone, V: Clone, A: Augment<K, V>> PersistentBTree<K, V, A>
+where
+    A::Value: Clone,
+{
+    /// Returns the current version of the tree in constant time. The snapshot is not affected by
+    /// later modifications of `self`, nor `self` by modifications of the snapshot.
+    pub fn snapshot(&self) -> Self {
+        self.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+    use std::ptr;
+    use std::string::{String, ToString};
+    use std::vec::Vec;
+
+    use crate::augments::SumAugment;
+    use crate::persistent::PersistentBTree;
+    use crate::tests::{check_tree, rng};
+    use crate::BTree;
+
+    #[test]
+    fn snapshots_survive_later_modifications() {
+        let mut tree = PersistentBTree::<u32, i64, SumAugment>::default();
+        let mut model = BTreeMap::new();
+        let mut versions = Vec::new();
+
+        let mut next = rng::<u32>(0x2545_f491);
+        for step in 0..3000 {
+            let state = next();
+            let key = state % 500;
+            if state.is_multiple_of(3) {
+                assert_eq!(tree.delete(&key), model.remove(&key));
+            } else {
+                let value = i64::from(state % 100);
+                assert_eq!(tree.insert(key, value), !model.contains_key(&key));
+                model.entry(key).or_insert(value);
+            }
+
+            if step % 250 == 0 {
+                versions.push((tree.snapshot(), model.clone()));
+            }
+        }
+        versions.push((tree, model));
+
+        for (tree, model) in &versions {
+            check_tree(tree);
+            assert!(tree.iter().eq(model.iter()));
+            for key in (0..500).step_by(7) {
+                let expected: i64 = model.range(..=key).map(|(_, v)| v).sum();
+                assert_eq!(tree.augment_search(&key), expected);
+            }
+        }
+    }
+
+    #[test]
+    fn modifications_copy_only_their_path() {
+        let mut tree: PersistentBTree<u32, ()> = (0..10_000).map(|i| (i, ())).collect();
+        let snapshot = tree.snapshot();
+        assert!(ptr::eq(&**tree.root, &**snapshot.root));
+
+        tree.insert(10_000, ());
+        assert!(!ptr::eq(&**tree.root, &**snapshot.root));
+        let (old, new) = (snapshot.root.children(), tree.root.children());
+        let shared = old.iter().zip(new).filter(|&(a, b)| ptr::eq(&**a, &**b));
+        assert_eq!(shared.count(), old.len() - 1);
+        assert_eq!(snapshot.len(), 10_000);
+        assert!(snapshot.search(&10_000).is_none());
+    }
+
+    #[test]
+    fn cloning_unique_tree_copies_it() {
+        let mut tree: BTree<u32, String> = (0..1000).map(|i| (i, i.to_string())).collect();
+        let copy = tree.clone();
+        for i in 0..500 {
+            tree.delete(&i);
+        }
+
+        check_tree(&copy);
+        assert_eq!(copy.len(), 1000);
+        assert_eq!(copy.search(&0).map(String::as_str), Some("0"));
+        assert!(tree.search(&0).is_none());
+    }
+}
@@ -0,0 +1,228 @@
+//! A double-ended priority queue
+//!
+//! [`BTreePriorityQueue`] stores every element as a pair in a [`BTree`], keyed by its priority
+//! along with a sequence number that grows with every push. Elements of equal priority are
+//! therefore popped from the front in the order they were pushed, and from the back in reverse.
+//! The sequence number doubles as the [`Handle`] of the element, and a second tree maps handles to
+//! priorities so that [`decrease_key`](BTreePriorityQueue::decrease_key) can find the element.
+//!
+//! ```
+//! use b_tree::augments::SumAugment;
+//! use b_tree::priority_queue::BTreePriorityQueue;
+//!
+//! let mut jobs = BTreePriorityQueue::with_augment::<SumAugment>();
+//! jobs.push(30, 4);
+//! let backup = jobs.push(50, 2);
+//! jobs.push(10, 1);
+//! jobs.decrease_key(backup, 5);
+//!
+//! assert_eq!(jobs.augment_search(&10), 3);
+//! assert_eq!(jobs.pop_min(), Some((5, 2)));
+//! assert_eq!(jobs.pop_max(), Some((30, 4)));
+//! assert_eq!(jobs.peek(), Some((&10, &1)));
+//! ```
+
+use crate::{Augment, BTree};
+
+/// Identifies an element of a [`BTreePriorityQueue`], returned by [`BTreePriorityQueue::push`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Handle(u64);
+
+/// A queue of elements ordered by priority that can be popped from both ends, see the
+/// [module documentation](self)
+///
+/// The augment `A` sees every element as a pair keyed by its priority and sequence number.
+pub struct BTreePriorityQueue<P, T, A = ()>
+where
+    A: Augment<(P, u64), T>,
+    A::Value: 'static,
+{
+    tree: BTree<(P, u64), T, A>,
+    /// The priority of every element in the queue, by the sequence number in its handle
+    priorities: BTree<u64, P>,
+    next_seq: u64,
+}
+
+impl<P: Ord + Clone, T> BTreePriorityQueue<P, T> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_augment<B: Augment<(P, u64), T>>() -> BTreePriorityQueue<P, T, B>
+    where
+        B::Value: 'static,
+    {
+        BTreePriorityQueue::default()
+    }
+}
+
+impl<P: Ord + Clone, T, A: Augment<(P, u64), T>> BTreePriorityQueue<P, T, A>
+where
+    A::Value: 'static,
+{
+    /// Adds `item` with priority `priority`, returning a handle to it
+    pub fn push(&mut self, priority: P, item: T) -> Handle {
+        let seq = self.next_seq;
+        self.next_seq += 1;
+        self.priorities.insert(seq, priority.clone());
+        self.tree.insert((priority, seq), item);
+        Handle(seq)
+    }
+
+    /// Returns the element with the smallest priority, pushed first among equals
+    pub fn peek(&self) -> Option<(&P, &T)> {
+        self.tree
+            .first_key_value()
+            .map(|((priority, _), item)| (priority, item))
+    }
+
+    /// Returns the element with the largest priority, pushed last among equals
+    pub fn peek_max(&self) -> Option<(&P, &T)> {
+        self.tree
+            .last_key_value()
+            .map(|((priority, _), item)| (priority, item))
+    }
+
+    /// Removes and returns the element returned by [`BTreePriorityQueue::peek`]
+    pub fn pop_min(&mut self) -> Option<(P, T)> {
+        let ((priority, seq), item) = self.tree.pop_first()?;
+        self.priorities.delete(&seq);
+        Some((priority, item))
+    }
+
+    /// Removes and returns the element returned by [`BTreePriorityQueue::peek_max`]
+    pub fn pop_max(&mut self) -> Option<(P, T)> {
+        let ((priority, seq), item) = self.tree.pop_last()?;
+        self.priorities.delete(&seq);
+        Some((priority, item))
+    }
+
+    /// Lowers the priority of the element of `handle` to `priority`. Returns `false` if the
+    /// element has already been popped.
+    ///
+    /// # Panics
+    /// Panics if `priority` is larger than the current priority of the element
+    pub fn decrease_key(&mut self, handle: Handle, priority: P) -> bool {
+        let Some(old) = self.priorities.search(&handle.0) else {
+            return false;
+        };
+        assert!(
+            priority <= *old,
+            "`decrease_key` must not increase the priority"
+        );
+
+        let old = self.priorities.delete(&handle.0).unwrap();
+        let item = self.tree.delete(&(old, handle.0)).unwrap();
+        self.priorities.insert(handle.0, priority.clone());
+        self.tree.insert((priority, handle.0), item);
+        true
+    }
+
+    /// Returns the priority of the element of `handle`, unless it has been popped
+    pub fn priority(&self, handle: Handle) -> Option<&P> {
+        self.priorities.search(&handle.0)
+    }
+
+    /// Returns the output of the augment for all elements with priorities smaller than or equal
+    /// to `priority`
+    pub fn augment_search(&self, priority: &P) -> A::Output {
+        self.tree.augment_search(&(priority.clone(), u64::MAX))
+    }
+
+    /// Returns the number of elements in the queue
+    pub fn len(&self) -> usize {
+        self.tree.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tree.is_empty()
+    }
+}
+
+impl<P: Ord + Clone, T, A: Augment<(P, u64), T>> Default for BTreePriorityQueue<P, T, A>
+where
+    A::Value: 'static,
+{
+    fn default() -> Self {
+        Self {
+            tree: BTree::default(),
+            priorities: BTree::default(),
+            next_seq: 0,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+    use std::vec::Vec;
+
+    use crate::augments::SumAugment;
+    use crate::priority_queue::BTreePriorityQueue;
+    use crate::tests::{check_tree, rng};
+
+    #[test]
+    fn random_operations_match_model() {
+        let mut queue = BTreePriorityQueue::with_augment::<SumAugment>();
+        let mut model = BTreeMap::new();
+        let mut handles = Vec::new();
+
+        let mut next = rng::<u32>(0x00de_c0de);
+        for seq in 0..5000 {
+            match next() % 8 {
+                0 => {
+                    let popped = model.pop_first().map(|((p, _), v)| (p, v));
+                    assert_eq!(queue.pop_min(), popped);
+                }
+                1 => {
+                    let popped = model.pop_last().map(|((p, _), v)| (p, v));
+                    assert_eq!(queue.pop_max(), popped);
+                }
+                2 | 3 if !handles.is_empty() => {
+                    let (handle, seq) = handles[next() as usize % handles.len()];
+                    let old = model.keys().find(|&&(_, s)| s == seq).map(|&(p, _)| p);
+                    assert_eq!(queue.priority(handle), old.as_ref());
+
+                    let priority = old.map_or(0, |old: u32| old - old.min(next() % 100));
+                    assert_eq!(queue.decrease_key(handle, priority), old.is_some());
+                    if let Some(old) = old {
+                        let value = model.remove(&(old, seq)).unwrap();
+                        model.insert((priority, seq), value);
+                    }
+                }
+                _ => {
+                    let (priority, value) = (next() % 1000, i64::from(next() % 100));
+                    handles.push((queue.push(priority, value), seq));
+                    model.insert((priority, seq), value);
+                }
+            }
+
+            assert_eq!(queue.len(), model.len());
+            let first = model.first_key_value().map(|((p, _), v)| (p, v));
+            assert_eq!(queue.peek(), first);
+            let last = model.last_key_value().map(|((p, _), v)| (p, v));
+            assert_eq!(queue.peek_max(), last);
+            if seq % 500 == 0 {
+                check_tree(&queue.tree);
+                let limit = next() % 1000;
+                let sum: i64 = model.range(..=(limit, u64::MAX)).map(|(_, v)| v).sum();
+                assert_eq!(queue.augment_search(&limit), sum);
+            }
+        }
+    }
+
+    #[test]
+    fn failed_decrease_key_keeps_element() {
+        let mut queue = BTreePriorityQueue::new();
+        let handle = queue.push(5, 'a');
+
+        let res = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            queue.decrease_key(handle, 7);
+        }));
+        assert!(res.is_err());
+        assert_eq!(queue.priority(handle), Some(&5));
+        assert!(queue.decrease_key(handle, 3));
+        assert_eq!(queue.pop_min(), Some((3, 'a')));
+        assert!(queue.is_empty());
+    }
+}
@@ -0,0 +1,291 @@
+//! A map from non-overlapping key ranges to values
+//!
+//! [`RangeMap`] stores every range as a pair in a [`BTree`] with an [`IntervalAugment`], keyed by
+//! the start of the range. Inserting a range overwrites the parts of the ranges it overlaps, and
+//! ranges that touch and carry equal values are merged, so the map always holds the fewest ranges
+//! possible.
+//!
+//! ```
+//! use b_tree::range_map::RangeMap;
+//!
+//! let mut owners = RangeMap::new();
+//! owners.insert(0..100, "kernel");
+//! owners.insert(100..200, "kernel");
+//! owners.insert(50..60, "driver");
+//!
+//! assert_eq!(owners.get(&55), Some(&"driver"));
+//! assert_eq!(owners.len(), 3);
+//! assert!(owners.gaps(0..300).eq([200..300]));
+//! ```
+
+use alloc::vec::Vec;
+use core::ops::{Range, RangeBounds};
+
+use crate::augments::{self, IntervalAugment};
+use crate::BTree;
+
+/// A map from non-overlapping half-open key ranges to values. See the
+/// [module documentation](self) for details.
+pub struct RangeMap<K: Ord + Clone + 'static, V> {
+    /// Maps the start of every range to its end and value
+    tree: BTree<K, (K, V), IntervalAugment>,
+}
+
+impl<K: Ord + Clone + 'static, V> RangeMap<K, V> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns the value of the range containing `point`
+    pub fn get(&self, point: &K) -> Option<&V> {
+        self.tree
+            .stabbing(point)
+            .next()
+            .map(|(_, (_, value))| value)
+    }
+
+    /// Returns an iterator over the ranges that overlap `range` and their values, in ascending
+    /// order
+    pub fn overlapping(&self, range: impl RangeBounds<K>) -> Ranges<'_, K, V> {
+        Ranges(self.tree.overlapping(range))
+    }
+
+    /// Returns an iterator over the ranges in ascending order, along with their values
+    pub fn iter(&self) -> Ranges<'_, K, V> {
+        self.overlapping(..)
+    }
+
+    /// Returns an iterator over the parts of `range` that are not covered by any range of the map,
+    /// in ascending order
+    pub fn gaps(&self, range: Range<K>) -> Gaps<'_, K, V> {
+        Gaps {
+            ranges: self.tree.overlapping(range.clone()),
+            remaining: Some(range),
+        }
+    }
+
+    /// Returns the number of ranges in the map
+    pub fn len(&self) -> usize {
+        self.tree.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tree.is_empty()
+    }
+}
+
+impl<K: Ord + Clone + 'static, V: Clone + PartialEq> RangeMap<K, V> {
+    /// Maps every key in `range` to `value`
+    ///
+    /// The parts of existing ranges that lie outside of `range` keep their values, which is why a
+    /// range that sticks out on both sides has its value cloned. Afterwards `range` is merged with
+    /// the ranges that touch it if their values are equal to `value`. Empty ranges are ignored.
+    pub fn insert(&mut self, range: Range<K>, value: V) {
+        if range.is_empty() {
+            return;
+        }
+
+        let starts: Vec<_> = self
+            .tree
+            .overlapping(range.clone())
+            .map(|(start, _)| start.clone())
+            .collect();
+        let mut removed = Vec::with_capacity(starts.len());
+        for start in starts {
+            let (end, value) = self.tree.delete(&start).unwrap();
+            removed.push((start, end, value));
+        }
+        // Only the first and the last of them can stick out of `range`
+        let last = if removed.len() > 1 {
+            removed.pop()
+        } else {
+            None
+        };
+        let first = removed.into_iter().next();
+
+        // What is left of the ranges that stick out on either side of `range`
+        let (mut left, mut right) = (None, None);
+        match (first, last) {
+            (Some((start, end, old)), None) => match (start < range.start, end > range.end) {
+                (true, true) => {
+                    right = Some((range.end.clone(), end, old.clone()));
+                    left = Some((start, range.start.clone(), old));
+                }
+                (true, false) => left = Some((start, range.start.clone(), old)),
+                (false, true) => right = Some((range.end.clone(), end, old)),
+                (false, false) => {}
+            },
+            (first, last) => {
+                left = first.filter(|(start, _, _)| *start < range.start);
+                right = last.filter(|(_, end, _)| *end > range.end);
+                if let Some(left) = &mut left {
+                    left.1 = range.start.clone();
+                }
+                if let Some(right) = &mut right {
+                    right.0 = range.end.clone();
+                }
+            }
+        }
+
+        // Ranges that only touch `range` are not overlapping it, so look for them separately
+        if left.is_none() {
+            let touching = self.tree.predecessor(&range.start);
+            if touching.is_some_and(|(_, (end, old))| *end == range.start && *old == value) {
+                let start = touching.unwrap().0.clone();
+                let (end, old) = self.tree.delete(&start).unwrap();
+                left = Some((start, end, old));
+            }
+        }
+        if right.is_none()
+            && self
+                .tree
+                .search(&range.end)
+                .is_some_and(|(_, old)| *old == value)
+        {
+            let (end, old) = self.tree.delete(&range.end).unwrap();
+            right = Some((range.end.clone(), end, old));
+        }
+
+        let Range { mut start, mut end } = range;
+        if let Some((left_start, left_end, old)) = left {
+            if old == value {
+                start = left_start;
+            } else {
+                self.tree.insert(left_start, (left_end, old));
+            }
+        }
+        if let Some((right_start, right_end, old)) = right {
+            if old == value {
+                end = right_end;
+            } else {
+                self.tree.insert(right_start, (right_end, old));
+            }
+        }
+        self.tree.insert(start, (end, value));
+    }
+}
+
+impl<K: Ord + Clone + 'static, V> Default for RangeMap<K, V> {
+    fn default() -> Self {
+        Self {
+            tree: BTree::default(),
+        }
+    }
+}
+
+/// An iterator over ranges of a [`RangeMap`] and their values, created by [`RangeMap::iter`] and
+/// [`RangeMap::overlapping`]
+pub struct Ranges<'a, K: Ord + Clone + 'static, V>(augments::Overlapping<'a, K, (K, V)>);
+
+impl<'a, K: Ord + Clone + 'static, V> Iterator for Ranges<'a, K, V> {
+    type Item = (Range<&'a K>, &'a V);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.0
+            .next()
+            .map(|(start, (end, value))| (start..end, value))
+    }
+}
+
+/// An iterator over the parts of a range that are not covered by a [`RangeMap`], created by
+/// [`RangeMap::gaps`]
+pub struct Gaps<'a, K: Ord + Clone + 'static, V> {
+    ranges: augments::Overlapping<'a, K, (K, V)>,
+    /// The part of the range that comes after the ranges returned by `ranges` so far
+    remaining: Option<Range<K>>,
+}
+
+impl<K: Ord + Clone + 'static, V> Iterator for Gaps<'_, K, V> {
+    type Item = Range<K>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let remaining = self.remaining.as_mut()?;
+        for (start, (end, _)) in self.ranges.by_ref() {
+            let gap = remaining.start.clone()..start.clone();
+            remaining.start = end.clone().max(remaining.start.clone());
+            if !gap.is_empty() {
+                return Some(gap);
+            }
+        }
+        self.remaining.take().filter(|gap| !gap.is_empty())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ops::Range;
+    use std::vec::Vec;
+
+    use crate::range_map::RangeMap;
+    use crate::tests::rng;
+
+    const SIZE: u32 = 300;
+
+    /// Checks the map against `model`, which holds the value of every point, and checks that no
+    /// two touching ranges carry the same value
+    fn check_map(map: &RangeMap<u32, u8>, model: &[Option<u8>]) {
+        for (point, expected) in model.iter().enumerate() {
+            assert_eq!(map.get(&(point as u32)), expected.as_ref());
+        }
+
+        let ranges: Vec<_> = map.iter().collect();
+        assert_eq!(ranges.len(), map.len());
+        for pair in ranges.windows(2) {
+            let ((left, left_value), (right, right_value)) = (&pair[0], &pair[1]);
+            assert!(left.end <= right.start);
+            assert!(left.end < right.start || left_value != right_value);
+        }
+
+        let mut expected_gaps: Vec<Range<u32>> = Vec::new();
+        for point in 0..SIZE {
+            if model[point as usize].is_none() {
+                match expected_gaps.last_mut() {
+                    Some(gap) if gap.end == point => gap.end += 1,
+                    _ => expected_gaps.push(point..point + 1),
+                }
+            }
+        }
+        assert!(map.gaps(0..SIZE).eq(expected_gaps));
+    }
+
+    #[test]
+    fn random_inserts_match_model() {
+        let mut map = RangeMap::new();
+        let mut model = vec![None; SIZE as usize];
+
+        let mut next = rng::<u32>(0xdead_beef);
+        for step in 0..3000 {
+            let start = next() % SIZE;
+            let end = (start + next() % 40).min(SIZE);
+            let value = (next() % 3) as u8;
+            map.insert(start..end, value);
+            model[start as usize..end as usize].fill(Some(value));
+
+            if step % 50 == 0 {
+                check_map(&map, &model);
+            }
+        }
+        check_map(&map, &model);
+    }
+
+    #[test]
+    fn overlapping_returns_touched_ranges() {
+        let mut map = RangeMap::new();
+        map.insert(10..20, 'a');
+        map.insert(20..30, 'b');
+        map.insert(40..50, 'a');
+        map.insert(15..45, 'c');
+
+        let ranges: Vec<_> = map
+            .overlapping(12..46)
+            .map(|(range, &value)| (*range.start..*range.end, value))
+            .collect();
+        assert_eq!(ranges, [(10..15, 'a'), (15..45, 'c'), (45..50, 'a')]);
+        assert!(map.overlapping(50..60).next().is_none());
+        assert!(map.gaps(0..100).eq([0..10, 50..100]));
+
+        map.insert(15..45, 'a');
+        assert_eq!(map.len(), 1);
+        assert!(map.gaps(5..55).eq([5..10, 50..55]));
+    }
+}
@@ -0,0 +1,481 @@
+//! Parallel iteration and bulk operations, using [rayon](https://docs.rs/rayon)
+//!
+//! The parallel iterators split their work along the structure of the tree. A part of a node is
+//! split into its first and second half, counting children and pairs alike, and a part that
+//! consists of a single child is split by descending into that child. Only the subtrees at the
+//! ends of a [`BTree::par_range`] have their keys compared to the bounds.
+//!
+//! ```
+//! use b_tree::augments::SumAugment;
+//! use b_tree::BTree;
+//! use rayon::prelude::*;
+//!
+//! let mut tree: BTree<u32, u64, SumAugment> = (0..1000).into_par_iter().map(|i| (i, 1)).collect();
+//! tree.par_extend((1000..2000).into_par_iter().map(|i| (i, 2)));
+//!
+//! assert_eq!(tree.par_iter().map(|(_, v)| v).sum::<u64>(), 3000);
+//! assert_eq!(tree.par_range(500..1500).count(), 1000);
+//! assert_eq!(tree.augment_search(&1999), 3000);
+//! ```
+
+use std::mem;
+use std::ops::{Bound, RangeBounds, RangeFull};
+
+use rayon::iter::plumbing::{bridge_unindexed, Folder, UnindexedConsumer, UnindexedProducer};
+use rayon::prelude::*;
+
+use crate::persistent::{Sharing, Unique};
+use crate::{Augment, BTree, Node};
+
+/// A parallel iterator over the pairs of a [`BTree`] in ascending key order, created by
+/// [`BTree::par_iter`]
+pub struct ParIter<'a, K, V, A: Augment<K, V> = (), S: Sharing<K, V, A> = Unique> {
+    range: ParRange<'a, K, V, RangeFull, A, S>,
+}
+
+/// A parallel iterator over the pairs of a [`BTree`] with keys in a range, in ascending key order,
+/// created by [`BTree::par_range`]
+pub struct ParRange<'a, K, V, R, A: Augment<K, V> = (), S: Sharing<K, V, A> = Unique> {
+    root: &'a Node<K, V, A, S>,
+    range: R,
+}
+
+/// A part of a subtree that is yet to be iterated over. Pairs are borrowed for `'a`, and the bounds
+/// of the range for `'b`.
+enum Producer<'a, 'b, K, V, A: Augment<K, V>, S: Sharing<K, V, A>> {
+    /// Slots `lo..hi` of `node`, where slot `2 * i` is child `i` and slot `2 * i + 1` is pair `i`.
+    /// The bounds only apply to the children in the first and last slots, as every other slot is
+    /// known to lie inside the range.
+    Slots {
+        node: &'a Node<K, V, A, S>,
+        lo: usize,
+        hi: usize,
+        start: Bound<&'b K>,
+        end: Bound<&'b K>,
+    },
+    Pairs(&'a [(K, V)]),
+}
+
+impl<'a, 'b, K: Ord, V, A: Augment<K, V>, S: Sharing<K, V, A>> Producer<'a, 'b, K, V, A, S> {
+    /// Covers the pairs of the subtree of `node` that lie within the bounds
+    fn new(node: &'a Node<K, V, A, S>, start: Bound<&'b K>, end: Bound<&'b K>) -> Self {
+        node.check_pending();
+        let (first, last) = node.bounds_idx(start, end);
+        if node.is_leaf() {
+            Self::Pairs(&node.pairs()[first..last])
+        } else {
+            Self::Slots {
+                node,
+                lo: 2 * first,
+                hi: 2 * last + 1,
+                start,
+                end,
+            }
+        }
+    }
+
+    /// Covers slot `idx` of `node`, applying the bounds if it is a child
+    fn slot(
+        node: &'a Node<K, V, A, S>,
+        idx: usize,
+        start: Bound<&'b K>,
+        end: Bound<&'b K>,
+    ) -> Self {
+        if idx.is_multiple_of(2) {
+            Self::new(&node.children()[idx / 2], start, end)
+        } else {
+            Self::Pairs(&node.pairs()[idx / 2..idx / 2 + 1])
+        }
+    }
+}
+
+impl<'a, 'b, K, V, A, S> UnindexedProducer for Producer<'a, 'b, K, V, A, S>
+where
+    K: Ord + Sync,
+    V: Sync,
+    A: Augment<K, V>,
+    A::Value: Sync,
+    S: Sharing<K, V, A>,
+    S::Leaf: Sync,
+    S::Internal: Sync,
+    S::Alloc: Sync,
+{
+    type Item = (&'a K, &'a V);
+
+    fn split(self) -> (Self, Option<Self>) {
+        match self {
+            Self::Slots {
+                node,
+                lo,
+                hi,
+                start,
+                end,
+            } if hi - lo == 1 => Self::slot(node, lo, start, end).split(),
+            Self::Slots {
+                node,
+                lo,
+                hi,
+                start,
+                end,
+            } => {
+                let mid = (lo + hi) / 2;
+                let left = Self::Slots {
+                    node,
+                    lo,
+                    hi: mid,
+                    start,
+                    end: Bound::Unbounded,
+                };
+                let right = Self::Slots {
+                    node,
+                    lo: mid,
+                    hi,
+                    start: Bound::Unbounded,
+                    end,
+                };
+                (left, Some(right))
+            }
+            // Leaves are too small to be worth splitting
+            Self::Pairs(_) => (self, None),
+        }
+    }
+
+    fn fold_with<F: Folder<Self::Item>>(self, mut folder: F) -> F {
+        match self {
+            Self::Slots {
+                node,
+                lo,
+                hi,
+                start,
+                end,
+            } => {
+                for idx in lo..hi {
+                    if folder.full() {
+                        break;
+                    }
+                    let start = if idx == lo { start } else { Bound::Unbounded };
+                    let end = if idx == hi - 1 { end } else { Bound::Unbounded };
+                    folder = Self::slot(node, idx, start, end).fold_with(folder);
+                }
+                folder
+            }
+            Self::Pairs(pairs) => folder.consume_iter(pairs.iter().map(|(k, v)| (k, v))),
+        }
+    }
+}
+
+impl<'a, K, V, A, S> ParallelIterator for ParIter<'a, K, V, A, S>
+where
+    K: Ord + Sync,
+    V: Sync,
+    A: Augment<K, V>,
+    A::Value: Sync,
+    S: Sharing<K, V, A>,
+    S::Leaf: Sync,
+    S::Internal: Sync,
+    S::Alloc: Sync,
+{
+    type Item = (&'a K, &'a V);
+
+    fn drive_unindexed<C: UnindexedConsumer<Self::Item>>(self, consumer: C) -> C::Result {
+        self.range.drive_unindexed(consumer)
+    }
+}
+
+impl<'a, K, V, R, A, S> ParallelIterator for ParRange<'a, K, V, R, A, S>
+where
+    K: Ord + Sync,
+    V: Sync,
+    R: RangeBounds<K> + Send,
+    A: Augment<K, V>,
+    A::Value: Sync,
+    S: Sharing<K, V, A>,
+    S::Leaf: Sync,
+    S::Internal: Sync,
+    S::Alloc: Sync,
+{
+    type Item = (&'a K, &'a V);
+
+    fn drive_unindexed<C: UnindexedConsumer<Self::Item>>(self, consumer: C) -> C::Result {
+        let range = self.range;
+        let producer = Producer::new(self.root, range.start_bound(), range.end_bound());
+        bridge_unindexed(producer, consumer)
+    }
+}
+
+impl<K, V, A, S> BTree<K, V, A, S>
+where
+    K: Ord + Sync,
+    V: Sync,
+    A: Augment<K, V>,
+    A::Value: Sync,
+    S: Sharing<K, V, A>,
+    S::Leaf: Sync,
+    S::Internal: Sync,
+    S::Alloc: Sync,
+{
+    /// Returns a parallel iterator over the pairs of the tree in ascending key order
+    pub fn par_iter(&self) -> ParIter<'_, K, V, A, S> {
+        ParIter {
+            range: self.par_range(..),
+        }
+    }
+
+    /// Returns a parallel iterator over the pairs of the tree with keys in `range`, in ascending
+    /// key order. The range is empty if it ends before it starts.
+    pub fn par_range<R: RangeBounds<K> + Send>(&self, range: R) -> ParRange<'_, K, V, R, A, S> {
+        self.check_poison();
+        ParRange {
+            root: &self.root,
+            range,
+        }
+    }
+}
+
+impl<'a, K, V, A, S> IntoParallelIterator for &'a BTree<K, V, A, S>
+where
+    K: Ord + Sync,
+    V: Sync,
+    A: Augment<K, V>,
+    A::Value: Sync,
+    S: Sharing<K, V, A>,
+    S::Leaf: Sync,
+    S::Internal: Sync,
+    S::Alloc: Sync,
+{
+    type Item = (&'a K, &'a V);
+    type Iter = ParIter<'a, K, V, A, S>;
+
+    fn into_par_iter(self) -> Self::Iter {
+        self.par_iter()
+    }
+}
+
+impl<K, V, A, S> Node<K, V, A, S>
+where
+    K: Ord + Send,
+    V: Send,
+    A: Augment<K, V>,
+    A::Value: Send,
+    S: Sharing<K, V, A>,
+    S::Leaf: Send,
+    S::Internal: Send,
+    S::Alloc: Send,
+{
+    /// Recomputes the augment values of the subtree bottom-up, handling the children in parallel
+    fn par_recompute_aug_val(&mut self) {
+        self.push_down();
+        if !self.is_leaf() {
+            let recompute = |child: &mut Self| child.par_recompute_aug_val();
+            // Leaves are too small to be worth a task of their own
+            if self.children()[0].is_leaf() {
+                self.children_mut().iter_mut().for_each(recompute);
+            } else {
+                self.children_mut().par_iter_mut().for_each(recompute);
+            }
+        }
+        self.recompute_aug_val();
+    }
+}
+
+impl<K, V, A, S> BTree<K, V, A, S>
+where
+    K: Ord + Send,
+    V: Send,
+    A: Augment<K, V>,
+    A::Value: Send,
+    S: Sharing<K, V, A>,
+    S::Leaf: Send,
+    S::Internal: Send,
+    S::Alloc: Send,
+{
+    /// Recomputes every augment value in the tree from scratch, in parallel
+    ///
+    /// Modifications keep the augment values up to date, so this is only needed for augments whose
+    /// hooks depend on outside state that has changed. Nodes that are
+    /// [`Shared`](crate::persistent::Shared) with snapshots are copied first.
+    pub fn par_recompute_augments(&mut self) {
+        self.poison_on_unwind(|tree| tree.root_mut().par_recompute_aug_val());
+    }
+}
+
+/// Merges two lists of pairs that are sorted by key and free of duplicates. Keys that are in both
+/// keep their pair from `old`.
+fn merge<K: Ord, V>(old: Vec<(K, V)>, new: Vec<(K, V)>) -> Vec<(K, V)> {
+    let mut merged = Vec::with_capacity(old.len() + new.len());
+    let mut new = new.into_iter().peekable();
+    for pair in old {
+        while let Some(next) = new.next_if(|(k, _)| *k < pair.0) {
+            merged.push(next);
+        }
+        new.next_if(|(k, _)| *k == pair.0);
+        merged.push(pair);
+    }
+    merged.extend(new);
+    merged
+}
+
+/// Collects and sorts the pairs in parallel. As with [`BTree::insert`], only the first pair with a
+/// given key is kept, and keys that are already in the tree keep their value.
+///
+/// Unless the new pairs are few compared to the size of the tree, the tree is rebuilt by bulk
+/// loading the old and new pairs together and computing the augment values in parallel.
+impl<K, V, A, S> ParallelExtend<(K, V)> for BTree<K, V, A, S>
+where
+    K: Ord + Send,
+    V: Send,
+    A: Augment<K, V>,
+    A::Value: Send,
+    S: Sharing<K, V, A>,
+    S::Leaf: Send,
+    S::Internal: Send,
+    S::Alloc: Send,
+{
+    fn par_extend<I: IntoParallelIterator<Item = (K, V)>>(&mut self, par_iter: I) {
+        self.check_poison();
+        let mut pairs: Vec<_> = par_iter.into_par_iter().collect();
+        // The sort is stable, so the first occurrence of each key is the one that survives
+        pairs.par_sort_by(|(a, _), (b, _)| a.cmp(b));
+        pairs.dedup_by(|(a, _), (b, _)| a == b);
+
+        // Inserting costs `O(log n)` per pair and rebuilding `O(n)` in total
+        if pairs.len() < self.len / 8 {
+            for (key, value) in pairs {
+                self.insert(key, value);
+            }
+            return;
+        }
+
+        self.poison_on_unwind(|tree| {
+            let mut old = Vec::with_capacity(tree.len);
+            let root = tree.take_root();
+            let alloc = root.alloc();
+            tree.len = 0;
+            root.into_pairs(&mut old);
+
+            let mut rebuilt = Self::from_sorted(merge(old, pairs), false, alloc);
+            rebuilt.root_mut().par_recompute_aug_val();
+            mem::swap(&mut tree.root, &mut rebuilt.root);
+            tree.len = rebuilt.len;
+        });
+    }
+}
+
+/// Builds the tree with [`ParallelExtend::par_extend`]
+impl<K, V, A, S> FromParallelIterator<(K, V)> for BTree<K, V, A, S>
+where
+    K: Ord + Send,
+    V: Send,
+    A: Augment<K, V>,
+    A::Value: Send,
+    S: Sharing<K, V, A>,
+    S::Leaf: Send,
+    S::Internal: Send,
+    S::Alloc: Send + Default,
+{
+    fn from_par_iter<I: IntoParallelIterator<Item = (K, V)>>(par_iter: I) -> Self {
+        let mut tree = Self::default();
+        tree.par_extend(par_iter);
+        tree
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+    use std::ops::Bound;
+
+    use rayon::prelude::*;
+
+    use crate::augments::SumAugment;
+    use crate::persistent::PersistentBTree;
+    use crate::tests::{check_tree, rng};
+    use crate::BTree;
+
+    #[test]
+    fn par_iter_matches_iter() {
+        let tree: BTree<u32, u32> = (0..100_000).map(|i| (i * 3, i)).collect();
+
+        let pairs: Vec<_> = tree.par_iter().collect();
+        assert!(pairs.into_iter().eq(tree.iter()));
+        assert_eq!(tree.par_iter().count(), tree.len());
+        assert_eq!(
+            tree.par_iter().find_first(|(_, &v)| v >= 500),
+            Some((&1500, &500))
+        );
+        assert_eq!(BTree::<u32, u32>::new().par_iter().count(), 0);
+    }
+
+    #[test]
+    fn par_range_matches_model() {
+        let mut next = rng::<u64>(3);
+        let model: BTreeMap<u32, u32> =
+            (0..20_000).map(|_| ((next() % 50_000) as u32, 0)).collect();
+        let tree: BTree<u32, u32> = model.iter().map(|(&k, &v)| (k, v)).collect();
+
+        let check = |range: (Bound<u32>, Bound<u32>)| {
+            let pairs: Vec<_> = tree.par_range(range).collect();
+            assert!(pairs.into_iter().eq(model.range(range)), "{range:?}");
+        };
+        check((Bound::Unbounded, Bound::Unbounded));
+        for _ in 0..200 {
+            let (a, b) = ((next() % 51_000) as u32, (next() % 51_000) as u32);
+            let (start, end) = (a.min(b), a.max(b));
+            check((Bound::Included(start), Bound::Excluded(end)));
+            check((Bound::Excluded(start), Bound::Included(end)));
+            check((Bound::Unbounded, Bound::Included(end)));
+            check((Bound::Excluded(start), Bound::Unbounded));
+        }
+        let reversed = (Bound::Included(30_000), Bound::Excluded(10_000));
+        assert_eq!(tree.par_range(reversed).count(), 0);
+    }
+
+    #[test]
+    fn par_extend_keeps_existing_values() {
+        let mut tree: BTree<u32, i64, SumAugment> = (0..10_000).map(|i| (i * 2, 1)).collect();
+        let mut model: BTreeMap<u32, i64> = tree.iter().map(|(&k, &v)| (k, v)).collect();
+
+        // Overlaps the tree and repeats keys, so only the first of each new key may be kept
+        let new: Vec<_> = (5_000..30_000).flat_map(|i| [(i, 2), (i, 3)]).collect();
+        tree.par_extend(new.clone());
+        for (key, value) in new {
+            model.entry(key).or_insert(value);
+        }
+
+        check_tree(&tree);
+        assert_eq!(tree.len(), model.len());
+        assert!(tree.iter().eq(model.iter()));
+        assert_eq!(tree.augment_search(&29_999), model.values().sum::<i64>());
+
+        // Few enough pairs to be inserted one by one
+        tree.par_extend((0..10).into_par_iter().map(|i| (i + 100_000, 5)));
+        check_tree(&tree);
+        assert_eq!(tree.len(), model.len() + 10);
+        assert_eq!(tree.search(&100_009), Some(&5));
+    }
+
+    #[test]
+    fn collect_bulk_loads_valid_tree() {
+        let tree: BTree<u32, i64, SumAugment> = (0..50_000)
+            .into_par_iter()
+            .rev()
+            .map(|i| (i % 20_000, 1))
+            .collect();
+
+        check_tree(&tree);
+        assert_eq!(tree.len(), 20_000);
+        assert_eq!(tree.augment_search(&19_999), 20_000);
+    }
+
+    #[test]
+    fn par_recompute_leaves_snapshots_alone() {
+        let mut tree: PersistentBTree<u32, i64, SumAugment> = (0..30_000).map(|i| (i, 1)).collect();
+        let snapshot = tree.snapshot();
+
+        tree.par_recompute_augments();
+        check_tree(&tree);
+        check_tree(&snapshot);
+        assert_eq!(tree.augment_search(&29_999), 30_000);
+        assert_eq!(snapshot.augment_search(&29_999), 30_000);
+    }
+}
@@ -0,0 +1,417 @@
+//! Range-based set reconciliation between two trees
+//!
+//! Two replicas that hold their keys in trees with a [`FingerprintAugment`] find the keys only one
+//! of them holds by exchanging [`Message`]s. A message lists ranges of keys that together cover
+//! the key space, and for each either the [`Fingerprint`] of the keys the sender holds in it or,
+//! once the range is small enough, the keys themselves. A replica answers a fingerprint that
+//! differs from its own by splitting the range into smaller ones, and answers keys with its own
+//! keys in the range, so both replicas end up with the exact symmetric difference. Ranges that
+//! match are dropped right away, so the number of round trips grows with the logarithm of the
+//! size of the trees and the size of the messages with the number of differences.
+//!
+//! [`Reconciler`] only produces and consumes messages, which are plain data, so they can be sent
+//! over any transport.
+//!
+//! ```
+//! use b_tree::augments::FingerprintAugment;
+//! use b_tree::reconcile::Reconciler;
+//! use b_tree::BTree;
+//!
+//! let mut ours = BTree::with_augment::<FingerprintAugment>();
+//! let mut theirs = BTree::with_augment::<FingerprintAugment>();
+//! for key in 0..1000u32 {
+//!     ours.insert(key, ());
+//!     theirs.insert(key + 1, ());
+//! }
+//!
+//! let mut local = Reconciler::new(&ours);
+//! let mut remote = Reconciler::new(&theirs);
+//! let mut message = local.initiate();
+//! while let Some(reply) = remote.respond(&message) {
+//!     match local.respond(&reply) {
+//!         Some(next) => message = next,
+//!         None => break,
+//!     }
+//! }
+//!
+//! let difference = local.finish();
+//! assert_eq!(difference.local_only, [0]);
+//! assert_eq!(difference.remote_only, [1000]);
+//! ```
+
+use alloc::vec;
+use alloc::vec::Vec;
+use core::cmp::Ordering;
+use core::ops::Bound;
+
+use crate::augments::{Fingerprint, FingerprintAugment};
+use crate::persistent::{Sharing, Unique};
+use crate::snapshot::Encode;
+use crate::{BTree, Node};
+
+/// A message of the reconciliation protocol, see the [module documentation](self)
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Message<K> {
+    /// Ranges in ascending order that do not overlap
+    pub ranges: Vec<RangeMessage<K>>,
+}
+
+/// What the sender of a [`Message`] knows about the half-open range of keys from `start` up to
+/// `end`, where `None` leaves that side unbounded
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RangeMessage<K> {
+    pub start: Option<K>,
+    pub end: Option<K>,
+    pub payload: Payload<K>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Payload<K> {
+    /// The fingerprint of the keys the sender holds in the range
+    Fingerprint(Fingerprint),
+    /// All keys the sender holds in the range, in ascending order. If `reply` is set, the receiver
+    /// answers with its own keys in the range.
+    Keys { keys: Vec<K>, reply: bool },
+}
+
+/// The keys that only one of two reconciled trees holds, in ascending order
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Difference<K> {
+    /// Keys held by the local tree only
+    pub local_only: Vec<K>,
+    /// Keys held by the remote tree only
+    pub remote_only: Vec<K>,
+}
+
+/// One side of the reconciliation protocol, see the [module documentation](self)
+pub struct Reconciler<'a, K, V, S = Unique>
+where
+    K: Encode,
+    S: Sharing<K, V, FingerprintAugment>,
+{
+    tree: &'a BTree<K, V, FingerprintAugment, S>,
+    /// The number of ranges a range with differing fingerprints is split into
+    branching: usize,
+    /// Ranges holding at most this many local keys are answered with the keys
+    max_keys: usize,
+    difference: Difference<K>,
+}
+
+impl<'a, K, V, S> Reconciler<'a, K, V, S>
+where
+    K: Ord + Clone + Encode,
+    S: Sharing<K, V, FingerprintAugment>,
+{
+    /// Splits ranges into 16 and sends the keys of ranges with at most 16 keys
+    pub fn new(tree: &'a BTree<K, V, FingerprintAugment, S>) -> Self {
+        Self::with_limits(tree, 16, 16)
+    }
+
+    /// Splits ranges whose fingerprints differ into `branching` ranges, and sends the keys of
+    /// ranges with at most `max_keys` keys instead of splitting them further
+    ///
+    /// # Panics
+    /// Panics if `branching < 2` or `max_keys == 0`, as ranges might never shrink
+    pub fn with_limits(
+        tree: &'a BTree<K, V, FingerprintAugment, S>,
+        branching: usize,
+        max_keys: usize,
+    ) -> Self {
+        assert!(branching >= 2, "ranges must be split into at least 2");
+        assert!(
+            max_keys > 0,
+            "ranges with a single key must be sent as keys"
+        );
+        Self {
+            tree,
+            branching,
+            max_keys,
+            difference: Difference {
+                local_only: Vec::new(),
+                remote_only: Vec::new(),
+            },
+        }
+    }
+
+    /// Returns the first message, which holds the fingerprint of the whole tree
+    pub fn initiate(&self) -> Message<K> {
+        Message {
+            ranges: vec![RangeMessage {
+                start: None,
+                end: None,
+                payload: Payload::Fingerprint(self.tree.augment_range(..)),
+            }],
+        }
+    }
+
+    /// Processes a message from the other side, returning the answer to send back. Returns `None`
+    /// once nothing is left to answer, at which point the reconciliation is complete on both
+    /// sides.
+    pub fn respond(&mut self, message: &Message<K>) -> Option<Message<K>> {
+        let mut ranges = Vec::new();
+        for range in &message.ranges {
+            let bounds = bounds(&range.start, &range.end);
+
+            match &range.payload {
+                Payload::Fingerprint(theirs) => {
+                    let ours = self.tree.augment_range(bounds);
+                    if ours == *theirs {
+                        continue;
+                    }
+                    if ours.count <= self.max_keys {
+                        ranges.push(RangeMessage {
+                            start: range.start.clone(),
+                            end: range.end.clone(),
+                            payload: Payload::Keys {
+                                keys: self.keys_in(bounds),
+                                reply: true,
+                            },
+                        });
+                    } else {
+                        self.split(range, ours.count, &mut ranges);
+                    }
+                }
+                Payload::Keys { keys, reply } => {
+                    let ours = self.keys_in(bounds);
+                    self.record_difference(&ours, keys);
+                    if *reply {
+                        ranges.push(RangeMessage {
+                            start: range.start.clone(),
+                            end: range.end.clone(),
+                            payload: Payload::Keys {
+                                keys: ours,
+                                reply: false,
+                            },
+                        });
+                    }
+                }
+            }
+        }
+
+        (!ranges.is_empty()).then_some(Message { ranges })
+    }
+
+    /// Returns the keys found to be held by only one of the trees
+    pub fn finish(mut self) -> Difference<K> {
+        self.difference.local_only.sort_unstable();
+        self.difference.remote_only.sort_unstable();
+        self.difference
+    }
+
+    /// Splits `range`, which holds `count > 1` local keys, into ranges holding about as many local
+    /// keys each, and adds their fingerprints to `ranges`
+    fn split(&self, range: &RangeMessage<K>, count: usize, ranges: &mut Vec<RangeMessage<K>>) {
+        let first = match &range.start {
+            Some(start) => self.tree.augment_range(..start).count,
+            None => 0,
+        };
+        // Every range starts with a local key, except maybe the first, so each holds fewer local
+        // keys than `range`
+        let mut splits: Vec<_> = (1..self.branching)
+            .map(|i| first + (count * i).div_ceil(self.branching))
+            .filter(|&rank| rank < first + count)
+            .collect();
+        splits.dedup();
+
+        let mut start = range.start.clone();
+        let ends = splits
+            .into_iter()
+            .map(|rank| Some(self.tree.root.nth_key(rank).clone()))
+            .chain([range.end.clone()]);
+        for end in ends {
+            let fingerprint = self.tree.augment_range(bounds(&start, &end));
+            ranges.push(RangeMessage {
+                start,
+                end: end.clone(),
+                payload: Payload::Fingerprint(fingerprint),
+            });
+            start = end;
+        }
+    }
+
+    fn keys_in(&self, bounds: (Bound<&K>, Bound<&K>)) -> Vec<K> {
+        self.tree
+            .range(bounds)
+            .map(|(key, _)| key.clone())
+            .collect()
+    }
+
+    /// Records the keys that only one of two sorted lists of the keys in a range holds
+    fn record_difference(&mut self, ours: &[K], theirs: &[K]) {
+        let (mut ours, mut theirs) = (ours.iter().peekable(), theirs.iter().peekable());
+        loop {
+            let ordering = match (ours.peek(), theirs.peek()) {
+                (Some(our), Some(their)) => our.cmp(their),
+                (Some(_), None) => Ordering::Less,
+                (None, Some(_)) => Ordering::Greater,
+                (None, None) => break,
+            };
+            match ordering {
+                Ordering::Less => self
+                    .difference
+                    .local_only
+                    .push(ours.next().unwrap().clone()),
+                Ordering::Greater => self
+                    .difference
+                    .remote_only
+                    .push(theirs.next().unwrap().clone()),
+                Ordering::Equal => {
+                    ours.next();
+                    theirs.next();
+                }
+            }
+        }
+    }
+}
+
+/// Converts the bounds of a [`RangeMessage`]
+fn bounds<'a, K>(start: &'a Option<K>, end: &'a Option<K>) -> (Bound<&'a K>, Bound<&'a K>) {
+    (
+        start.as_ref().map_or(Bound::Unbounded, Bound::Included),
+        end.as_ref().map_or(Bound::Unbounded, Bound::Excluded),
+    )
+}
+
+impl<K, V, S: Sharing<K, V, FingerprintAugment>> Node<K, V, FingerprintAugment, S>
+where
+    K: Ord + Encode,
+{
+    /// Returns the `n`th smallest key of the subtree, counting from 0
+    fn nth_key(&self, mut n: usize) -> &K {
+        if self.is_leaf() {
+            return &self.pairs()[n].0;
+        }
+        for (i, child) in self.children().iter().enumerate() {
+            match n.cmp(&child.aug_val.count) {
+                Ordering::Less => return child.nth_key(n),
+                Ordering::Equal => return &self.pairs()[i].0,
+                Ordering::Greater => n -= child.aug_val.count + 1,
+            }
+        }
+        unreachable!("rank out of bounds")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeSet;
+    use std::sync::mpsc;
+    use std::thread;
+    use std::vec::Vec;
+
+    use crate::augments::FingerprintAugment;
+    use crate::reconcile::{Message, Reconciler};
+    use crate::tests::{check_tree, rng};
+    use crate::BTree;
+
+    type Tree = BTree<u32, (), FingerprintAugment>;
+
+    /// Builds a tree with inserts and deletes, so that every augment hook is used
+    fn build(keys: &BTreeSet<u32>) -> Tree {
+        let mut tree = BTree::with_augment::<FingerprintAugment>();
+        for &key in keys {
+            tree.insert(key, ());
+            tree.insert(key ^ 1 << 31, ());
+        }
+        for &key in keys {
+            tree.delete(&(key ^ 1 << 31));
+        }
+        check_tree(&tree);
+        tree
+    }
+
+    #[test]
+    fn random_sets_reconcile_exactly() {
+        let mut next = rng::<u32>(0xfeed_f00d);
+        let configs = [
+            (0, 0, 16, 16),
+            (3000, 0, 16, 16),
+            (3000, 40, 2, 1),
+            (5000, 900, 8, 32),
+        ];
+        for (shared, differing, branching, max_keys) in configs {
+            let mut local = BTreeSet::new();
+            let mut remote = BTreeSet::new();
+            for _ in 0..shared {
+                let key = next() % (1 << 30);
+                local.insert(key);
+                remote.insert(key);
+            }
+            for _ in 0..differing {
+                let key = next() % (1 << 30);
+                if next().is_multiple_of(2) {
+                    local.insert(key);
+                } else {
+                    remote.insert(key);
+                }
+            }
+            let (local_tree, remote_tree) = (build(&local), build(&remote));
+
+            let mut sides = [
+                Reconciler::with_limits(&local_tree, branching, max_keys),
+                Reconciler::with_limits(&remote_tree, branching, max_keys),
+            ];
+            let mut message = sides[0].initiate();
+            let mut messages = 1;
+            while let Some(reply) = sides[messages % 2].respond(&message) {
+                message = reply;
+                messages += 1;
+            }
+            // Both sides find the differing keys after about `log(len)` round trips
+            assert!(messages <= 30, "took {messages} messages");
+
+            let local_only: Vec<_> = local.difference(&remote).copied().collect();
+            let remote_only: Vec<_> = remote.difference(&local).copied().collect();
+            let [local_side, remote_side] = sides;
+            let local_difference = local_side.finish();
+            assert_eq!(local_difference.local_only, local_only);
+            assert_eq!(local_difference.remote_only, remote_only);
+            let remote_difference = remote_side.finish();
+            assert_eq!(remote_difference.local_only, remote_only);
+            assert_eq!(remote_difference.remote_only, local_only);
+        }
+    }
+
+    #[test]
+    fn reconciles_over_channels() {
+        let local_tree: Tree = (0..2000).filter(|k| k % 97 != 0).map(|k| (k, ())).collect();
+        let remote_tree: Tree = (0..2000).filter(|k| k % 89 != 0).map(|k| (k, ())).collect();
+
+        let (to_remote, from_local) = mpsc::channel::<Option<Message<u32>>>();
+        let (to_local, from_remote) = mpsc::channel();
+        let (local, remote) = thread::scope(|scope| {
+            // Either side sends `None` once it has nothing left to answer
+            let run = |mut side: Reconciler<'_, u32, ()>,
+                       first: Option<Message<u32>>,
+                       send: mpsc::Sender<Option<Message<u32>>>,
+                       receive: mpsc::Receiver<Option<Message<u32>>>| {
+                if let Some(first) = first {
+                    send.send(Some(first)).unwrap();
+                }
+                while let Some(message) = receive.recv().unwrap() {
+                    let reply = side.respond(&message);
+                    let done = reply.is_none();
+                    send.send(reply).unwrap();
+                    if done {
+                        break;
+                    }
+                }
+                side.finish()
+            };
+
+            let local_side = Reconciler::new(&local_tree);
+            let first = Some(local_side.initiate());
+            let local = scope.spawn(move || run(local_side, first, to_remote, from_remote));
+            let remote_side = Reconciler::new(&remote_tree);
+            let remote = scope.spawn(move || run(remote_side, None, to_local, from_local));
+            (local.join().unwrap(), remote.join().unwrap())
+        });
+
+        let local_only: Vec<_> = (0..2000).filter(|k| k % 89 == 0 && k % 97 != 0).collect();
+        let remote_only: Vec<_> = (0..2000).filter(|k| k % 97 == 0 && k % 89 != 0).collect();
+        assert_eq!(local.local_only, local_only);
+        assert_eq!(local.remote_only, remote_only);
+        assert_eq!(remote.local_only, remote_only);
+        assert_eq!(remote.remote_only, local_only);
+    }
+}
@@ -0,0 +1,146 @@
+use alloc::vec::Vec;
+use core::fmt::{self, Formatter};
+use core::marker::PhantomData;
+
+use serde::de::{MapAccess, Visitor};
+use serde::ser::Error;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::persistent::Sharing;
+use crate::{Augment, BTree};
+
+/// Serializes the tree as a map in ascending key order. Augment values are not serialized, and
+/// serializing fails if a change made by [`BTree::update_range`] has yet to reach the values, see
+/// [`BTree::apply_pending`].
+impl<K, V, A, S> Serialize for BTree<K, V, A, S>
+where
+    K: Ord + Serialize,
+    V: Serialize,
+    A: Augment<K, V>,
+    S: Sharing<K, V, A>,
+{
+    fn serialize<T: Serializer>(&self, serializer: T) -> Result<T::Ok, T::Error> {
+        if self.has_pending_changes() {
+            return Err(T::Error::custom("the tree has pending range updates"));
+        }
+        serializer.collect_map(self.iter())
+    }
+}
+
+/// Deserializes a map into a tree by bulk loading it, so every augment value is computed from
+/// scratch. The map does not have to be sorted, and only the first pair with a given key is kept.
+impl<'de, K, V, A, S> Deserialize<'de> for BTree<K, V, A, S>
+where
+    K: Ord + Deserialize<'de>,
+    V: Deserialize<'de>,
+    A: Augment<K, V>,
+    S: Sharing<K, V, A>,
+    S::Alloc: Default,
+{
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_map(BTreeVisitor(PhantomData))
+    }
+}
+
+struct BTreeVisitor<K, V, A, S>(PhantomData<(K, V, A, S)>);
+
+impl<'de, K, V, A, S> Visitor<'de> for BTreeVisitor<K, V, A, S>
+where
+    K: Ord + Deserialize<'de>,
+    V: Deserialize<'de>,
+    A: Augment<K, V>,
+    S: Sharing<K, V, A>,
+    S::Alloc: Default,
+{
+    type Value = BTree<K, V, A, S>;
+
+    fn expecting(&self, f: &mut Formatter) -> fmt::Result {
+        f.write_str("a map")
+    }
+
+    fn visit_map<M: MapAccess<'de>>(self, mut map: M) -> Result<Self::Value, M::Error> {
+        // Don't trust the size hint with a huge allocation
+        let mut pairs = Vec::with_capacity(map.size_hint().unwrap_or(0).min(4096));
+        while let Some(pair) = map.next_entry()? {
+            pairs.push(pair);
+        }
+        Ok(pairs.into_iter().collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::augments::{LazySumAugment, SumAugment};
+    use crate::BTree;
+
+    fn setup_tree() -> BTree<i32, i32, SumAugment> {
+        let mut tree = BTree::with_augment::<SumAugment>();
+        for i in (0..1000).rev() {
+            tree.insert(i, i * 3);
+        }
+        for i in (0..1000).step_by(4) {
+            tree.delete(&i);
+        }
+        tree
+    }
+
+    #[test]
+    fn json_round_trip() {
+        let tree = setup_tree();
+
+        let json = serde_json::to_string(&tree).unwrap();
+        let copy: BTree<i32, i32, SumAugment> = serde_json::from_str(&json).unwrap();
+
+        assert!(copy.iter().eq(tree.iter()));
+        for i in [0, 1, 500, 999, 2000] {
+            assert_eq!(copy.augment_search(&i), tree.augment_search(&i));
+        }
+    }
+
+    #[test]
+    fn range_updates_are_serialized() {
+        let mut tree: BTree<u32, i64, LazySumAugment> = (0..1000).map(|i| (i, 1)).collect();
+        tree.update_range(100..900, &5);
+        assert!(serde_json::to_string(&tree).is_err());
+
+        tree.apply_pending();
+        let json = serde_json::to_string(&tree).unwrap();
+        let copy: BTree<u32, i64, LazySumAugment> = serde_json::from_str(&json).unwrap();
+
+        assert!(copy.iter().eq(tree.iter()));
+        assert_eq!(copy.search(&500), Some(&6));
+        assert_eq!(copy.augment_search(&999), 5000);
+    }
+
+    #[test]
+    fn bincode_round_trip() {
+        let tree = setup_tree();
+
+        let bytes = bincode::serialize(&tree).unwrap();
+        let copy: BTree<i32, i32, SumAugment> = bincode::deserialize(&bytes).unwrap();
+
+        assert!(copy.iter().eq(tree.iter()));
+        for i in [0, 1, 500, 999, 2000] {
+            assert_eq!(copy.augment_search(&i), tree.augment_search(&i));
+        }
+    }
+
+    #[test]
+    fn json_map_is_ordered() {
+        let tree: BTree<i32, char> = [(3, 'c'), (1, 'a'), (2, 'b')].into_iter().collect();
+
+        assert_eq!(
+            serde_json::to_string(&tree).unwrap(),
+            r#"{"1":"a","2":"b","3":"c"}"#
+        );
+    }
+
+    #[test]
+    fn unsorted_input_is_bulk_loaded() {
+        let tree: BTree<i32, i32, SumAugment> =
+            serde_json::from_str(r#"{"5":5,"1":1,"3":3,"1":100}"#).unwrap();
+
+        assert!(tree.iter().eq([(&1, &1), (&3, &3), (&5, &5)]));
+        assert_eq!(tree.augment_search(&4), 4);
+    }
+}
@@ -0,0 +1,663 @@
+//! A compact binary snapshot format for [B-trees](crate::BTree)
+//!
+//! The format is stable: snapshots written by any version of this crate can be read by every
+//! later version. All integers are little-endian, and a snapshot is laid out as follows:
+//!
+//! | Field       | Size         | Contents                                              |
+//! |-------------|--------------|-------------------------------------------------------|
+//! | Magic       | 8            | `b"BTREESNP"`                                         |
+//! | Version     | 4            | [`VERSION`]                                           |
+//! | Degree      | 4            | Minimum degree of the tree that wrote the snapshot    |
+//! | Entry count | 8            | Number of pairs that follow                           |
+//! | Pairs       | variable     | For each pair in ascending key order: a 4 byte key length, the encoded key, a 4 byte value length and the encoded value |
+//! | Checksum    | 4            | CRC-32 (IEEE) of everything before it                 |
+//!
+//! Augment values are not stored. Loading a snapshot bulk loads the pairs, so the augment values
+//! are recomputed and the degree of the reading tree does not have to match the stored one.
+//!
+//! Reading and writing snapshots requires the `std` feature, while [`Encode`] and [`Decode`] are
+//! always available.
+
+use alloc::string::String;
+use alloc::vec::Vec;
+#[cfg(feature = "std")]
+use core::error::Error;
+#[cfg(feature = "std")]
+use core::fmt::{self, Display, Formatter};
+#[cfg(feature = "std")]
+use std::io::{self, Read, Write};
+
+#[cfg(feature = "std")]
+use crate::persistent::Sharing;
+#[cfg(feature = "std")]
+use crate::{Augment, BTree, MIN_DEGREE};
+
+#[cfg(feature = "std")]
+const MAGIC: [u8; 8] = *b"BTREESNP";
+
+/// The version of the snapshot format written by this crate
+pub const VERSION: u32 = 1;
+
+/// Converts a value to the bytes stored in a snapshot
+pub trait Encode {
+    fn encode(&self, buf: &mut Vec<u8>);
+}
+
+/// Converts the bytes stored in a snapshot back to a value. Should return `None` if `bytes` is not
+/// a valid encoding.
+pub trait Decode: Sized {
+    fn decode(bytes: &[u8]) -> Option<Self>;
+}
+
+macro_rules! impl_codec_for_int {
+    ($($int:ty),*) => {$(
+        impl Encode for $int {
+            fn encode(&self, buf: &mut Vec<u8>) {
+                buf.extend_from_slice(&self.to_le_bytes());
+            }
+        }
+
+        impl Decode for $int {
+            fn decode(bytes: &[u8]) -> Option<Self> {
+                Some(Self::from_le_bytes(bytes.try_into().ok()?))
+            }
+        }
+    )*};
+}
+
+impl_codec_for_int!(u8, u16, u32, u64, u128, i8, i16, i32, i64, i128);
+
+impl Encode for () {
+    fn encode(&self, _: &mut Vec<u8>) {}
+}
+
+impl Decode for () {
+    fn decode(bytes: &[u8]) -> Option<Self> {
+        bytes.is_empty().then_some(())
+    }
+}
+
+impl Encode for bool {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        buf.push(u8::from(*self));
+    }
+}
+
+impl Decode for bool {
+    fn decode(bytes: &[u8]) -> Option<Self> {
+        match bytes {
+            [0] => Some(false),
+            [1] => Some(true),
+            _ => None,
+        }
+    }
+}
+
+impl Encode for String {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        buf.extend_from_slice(self.as_bytes());
+    }
+}
+
+impl Decode for String {
+    fn decode(bytes: &[u8]) -> Option<Self> {
+        String::from_utf8(bytes.to_vec()).ok()
+    }
+}
+
+impl Encode for Vec<u8> {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        buf.extend_from_slice(self);
+    }
+}
+
+impl Decode for Vec<u8> {
+    fn decode(bytes: &[u8]) -> Option<Self> {
+        Some(bytes.to_vec())
+    }
+}
+
+impl<const N: usize> Encode for [u8; N] {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        buf.extend_from_slice(self);
+    }
+}
+
+impl<const N: usize> Decode for [u8; N] {
+    fn decode(bytes: &[u8]) -> Option<Self> {
+        bytes.try_into().ok()
+    }
+}
+
+/// `None` is stored as no bytes at all, and `Some` as a 1 followed by the encoded value
+impl<T: Encode> Encode for Option<T> {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        if let Some(value) = self {
+            buf.push(1);
+            value.encode(buf);
+        }
+    }
+}
+
+impl<T: Decode> Decode for Option<T> {
+    fn decode(bytes: &[u8]) -> Option<Self> {
+        match bytes {
+            [] => Some(None),
+            [1, rest @ ..] => T::decode(rest).map(Some),
+            _ => None,
+        }
+    }
+}
+
+/// An error encountered while reading a snapshot
+#[cfg(feature = "std")]
+#[derive(Debug)]
+pub enum SnapshotError {
+    Io(io::Error),
+    /// The input does not start with the snapshot magic bytes
+    BadMagic,
+    /// The snapshot was written in a format version this crate does not know
+    UnsupportedVersion(u32),
+    /// The input ended before the snapshot did
+    Truncated,
+    /// The checksum did not match the contents
+    ChecksumMismatch,
+    /// A key or value could not be decoded
+    Decode,
+    /// The keys are not in strictly ascending order
+    Unordered,
+}
+
+#[cfg(feature = "std")]
+impl Display for SnapshotError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            Self::Io(err) => write!(f, "I/O error while reading snapshot: {err}"),
+            Self::BadMagic => f.write_str("input is not a BTree snapshot"),
+            Self::UnsupportedVersion(v) => write!(f, "unsupported snapshot version {v}"),
+            Self::Truncated => f.write_str("snapshot is truncated"),
+            Self::ChecksumMismatch => f.write_str("snapshot checksum does not match"),
+            Self::Decode => f.write_str("failed to decode a key or value"),
+            Self::Unordered => f.write_str("snapshot keys are not in ascending order"),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl Error for SnapshotError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            Self::Io(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl From<io::Error> for SnapshotError {
+    fn from(err: io::Error) -> Self {
+        if err.kind() == io::ErrorKind::UnexpectedEof {
+            Self::Truncated
+        } else {
+            Self::Io(err)
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+const CRC_TABLE: [u32; 256] = {
+    let mut table = [0; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ 0xEDB8_8320
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+};
+
+/// Incremental CRC-32 (IEEE)
+#[cfg(feature = "std")]
+pub(crate) struct Crc32(u32);
+
+#[cfg(feature = "std")]
+impl Crc32 {
+    pub(crate) fn new() -> Self {
+        Self(!0)
+    }
+
+    pub(crate) fn update(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.0 = CRC_TABLE[((self.0 ^ byte as u32) & 0xFF) as usize] ^ (self.0 >> 8);
+        }
+    }
+
+    pub(crate) fn finish(&self) -> u32 {
+        !self.0
+    }
+}
+
+/// Passes everything through to `inner`, checksumming it on the way
+#[cfg(feature = "std")]
+struct Checksummed<T> {
+    inner: T,
+    crc: Crc32,
+}
+
+#[cfg(feature = "std")]
+impl<W: Write> Checksummed<W> {
+    fn write_all(&mut self, bytes: &[u8]) -> io::Result<()> {
+        self.crc.update(bytes);
+        self.inner.write_all(bytes)
+    }
+
+    fn write_chunk(&mut self, bytes: &[u8]) -> io::Result<()> {
+        let len = u32::try_from(bytes.len()).map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "encoded key or value exceeds 4 GiB",
+            )
+        })?;
+        self.write_all(&len.to_le_bytes())?;
+        self.write_all(bytes)
+    }
+}
+
+#[cfg(feature = "std")]
+impl<R: Read> Checksummed<R> {
+    fn read_exact(&mut self, buf: &mut [u8]) -> io::Result<()> {
+        self.inner.read_exact(buf)?;
+        self.crc.update(buf);
+        Ok(())
+    }
+
+    fn read_array<const N: usize>(&mut self) -> io::Result<[u8; N]> {
+        let mut buf = [0; N];
+        self.read_exact(&mut buf)?;
+        Ok(buf)
+    }
+
+    /// Appends a length-prefixed chunk to `payload` without its length
+    fn read_chunk(&mut self, payload: &mut Vec<u8>) -> Result<(), SnapshotError> {
+        let len = u32::from_le_bytes(self.read_array()?) as usize;
+        let start = payload.len();
+        // Read through `take` so a corrupt length can't make us allocate a huge buffer up front
+        let read = (&mut self.inner).take(len as u64).read_to_end(payload)?;
+        if read < len {
+            return Err(SnapshotError::Truncated);
+        }
+        self.crc.update(&payload[start..]);
+        Ok(())
+    }
+}
+
+/// Checks that snapshots of format `version` can be read. Every version ever written must stay
+/// listed here.
+#[cfg(feature = "std")]
+fn check_version(version: u32) -> Result<(), SnapshotError> {
+    match version {
+        1 => Ok(()),
+        _ => Err(SnapshotError::UnsupportedVersion(version)),
+    }
+}
+
+/// Reads a snapshot out of a slice, which it is advanced past
+#[cfg(feature = "mmap")]
+struct SliceReader<'a>(&'a [u8]);
+
+#[cfg(feature = "mmap")]
+impl<'a> SliceReader<'a> {
+    fn read_slice(&mut self, len: usize) -> Result<&'a [u8], SnapshotError> {
+        if self.0.len() < len {
+            return Err(SnapshotError::Truncated);
+        }
+        let (read, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Ok(read)
+    }
+
+    fn read_array<const N: usize>(&mut self) -> Result<[u8; N], SnapshotError> {
+        Ok(self.read_slice(N)?.try_into().unwrap())
+    }
+
+    fn read_chunk(&mut self) -> Result<&'a [u8], SnapshotError> {
+        let len = u32::from_le_bytes(self.read_array()?) as usize;
+        self.read_slice(len)
+    }
+}
+
+#[cfg(feature = "std")]
+impl<K: Ord + Encode, V: Encode, A: Augment<K, V>, S: Sharing<K, V, A>> BTree<K, V, A, S> {
+    /// Writes a snapshot of the tree to `writer`. See the [module documentation](self) for the
+    /// format. The writer is not flushed.
+    ///
+    /// Fails with [`io::ErrorKind::InvalidInput`] without writing anything if a change made by
+    /// [`BTree::update_range`] has yet to reach the values, see [`BTree::apply_pending`].
+    pub fn write_to(&self, writer: impl Write) -> io::Result<()> {
+        if self.has_pending_changes() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "the tree has pending range updates",
+            ));
+        }
+        let mut out = Checksummed {
+            inner: writer,
+            crc: Crc32::new(),
+        };
+
+        out.write_all(&MAGIC)?;
+        out.write_all(&VERSION.to_le_bytes())?;
+        out.write_all(&(MIN_DEGREE as u32).to_le_bytes())?;
+        out.write_all(&(self.len() as u64).to_le_bytes())?;
+
+        let mut buf = Vec::new();
+        for (key, value) in self.iter() {
+            buf.clear();
+            key.encode(&mut buf);
+            out.write_chunk(&buf)?;
+
+            buf.clear();
+            value.encode(&mut buf);
+            out.write_chunk(&buf)?;
+        }
+
+        let checksum = out.crc.finish();
+        out.write_all(&checksum.to_le_bytes())
+    }
+}
+
+#[cfg(feature = "std")]
+impl<K: Ord + Decode, V: Decode, A: Augment<K, V>, S: Sharing<K, V, A>> BTree<K, V, A, S>
+where
+    S::Alloc: Default,
+{
+    /// Reads a snapshot written by [`BTree::write_to`] and bulk loads it into a new tree
+    ///
+    /// The encoded pairs are read in full and checked against the checksum before any of them is
+    /// decoded, so [`Decode`] only ever sees bytes that were written by [`Encode`].
+    pub fn read_from(reader: impl Read) -> Result<Self, SnapshotError> {
+        let mut input = Checksummed {
+            inner: reader,
+            crc: Crc32::new(),
+        };
+
+        if input.read_array()? != MAGIC {
+            return Err(SnapshotError::BadMagic);
+        }
+        check_version(u32::from_le_bytes(input.read_array()?))?;
+        // The degree is informational only, since the pairs are bulk loaded either way
+        input.read_array::<4>()?;
+        let len = u64::from_le_bytes(input.read_array()?);
+
+        // The encoded keys and values back to back, and where each of them ends
+        let mut payload = Vec::new();
+        let mut ends = Vec::with_capacity(len.min(4096) as usize * 2);
+        for _ in 0..len {
+            input.read_chunk(&mut payload)?;
+            ends.push(payload.len());
+            input.read_chunk(&mut payload)?;
+            ends.push(payload.len());
+        }
+
+        let checksum = input.crc.finish();
+        if u32::from_le_bytes(input.read_array()?) != checksum {
+            return Err(SnapshotError::ChecksumMismatch);
+        }
+
+        let mut pairs: Vec<(K, V)> = Vec::with_capacity(ends.len() / 2);
+        let mut start = 0;
+        for end in ends.chunks_exact(2) {
+            let key = K::decode(&payload[start..end[0]]).ok_or(SnapshotError::Decode)?;
+            let value = V::decode(&payload[end[0]..end[1]]).ok_or(SnapshotError::Decode)?;
+            pairs.push((key, value));
+            start = end[1];
+        }
+        Self::from_snapshot_pairs(pairs)
+    }
+
+    /// Memory maps the snapshot file at `path` and bulk loads it into a new tree
+    ///
+    /// Unlike [`BTree::read_from`], the snapshot is not copied: the map is walked once to find the
+    /// end of the pairs, once to verify the checksum and once more to decode every pair straight
+    /// out of it. The tree holds decoded pairs on the heap and does not refer to the file once
+    /// this returns.
+    ///
+    /// # Safety
+    /// The file must not be modified while it is being loaded, as that is undefined behaviour.
+    #[cfg(feature = "mmap")]
+    pub unsafe fn read_mmap(path: impl AsRef<std::path::Path>) -> Result<Self, SnapshotError> {
+        let file = std::fs::File::open(path)?;
+        let map = memmap2::Mmap::map(&file)?;
+        Self::read_slice(&map)
+    }
+
+    /// Reads a snapshot out of `bytes` without copying it, see [`BTree::read_mmap`]
+    #[cfg(feature = "mmap")]
+    fn read_slice(bytes: &[u8]) -> Result<Self, SnapshotError> {
+        let mut input = SliceReader(bytes);
+        if input.read_array()? != MAGIC {
+            return Err(SnapshotError::BadMagic);
+        }
+        check_version(u32::from_le_bytes(input.read_array()?))?;
+        input.read_array::<4>()?;
+        let len = u64::from_le_bytes(input.read_array()?);
+
+        let encoded = input.0;
+        for _ in 0..len {
+            input.read_chunk()?;
+            input.read_chunk()?;
+        }
+        let checked = bytes.len() - input.0.len();
+        let mut crc = Crc32::new();
+        crc.update(&bytes[..checked]);
+        if u32::from_le_bytes(input.read_array()?) != crc.finish() {
+            return Err(SnapshotError::ChecksumMismatch);
+        }
+
+        // Every pair takes at least 8 bytes of the input, so `len` is not made up
+        let mut input = SliceReader(encoded);
+        let mut pairs: Vec<(K, V)> = Vec::with_capacity(len as usize);
+        for _ in 0..len {
+            let key = K::decode(input.read_chunk()?).ok_or(SnapshotError::Decode)?;
+            let value = V::decode(input.read_chunk()?).ok_or(SnapshotError::Decode)?;
+            pairs.push((key, value));
+        }
+        Self::from_snapshot_pairs(pairs)
+    }
+
+    fn from_snapshot_pairs(pairs: Vec<(K, V)>) -> Result<Self, SnapshotError> {
+        if !pairs.windows(2).all(|w| w[0].0 < w[1].0) {
+            return Err(SnapshotError::Unordered);
+        }
+        Ok(Self::from_sorted(pairs, true, S::Alloc::default()))
+    }
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use std::io;
+
+    use crate::augments::{LazySumAugment, SumAugment};
+    use crate::snapshot::SnapshotError;
+    use crate::BTree;
+
+    fn setup_tree() -> BTree<u32, i64, SumAugment> {
+        let mut tree = BTree::with_augment::<SumAugment>();
+        for i in 0..2000 {
+            tree.insert(i, i as i64 - 1000);
+        }
+        for i in (0..2000).step_by(3) {
+            tree.delete(&i);
+        }
+        tree
+    }
+
+    fn snapshot(tree: &BTree<u32, i64, SumAugment>) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        tree.write_to(&mut bytes).unwrap();
+        bytes
+    }
+
+    #[test]
+    fn round_trip() {
+        let tree = setup_tree();
+
+        let copy = BTree::<u32, i64, SumAugment>::read_from(&snapshot(&tree)[..]).unwrap();
+
+        assert!(copy.iter().eq(tree.iter()));
+        for i in [0, 1, 999, 1998, 5000] {
+            assert_eq!(copy.augment_search(&i), tree.augment_search(&i));
+        }
+    }
+
+    #[test]
+    fn range_updates_are_written() {
+        let mut tree: BTree<u32, i64, LazySumAugment> = (0..1000).map(|i| (i, 1)).collect();
+        tree.update_range(100..900, &5);
+        let mut bytes = Vec::new();
+        let err = tree.write_to(&mut bytes).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        assert!(bytes.is_empty());
+
+        tree.apply_pending();
+        tree.write_to(&mut bytes).unwrap();
+        let copy = BTree::<u32, i64, LazySumAugment>::read_from(&bytes[..]).unwrap();
+
+        assert!(copy.iter().eq(tree.iter()));
+        assert_eq!(copy.search(&500), Some(&6));
+        assert_eq!(copy.augment_search(&999), 5000);
+    }
+
+    #[test]
+    #[cfg_attr(
+        any(b_tree_min_degree = "3", b_tree_min_degree = "16"),
+        ignore = "the header records the degree"
+    )]
+    fn format_is_stable() {
+        let tree: BTree<u16, String> = [(2, "b".to_string()), (1, "a".to_string())]
+            .into_iter()
+            .collect();
+
+        let mut bytes = Vec::new();
+        tree.write_to(&mut bytes).unwrap();
+
+        let mut expected = b"BTREESNP".to_vec();
+        expected.extend([1, 0, 0, 0, 6, 0, 0, 0, 2, 0, 0, 0, 0, 0, 0, 0]);
+        expected.extend([2, 0, 0, 0, 1, 0, 1, 0, 0, 0, b'a']);
+        expected.extend([2, 0, 0, 0, 2, 0, 1, 0, 0, 0, b'b']);
+        expected.extend([0xAF, 0x09, 0x71, 0xA4]);
+        assert_eq!(bytes, expected);
+    }
+
+    #[test]
+    fn corruption_is_detected() {
+        let bytes = snapshot(&setup_tree());
+
+        let mut flipped = bytes.clone();
+        flipped[100] ^= 1;
+        assert!(matches!(
+            BTree::<u32, i64, SumAugment>::read_from(&flipped[..]),
+            Err(SnapshotError::ChecksumMismatch)
+        ));
+
+        assert!(matches!(
+            BTree::<u32, i64, SumAugment>::read_from(&bytes[..bytes.len() - 1]),
+            Err(SnapshotError::Truncated)
+        ));
+
+        let mut bad_magic = bytes.clone();
+        bad_magic[0] = b'X';
+        assert!(matches!(
+            BTree::<u32, i64, SumAugment>::read_from(&bad_magic[..]),
+            Err(SnapshotError::BadMagic)
+        ));
+
+        for version in [0, 2] {
+            let mut unknown = bytes.clone();
+            unknown[8] = version;
+            assert!(matches!(
+                BTree::<u32, i64, SumAugment>::read_from(&unknown[..]),
+                Err(SnapshotError::UnsupportedVersion(v)) if v == version as u32
+            ));
+        }
+    }
+
+    #[test]
+    fn checksum_is_checked_before_decoding() {
+        let tree: BTree<u16, String> = [(1, "a".to_string())].into_iter().collect();
+        let mut bytes = Vec::new();
+        tree.write_to(&mut bytes).unwrap();
+
+        // Turn the value into invalid UTF-8
+        let value = bytes.len() - 5;
+        bytes[value] = 0xFF;
+        assert!(matches!(
+            BTree::<u16, String>::read_from(&bytes[..]),
+            Err(SnapshotError::ChecksumMismatch)
+        ));
+    }
+
+    #[test]
+    fn wrong_type_fails_to_decode() {
+        let bytes = snapshot(&setup_tree());
+
+        assert!(matches!(
+            BTree::<u64, i64>::read_from(&bytes[..]),
+            Err(SnapshotError::Decode)
+        ));
+    }
+
+    #[cfg(feature = "mmap")]
+    #[test]
+    fn mmap_round_trip() {
+        let tree = setup_tree();
+        let path = std::env::temp_dir().join(format!("b-tree-snapshot-{}", std::process::id()));
+        std::fs::write(&path, snapshot(&tree)).unwrap();
+
+        let copy = unsafe { BTree::<u32, i64, SumAugment>::read_mmap(&path) };
+        std::fs::remove_file(&path).unwrap();
+
+        assert!(copy.unwrap().iter().eq(tree.iter()));
+    }
+
+    #[cfg(feature = "mmap")]
+    #[test]
+    fn mmap_detects_corruption() {
+        let bytes = snapshot(&setup_tree());
+        let mut flipped = bytes.clone();
+        flipped[100] ^= 1;
+        let mut unknown = bytes.clone();
+        unknown[8] = 0;
+
+        let path = std::env::temp_dir().join(format!("b-tree-corrupt-{}", std::process::id()));
+        let read = |bytes: &[u8]| {
+            std::fs::write(&path, bytes).unwrap();
+            unsafe { BTree::<u32, i64, SumAugment>::read_mmap(&path) }
+        };
+        assert!(matches!(
+            read(&flipped),
+            Err(SnapshotError::ChecksumMismatch)
+        ));
+        assert!(matches!(
+            read(&bytes[..bytes.len() - 1]),
+            Err(SnapshotError::Truncated)
+        ));
+        assert!(matches!(
+            read(&unknown),
+            Err(SnapshotError::UnsupportedVersion(0))
+        ));
+        std::fs::write(&path, &bytes).unwrap();
+        let wrong_type = unsafe { BTree::<u64, i64>::read_mmap(&path) };
+        std::fs::remove_file(&path).unwrap();
+        assert!(matches!(wrong_type, Err(SnapshotError::Decode)));
+    }
+}
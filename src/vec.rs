@@ -0,0 +1,665 @@
+//! A sequence indexed by position instead of by key
+//!
+//! [`BTreeVec`] stores its elements as the values of a [`BTree`] with `()` keys, in the order of
+//! their positions. A [`CountAugment`] keeps the size of every subtree, which is all that is
+//! needed to find an element by its position, so inserting and removing anywhere in the sequence
+//! takes `O(log n)` time. Splitting the sequence in two and appending one to another join and
+//! split whole subtrees, and also take `O(log n)` time.
+//!
+//! ```
+//! use b_tree::augments::SumAugment;
+//! use b_tree::vec::BTreeVec;
+//!
+//! let mut playlist = BTreeVec::with_augment::<SumAugment>();
+//! playlist.push(180);
+//! playlist.push(240);
+//! playlist.insert(1, 200);
+//!
+//! assert_eq!(playlist.get(1), Some(&200));
+//! assert_eq!(playlist.augment_search(1), 380);
+//!
+//! let mut rest = playlist.split_off(1);
+//! assert!(rest.iter().eq(&[200, 240]));
+//! playlist.append(&mut rest);
+//! assert_eq!(playlist.len(), 3);
+//! ```
+
+use core::mem::{self, ManuallyDrop, MaybeUninit};
+use core::ops::{Bound, RangeBounds};
+
+use allocator_api2::alloc::Global;
+
+use crate::augments::CountAugment;
+use crate::persistent::Unique;
+use crate::{Augment, BTree, Node};
+
+type VecAugment<A> = (CountAugment, A);
+type VecNode<T, A> = Node<(), T, VecAugment<A>, Unique>;
+
+/// A sequence with `O(log n)` positional insertion and removal, see the
+/// [module documentation](self)
+///
+/// The augment `A` sees every element as a pair with a `()` key, so [`BTreeVec::augment_search`]
+/// folds over a prefix of the sequence and [`BTreeVec::augment_range`] over any range of
+/// positions.
+pub struct BTreeVec<T, A = ()>
+where
+    A: Augment<(), T>,
+    A::Value: 'static,
+{
+    tree: BTree<(), T, VecAugment<A>>,
+}
+
+impl<T> BTreeVec<T> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_augment<B: Augment<(), T>>() -> BTreeVec<T, B>
+    where
+        B::Value: 'static,
+    {
+        BTreeVec::default()
+    }
+}
+
+impl<T, A: Augment<(), T>> BTreeVec<T, A>
+where
+    A::Value: 'static,
+{
+    /// Inserts `value` at position `idx`, shifting the elements after it to the right
+    ///
+    /// # Panics
+    /// Panics if `idx > len`
+    pub fn insert(&mut self, idx: usize, value: T) {
+        let len = self.len();
+        assert!(
+            idx <= len,
+            "insertion index (is {idx}) should be <= len (is {len})"
+        );
+        self.tree.poison_on_unwind(|tree| {
+            let root = tree.root_mut();
+            if root.is_full() {
+                unsafe { root.split_root() }
+                    .unwrap_or_else(|err| VecNode::<T, A>::alloc_failed(err));
+            }
+            root.insert_at_non_full(idx, value);
+            tree.len += 1;
+        });
+    }
+
+    /// Appends `value` to the end of the sequence
+    pub fn push(&mut self, value: T) {
+        self.insert(self.len(), value);
+    }
+
+    /// Removes and returns the element at position `idx`, shifting the elements after it to the
+    /// left
+    ///
+    /// # Panics
+    /// Panics if `idx >= len`
+    pub fn remove(&mut self, idx: usize) -> T {
+        let len = self.len();
+        assert!(
+            idx < len,
+            "removal index (is {idx}) should be < len (is {len})"
+        );
+        self.tree.poison_on_unwind(|tree| {
+            let value = unsafe { tree.root_mut().remove_at(idx) };
+            if tree.root.children().len() == 1 {
+                *tree.root = tree.root_mut().children_mut().pop().unwrap();
+            }
+            tree.len -= 1;
+            value
+        })
+    }
+
+    /// Returns the element at position `idx`
+    pub fn get(&self, idx: usize) -> Option<&T> {
+        self.tree.check_poison();
+        (idx < self.len()).then(|| self.tree.root.get(idx))
+    }
+
+    /// Returns the output of the augment for the elements at positions `0..=idx`
+    ///
+    /// # Panics
+    /// Panics if `idx >= len`
+    pub fn augment_search(&self, idx: usize) -> A::Output {
+        self.tree.check_poison();
+        assert!(idx < self.len(), "index out of bounds");
+        let root = &self.tree.root;
+        root.augment_search(idx, &root.aug_val, VecAugment::<A>::initial_output())
+            .1
+    }
+
+    /// Combines the augment values of the elements at the positions in `range` with
+    /// [`Augment::compute`], as if they made up a node of their own, in `O(log n)` time
+    ///
+    /// # Panics
+    /// Panics if the range starts after it ends or ends after `len`
+    pub fn augment_range(&self, range: impl RangeBounds<usize>) -> A::Value {
+        self.tree.check_poison();
+        let len = self.len();
+        let start = match range.start_bound() {
+            Bound::Included(&start) => start,
+            Bound::Excluded(&start) => start.checked_add(1).expect("range start overflows"),
+            Bound::Unbounded => 0,
+        };
+        let end = match range.end_bound() {
+            Bound::Included(&end) => end.checked_add(1).expect("range end overflows"),
+            Bound::Excluded(&end) => end,
+            Bound::Unbounded => len,
+        };
+        assert!(
+            start <= end,
+            "range start (is {start}) should be <= range end (is {end})"
+        );
+        assert!(
+            end <= len,
+            "range end (is {end}) should be <= len (is {len})"
+        );
+        self.tree.root.augment_positions(start, end).1
+    }
+
+    /// Splits the sequence in two at position `at`, returning the elements from `at` on
+    ///
+    /// # Panics
+    /// Panics if `at > len`
+    pub fn split_off(&mut self, at: usize) -> Self {
+        let len = self.len();
+        assert!(
+            at <= len,
+            "`at` split index (is {at}) should be <= len (is {len})"
+        );
+        self.tree.poison_on_unwind(|tree| {
+            let height = tree.root.height();
+            let root = tree.take_root();
+            let ((left, _), (right, _)) =
+                root.split_at_gap(height, at, &|node, at| node.find_gap(at));
+            *tree.root_mut() = left;
+            tree.len = at;
+
+            // Creating the new sequence runs a hook, and if it panics the half that is no longer
+            // part of the tree is leaked along with the poisoned tree
+            let right = ManuallyDrop::new(right);
+            let mut other = Self::default();
+            *other.tree.root_mut() = ManuallyDrop::into_inner(right);
+            other.tree.len = len - at;
+            other
+        })
+    }
+
+    /// Moves all elements of `other` to the end of the sequence, leaving `other` empty
+    pub fn append(&mut self, other: &mut Self) {
+        if other.is_empty() {
+            return;
+        }
+        if self.is_empty() {
+            mem::swap(self, other);
+            return;
+        }
+
+        let mid = other.remove(0);
+        let other_len = other.len();
+        // Taking the roots runs hooks, and if they panic the pieces that are no longer part of a
+        // tree are leaked along with the poisoned tree
+        let right = ManuallyDrop::new(other.tree.take_root());
+        other.tree.len = 0;
+        self.tree.poison_on_unwind(|tree| {
+            let (left_height, right_height) = (tree.root.height(), right.height());
+            let left = tree.take_root();
+            let right = ManuallyDrop::into_inner(right);
+            let (root, _) = Node::join(left, left_height, ((), mid), right, right_height);
+            *tree.root_mut() = root;
+            tree.len += 1 + other_len;
+        });
+    }
+
+    /// Returns the number of elements in the sequence
+    pub fn len(&self) -> usize {
+        self.tree.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tree.is_empty()
+    }
+
+    /// Returns an iterator over the elements in order
+    pub fn iter(&self) -> Iter<'_, T, A> {
+        Iter(self.tree.iter())
+    }
+}
+
+impl<T, A: Augment<(), T>> Default for BTreeVec<T, A>
+where
+    A::Value: 'static,
+{
+    fn default() -> Self {
+        Self {
+            tree: BTree::default(),
+        }
+    }
+}
+
+impl<T: Clone, A: Augment<(), T>> Clone for BTreeVec<T, A>
+where
+    A::Value: Clone + 'static,
+{
+    fn clone(&self) -> Self {
+        Self {
+            tree: self.tree.clone(),
+        }
+    }
+}
+
+/// Bulk loads the elements in `O(n)` time
+impl<T, A: Augment<(), T>> FromIterator<T> for BTreeVec<T, A>
+where
+    A::Value: 'static,
+{
+    fn from_iter<I: IntoIterator<Item = T>>(iter: I) -> Self {
+        let pairs = iter.into_iter().map(|value| ((), value)).collect();
+        Self {
+            tree: BTree::from_sorted(pairs, true, Global),
+        }
+    }
+}
+
+impl<'a, T, A: Augment<(), T>> IntoIterator for &'a BTreeVec<T, A>
+where
+    A::Value: 'static,
+{
+    type Item = &'a T;
+    type IntoIter = Iter<'a, T, A>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
+
+/// An iterator over the elements of a [`BTreeVec`], created by [`BTreeVec::iter`]
+pub struct Iter<'a, T, A>(crate::Iter<'a, (), T, VecAugment<A>>)
+where
+    A: Augment<(), T>,
+    A::Value: 'static;
+
+impl<'a, T, A: Augment<(), T>> Iterator for Iter<'a, T, A>
+where
+    A::Value: 'static,
+{
+    type Item = &'a T;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.0.next().map(|(_, value)| value)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.0.size_hint()
+    }
+}
+
+impl<T, A: Augment<(), T>> ExactSizeIterator for Iter<'_, T, A> where A::Value: 'static {}
+
+impl<T, A: Augment<(), T>> VecNode<T, A>
+where
+    A::Value: 'static,
+{
+    /// The number of elements in the subtree
+    fn size(&self) -> usize {
+        self.aug_val.0
+    }
+
+    /// Finds position `idx` of the subtree, which must be in bounds. Returns the index of the pair
+    /// at that position, or the index of the child holding it along with the position within the
+    /// child.
+    fn find_pos(&self, mut idx: usize) -> Result<usize, (usize, usize)> {
+        if self.is_leaf() {
+            return Ok(idx);
+        }
+        for (i, child) in self.children().iter().enumerate() {
+            match idx.cmp(&child.size()) {
+                core::cmp::Ordering::Less => return Err((i, idx)),
+                core::cmp::Ordering::Equal => return Ok(i),
+                core::cmp::Ordering::Greater => idx -= child.size() + 1,
+            }
+        }
+        unreachable!("position out of bounds")
+    }
+
+    /// Finds the gap before position `idx` of the subtree, where `idx` may also be the size of
+    /// the subtree. Returns the index of the child holding the gap, or of the pair after it in a
+    /// leaf, along with the position within the child.
+    fn find_gap(&self, mut idx: usize) -> (usize, usize) {
+        if self.is_leaf() {
+            return (idx, 0);
+        }
+        for (i, child) in self.children().iter().enumerate() {
+            if idx <= child.size() {
+                return (i, idx);
+            }
+            idx -= child.size() + 1;
+        }
+        unreachable!("position out of bounds")
+    }
+
+    fn get(&self, idx: usize) -> &T {
+        match self.find_pos(idx) {
+            Ok(i) => &self.pairs()[i].1,
+            Err((i, idx)) => self.children()[i].get(idx),
+        }
+    }
+
+    fn insert_at_non_full(&mut self, idx: usize, value: T) {
+        debug_assert!(!self.is_full());
+        self.push_down();
+        if !VecAugment::<A>::RECOMPUTE_ALWAYS {
+            self.aug_val = VecAugment::<A>::inserted_sub_tree(&(), &value, &self.aug_val);
+        }
+
+        let (mut i, mut idx) = self.find_gap(idx);
+        if self.is_leaf() {
+            self.insert_pair(i, ((), value));
+        } else {
+            if self.children()[i].is_full() {
+                // Safety: Child is definitely full
+                unsafe { self.split_child(i) }.unwrap_or_else(|err| Self::alloc_failed(err));
+                let left_size = self.children()[i].size();
+                if idx > left_size {
+                    idx -= left_size + 1;
+                    i += 1;
+                }
+            }
+            self.child_mut(i).insert_at_non_full(idx, value);
+        }
+
+        if VecAugment::<A>::RECOMPUTE_ALWAYS {
+            self.recompute_aug_val();
+        }
+    }
+
+    /// # Safety
+    /// `idx` must be below the size of the subtree
+    unsafe fn remove_at(&mut self, idx: usize) -> T {
+        self.push_down();
+        let value = match self.find_pos(idx) {
+            Ok(i) if self.is_leaf() => self.remove_pair(i).1,
+            Ok(i) if !self.children()[i].is_min() => {
+                // The pair is only replaced once the hooks run by the deletion are done
+                let pair = self.child_mut(i).delete_max();
+                mem::replace(&mut self.keys[i], MaybeUninit::new(pair))
+                    .assume_init()
+                    .1
+            }
+            Ok(i) if !self.children()[i + 1].is_min() => {
+                let pair = self.child_mut(i + 1).delete_min();
+                mem::replace(&mut self.keys[i], MaybeUninit::new(pair))
+                    .assume_init()
+                    .1
+            }
+            Ok(i) => {
+                let left_size = self.children()[i].size();
+                self.merge_children(i);
+                self.child_mut(i).remove_at(left_size)
+            }
+            Err((i, _)) => {
+                if self.children()[i].is_min() {
+                    self.make_space(i);
+                }
+                // The position is still in a child, but its index within it may have changed
+                let Err((i, idx)) = self.find_pos(idx) else {
+                    unreachable!("position moved out of the child");
+                };
+                self.child_mut(i).remove_at(idx)
+            }
+        };
+
+        self.deleted_sub_tree(&(), &value);
+        value
+    }
+
+    /// Like [`Node::augment_range`], but for the positions `start..end`, which must be in bounds
+    fn augment_positions(
+        &self,
+        start: usize,
+        end: usize,
+    ) -> <VecAugment<A> as Augment<(), T>>::Value {
+        self.check_pending();
+        if self.is_leaf() {
+            return VecAugment::<A>::compute(&self.pairs()[start..end], core::iter::empty());
+        }
+        let (first, start) = self.find_gap(start);
+        let (last, end) = self.find_gap(end);
+        if first == last {
+            return self.children()[first].augment_positions(start, end);
+        }
+
+        // Only the children at either end can stick out of the range, and only on one side
+        let (left, right);
+        let left_child = &self.children()[first];
+        let left = if start == 0 {
+            &left_child.aug_val
+        } else {
+            left = left_child.augment_positions(start, left_child.size());
+            &left
+        };
+        let right_child = &self.children()[last];
+        let right = if end == right_child.size() {
+            &right_child.aug_val
+        } else {
+            right = right_child.augment_positions(0, end);
+            &right
+        };
+        let middle = self.children()[first + 1..last].iter().map(|c| &c.aug_val);
+        VecAugment::<A>::compute(
+            &self.pairs()[first..last],
+            core::iter::once(left).chain(middle).chain([right]),
+        )
+    }
+
+    /// Like [`Node::search`], but for the position `idx`
+    fn augment_search(
+        &self,
+        idx: usize,
+        aug_val: &<VecAugment<A> as Augment<(), T>>::Value,
+        mut acc: <VecAugment<A> as Augment<(), T>>::Output,
+    ) -> <VecAugment<A> as Augment<(), T>>::Output {
+        let (found, i, idx) = match self.find_pos(idx) {
+            Ok(i) => (true, i, idx),
+            Err((i, idx)) => (false, i, idx),
+        };
+
+        acc = VecAugment::<A>::visit(
+            found,
+            i,
+            self.pairs(),
+            self.children().iter().map(|n| &n.aug_val),
+            aug_val,
+            acc,
+        );
+
+        if found {
+            return acc;
+        }
+        let child = &self.children()[i];
+        if VecAugment::<A>::has_pending(aug_val) {
+            let pushed = VecAugment::<A>::pushed_down(aug_val, &child.aug_val);
+            child.augment_search(idx, &pushed, acc)
+        } else {
+            child.augment_search(idx, &child.aug_val, acc)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+    use std::panic::{self, AssertUnwindSafe};
+    use std::vec::Vec;
+
+    use crate::augments::SumAugment;
+    use crate::tests::{rng, PanickyAugment, Tracked, DOUBLE_DROPS, LIVE, PANIC_AT};
+    use crate::vec::{BTreeVec, VecNode};
+    use crate::MIN_DEGREE;
+
+    /// Checks the structural invariants of the subtree and that every augment value agrees with
+    /// one computed from scratch. Returns the height of the subtree.
+    fn check_node(node: &VecNode<i64, SumAugment>, is_root: bool) -> usize {
+        assert!(node.n < 2 * MIN_DEGREE);
+        assert!(is_root || node.n >= MIN_DEGREE - 1);
+        assert!(!is_root || node.n > 0 || node.is_leaf());
+        assert_eq!(node.aug_val, node.computed_aug_val());
+
+        if node.is_leaf() {
+            return 0;
+        }
+        assert_eq!(node.children().len(), node.n + 1);
+        let height = check_node(&node.children()[0], false);
+        for child in node.children() {
+            assert_eq!(check_node(child, false), height);
+        }
+        height + 1
+    }
+
+    fn check_vec(vec: &BTreeVec<i64, SumAugment>, model: &[i64]) {
+        check_node(&vec.tree.root, true);
+        assert_eq!(vec.len(), model.len());
+        assert!(vec.iter().eq(model));
+    }
+
+    #[test]
+    fn random_operations_match_model() {
+        let mut vec = BTreeVec::with_augment::<SumAugment>();
+        let mut model = Vec::new();
+
+        let mut next = rng::<usize>(0x1234_5678);
+        for step in 0..5000 {
+            match next() % 20 {
+                0 => {
+                    let at = next() % (model.len() + 1);
+                    let split = vec.split_off(at);
+                    let model_split = model.split_off(at);
+                    check_vec(&vec, &model);
+                    check_vec(&split, &model_split);
+
+                    // Put the halves back together, sometimes after rebuilding one of them
+                    let mut split = if next().is_multiple_of(2) {
+                        split.iter().copied().collect()
+                    } else {
+                        split
+                    };
+                    vec.append(&mut split);
+                    model.extend(model_split);
+                    assert!(split.is_empty());
+                }
+                1..=7 if !model.is_empty() => {
+                    let idx = next() % model.len();
+                    assert_eq!(vec.remove(idx), model.remove(idx));
+                }
+                _ => {
+                    let idx = next() % (model.len() + 1);
+                    let value = (next() % 1000) as i64;
+                    vec.insert(idx, value);
+                    model.insert(idx, value);
+                }
+            }
+
+            if step % 100 == 0 {
+                check_vec(&vec, &model);
+                for (idx, value) in model.iter().enumerate() {
+                    assert_eq!(vec.get(idx), Some(value));
+                }
+                assert_eq!(vec.get(model.len()), None);
+                if !model.is_empty() {
+                    let idx = next() % model.len();
+                    assert_eq!(vec.augment_search(idx), model[..=idx].iter().sum::<i64>());
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn augment_range_matches_model() {
+        let mut next = rng::<usize>(0x9e37_79b9);
+        for len in [0, 1, 2 * MIN_DEGREE, 300, 3000] {
+            let model: Vec<i64> = (0..len).map(|_| (next() % 1000) as i64).collect();
+            let vec: BTreeVec<i64, SumAugment> = model.iter().copied().collect();
+            assert_eq!(vec.augment_range(..), model.iter().sum::<i64>());
+
+            for _ in 0..200 {
+                let (a, b) = (next() % (len + 1), next() % (len + 1));
+                let (start, end) = (a.min(b), a.max(b));
+                let sum = model[start..end].iter().sum::<i64>();
+                assert_eq!(vec.augment_range(start..end), sum);
+                assert_eq!(vec.augment_range(..end), model[..end].iter().sum::<i64>());
+                assert_eq!(
+                    vec.augment_range(start..),
+                    model[start..].iter().sum::<i64>()
+                );
+                if end < len {
+                    assert_eq!(vec.augment_range(start..=end), sum + model[end]);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn appending_trees_of_different_heights() {
+        for (left_len, right_len) in [(0, 5), (3, 2000), (2000, 3), (700, 900), (1, 1)] {
+            let mut left: BTreeVec<i64, SumAugment> = (0..left_len).collect();
+            let mut right: BTreeVec<i64, SumAugment> = (left_len..left_len + right_len).collect();
+            left.append(&mut right);
+
+            let model: Vec<_> = (0..left_len + right_len).collect();
+            check_vec(&left, &model);
+            check_vec(&right, &[]);
+
+            for at in [0, 1, left_len as usize, model.len() / 3, model.len()] {
+                let mut vec: BTreeVec<i64, SumAugment> = model.iter().copied().collect();
+                let rest = vec.split_off(at);
+                check_vec(&vec, &model[..at]);
+                check_vec(&rest, &model[at..]);
+            }
+        }
+    }
+
+    #[test]
+    fn panicking_hooks_do_not_double_drop() {
+        let hooks = [
+            "initial_value",
+            "inserted_sub_tree",
+            "deleted_sub_tree",
+            "merge",
+            "steal",
+        ];
+
+        for hook in hooks {
+            let mut triggered_any = false;
+
+            for countdown in [0, 1, 7, 40, 150] {
+                LIVE.with(|live| live.borrow_mut().clear());
+                let mut vec = BTreeVec::with_augment::<PanickyAugment>();
+                for i in 0..300 {
+                    vec.push(Tracked::new(i));
+                }
+
+                PANIC_AT.with(|at| at.set(Some((hook, countdown))));
+                let res = panic::catch_unwind(AssertUnwindSafe(|| {
+                    for at in [150, 20, 280, 1] {
+                        let mut rest = vec.split_off(at);
+                        rest.remove(0);
+                        rest.remove(rest.len() / 2);
+                        vec.append(&mut rest);
+                    }
+                }));
+                let triggered = PANIC_AT.with(Cell::take).is_none();
+                assert_eq!(res.is_err(), triggered, "hook `{hook}`");
+                triggered_any |= triggered;
+
+                drop(vec);
+                assert_eq!(DOUBLE_DROPS.with(Cell::get), 0, "hook `{hook}`");
+            }
+
+            assert!(triggered_any, "hook `{hook}` was never called");
+        }
+    }
+}